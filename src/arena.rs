@@ -0,0 +1,586 @@
+//! Variable-length messages encoded in place in a slot.
+//!
+//! A message is a [`ShmPod`] root at the start of the slot, followed by an
+//! arena holding the strings and vectors it refers to. Those are referenced
+//! through [`Str`] and [`Vector`] handles, which store an offset from the
+//! start of the message and a length, so that the encoding does not depend on
+//! where each process maps the segment. Handles are themselves [`ShmPod`] and
+//! can be nested, e.g. a `Vector` of structs holding `Str`s.
+//!
+//! Producers build the message directly in the slot with a [`Builder`], and
+//! consumers read it in place through a [`Message`], which bounds-checks
+//! every handle instead of decoding anything up front:
+//!
+//! ```
+//! use zshm::arena::{Str, Vector};
+//!
+//! zshm::shm_pod! {
+//!     #[derive(Clone, Copy, Debug, Default)]
+//!     pub struct Scan {
+//!         pub frame: Str,
+//!         pub ranges: Vector<f32>,
+//!     }
+//! }
+//!
+//! #[repr(align(8))]
+//! struct Slot([u8; 128]);
+//!
+//! let mut slot = Slot([0; 128]);
+//! let mut builder = zshm::arena::Builder::<Scan>::new(&mut slot.0).unwrap();
+//! let frame = builder.push_str("lidar").unwrap();
+//! let ranges = builder.push_slice(&[1.0f32, 2.5, 4.0]).unwrap();
+//! *builder.root_mut() = Scan { frame, ranges };
+//! let len = builder.finish();
+//!
+//! let message = zshm::arena::Message::<Scan>::new(&slot.0[..len]).unwrap();
+//! assert_eq!(message.str(message.root().frame).unwrap(), "lidar");
+//! assert_eq!(message.slice(message.root().ranges).unwrap(), &[1.0, 2.5, 4.0]);
+//! ```
+
+use std::marker::PhantomData;
+
+use zenoh::Session;
+
+use crate::deadline::Deadline;
+use crate::header::{LayoutError, MessageType};
+use crate::pod::{self, RawConsumer, RawProducer, ShmPod};
+use crate::{Error, Result};
+
+/// A UTF-8 string stored in the arena of a message.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Str {
+    pub offset: u32,
+    pub len: u32, // In bytes
+}
+
+unsafe impl ShmPod for Str {
+    const FINGERPRINT: u64 = pod::fnv1a(pod::FNV_OFFSET, b"Str");
+}
+
+/// A sequence of `T` stored in the arena of a message.
+#[repr(C)]
+pub struct Vector<T> {
+    pub offset: u32,
+    pub len: u32, // In elements
+    _element: PhantomData<T>,
+}
+
+impl<T> Clone for Vector<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Vector<T> {}
+
+impl<T> Default for Vector<T> {
+    fn default() -> Self {
+        Vector {
+            offset: 0,
+            len: 0,
+            _element: PhantomData,
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for Vector<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Vector")
+            .field("offset", &self.offset)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+unsafe impl<T: ShmPod> ShmPod for Vector<T> {
+    const FINGERPRINT: u64 = pod::mix(pod::fnv1a(pod::FNV_OFFSET, b"Vector"), T::FINGERPRINT);
+}
+
+/// Writes a message rooted at an `R` into a buffer, the root first and the
+/// arena after it.
+pub struct Builder<'a, R> {
+    buf: &'a mut [u8],
+    len: usize,
+    _root: PhantomData<R>,
+}
+
+impl<'a, R: ShmPod> Builder<'a, R> {
+    /// Starts a message in `buf`, with a zeroed root. `buf` must be aligned
+    /// to [`pod::MAX_ALIGN`], as channel slots are.
+    pub fn new(buf: &'a mut [u8]) -> Result<Self> {
+        if !(buf.as_ptr() as usize).is_multiple_of(pod::MAX_ALIGN) {
+            return Err(LayoutError::Misaligned {
+                align: pod::MAX_ALIGN,
+                addr: buf.as_ptr() as usize,
+            }
+            .into());
+        }
+        let mut builder = Builder {
+            buf,
+            len: 0,
+            _root: PhantomData,
+        };
+        let offset = builder.reserve::<R>(1)?;
+        debug_assert_eq!(offset, 0);
+        Ok(builder)
+    }
+
+    /// The root of the message, zeroed until written.
+    pub fn root_mut(&mut self) -> &mut R {
+        // SAFETY: `new` reserved an aligned `R` at offset 0, and any bit
+        // pattern is a valid `R`.
+        unsafe { &mut *self.buf.as_mut_ptr().cast::<R>() }
+    }
+
+    /// Copies `s` into the arena.
+    pub fn push_str(&mut self, s: &str) -> Result<Str> {
+        let offset = self.reserve::<u8>(s.len())?;
+        self.buf[offset..offset + s.len()].copy_from_slice(s.as_bytes());
+        Ok(Str {
+            offset: offset as u32,
+            len: s.len() as u32,
+        })
+    }
+
+    /// Copies `items` into the arena.
+    pub fn push_slice<T: ShmPod>(&mut self, items: &[T]) -> Result<Vector<T>> {
+        self.vector_with(items.len(), |slice| slice.copy_from_slice(items))
+    }
+
+    /// Reserves `len` zeroed elements in the arena and lets `f` fill them in
+    /// place.
+    pub fn vector_with<T, F>(&mut self, len: usize, f: F) -> Result<Vector<T>>
+    where
+        T: ShmPod,
+        F: FnOnce(&mut [T]),
+    {
+        let offset = self.reserve::<T>(len)?;
+        let ptr = self.buf[offset..].as_mut_ptr().cast::<T>();
+        // SAFETY: `reserve` checked bounds and alignment, and any bit pattern
+        // is a valid `T`.
+        f(unsafe { std::slice::from_raw_parts_mut(ptr, len) });
+        Ok(Vector {
+            offset: offset as u32,
+            len: len as u32,
+            _element: PhantomData,
+        })
+    }
+
+    /// Number of bytes written so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Ends the message and returns its length.
+    pub fn finish(self) -> usize {
+        self.len
+    }
+
+    /// Zeroes room for `count` aligned `T`s past the current end and returns
+    /// their offset.
+    fn reserve<T>(&mut self, count: usize) -> Result<usize> {
+        const {
+            assert!(
+                std::mem::align_of::<T>() <= pod::MAX_ALIGN,
+                "elements cannot be aligned to more than MAX_ALIGN"
+            );
+        }
+        let align = std::mem::align_of::<T>();
+        let offset = self.len.next_multiple_of(align);
+        let end = std::mem::size_of::<T>()
+            .checked_mul(count)
+            .and_then(|size| offset.checked_add(size))
+            .filter(|&end| end <= self.buf.len() && end <= u32::MAX as usize)
+            .ok_or(Error::TooLarge {
+                len: offset.saturating_add(count.saturating_mul(std::mem::size_of::<T>())),
+                capacity: self.buf.len(),
+            })?;
+        self.buf[self.len..end].fill(0);
+        self.len = end;
+        Ok(offset)
+    }
+}
+
+/// A message rooted at an `R`, read in place.
+#[derive(Clone, Copy)]
+pub struct Message<'a, R> {
+    bytes: &'a [u8],
+    _root: PhantomData<R>,
+}
+
+impl<'a, R: ShmPod> Message<'a, R> {
+    /// Checks that `bytes` can hold the root of a message. Handles are only
+    /// checked when followed.
+    pub fn new(bytes: &'a [u8]) -> Result<Self> {
+        if bytes.len() < std::mem::size_of::<R>() {
+            return Err(LayoutError::Field {
+                name: "len",
+                value: bytes.len() as u64,
+            }
+            .into());
+        }
+        if !bytes.as_ptr().cast::<R>().is_aligned() {
+            return Err(LayoutError::Misaligned {
+                align: std::mem::align_of::<R>(),
+                addr: bytes.as_ptr() as usize,
+            }
+            .into());
+        }
+        Ok(Message {
+            bytes,
+            _root: PhantomData,
+        })
+    }
+
+    pub fn root(&self) -> &'a R {
+        // SAFETY: `new` checked length and alignment, and any bit pattern is
+        // a valid `R`.
+        unsafe { &*self.bytes.as_ptr().cast::<R>() }
+    }
+
+    /// The whole encoded message.
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Follows a string handle.
+    pub fn str(&self, s: Str) -> Result<&'a str> {
+        let bytes = self.get::<u8>(s.offset, s.len)?;
+        std::str::from_utf8(bytes).map_err(|_| {
+            LayoutError::Field {
+                name: "str",
+                value: s.offset as u64,
+            }
+            .into()
+        })
+    }
+
+    /// Follows a vector handle.
+    pub fn slice<T: ShmPod>(&self, v: Vector<T>) -> Result<&'a [T]> {
+        self.get(v.offset, v.len)
+    }
+
+    fn get<T: ShmPod>(&self, offset: u32, len: u32) -> Result<&'a [T]> {
+        let (offset, len) = (offset as usize, len as usize);
+        let end = std::mem::size_of::<T>()
+            .checked_mul(len)
+            .and_then(|size| offset.checked_add(size));
+        let invalid = LayoutError::Field {
+            name: "offset",
+            value: offset as u64,
+        };
+        if end.is_none_or(|end| end > self.bytes.len()) {
+            return Err(invalid.into());
+        }
+        let ptr = self.bytes[offset..].as_ptr().cast::<T>();
+        if !ptr.is_aligned() {
+            return Err(invalid.into());
+        }
+        // SAFETY: checked to lie within the message and to be aligned, and
+        // any bit pattern is a valid `T`.
+        Ok(unsafe { std::slice::from_raw_parts(ptr, len) })
+    }
+}
+
+/// A producer of variable-length messages rooted at an `R` over channel `C`.
+pub struct Producer<C, R> {
+    channel: C,
+    _root: PhantomData<fn(R)>,
+}
+
+impl<C: RawProducer, R: ShmPod> Producer<C, R> {
+    /// Wraps a byte-level producer created with
+    /// [`MessageType::arena::<R>()`](MessageType::arena), e.g. through
+    /// [`crate::spsc::Producer::with_message`].
+    pub fn new(channel: C) -> Result<Self> {
+        pod::check::<R>(channel.header(), MessageType::arena::<R>())?;
+        Ok(Producer {
+            channel,
+            _root: PhantomData,
+        })
+    }
+
+    /// The byte-level producer.
+    pub fn channel(&self) -> &C {
+        &self.channel
+    }
+
+    /// Waits for a free slot and lets `f` build a message in place. If `f`
+    /// fails, e.g. with [`Error::TooLarge`] because the message outgrew the
+    /// slot, nothing is published and the error returned.
+    pub fn publish_with<F>(&mut self, deadline: &Deadline, f: F) -> Result<C::Published>
+    where
+        F: FnOnce(&mut Builder<'_, R>) -> Result<()>,
+    {
+        let mut result = Ok(());
+        let published = self.channel.publish_raw(deadline, |buf| {
+            let built = Builder::<R>::new(buf).and_then(|mut builder| {
+                f(&mut builder)?;
+                Ok(builder.finish())
+            });
+            // Overfilling the slot makes the channel abort the publication
+            built.unwrap_or_else(|e| {
+                result = Err(e);
+                usize::MAX
+            })
+        });
+        result?;
+        published
+    }
+}
+
+/// A consumer of variable-length messages rooted at an `R` over channel `C`.
+pub struct Consumer<C, R> {
+    channel: C,
+    _root: PhantomData<fn() -> R>,
+}
+
+impl<C: RawConsumer, R: ShmPod> Consumer<C, R> {
+    /// Fetches the segment served on `key_expr` and checks that it carries
+    /// messages rooted at an `R`.
+    pub fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        Self::new(C::attach(session, key_expr)?)
+    }
+
+    /// Wraps a byte-level consumer, after checking that its segment carries
+    /// messages rooted at an `R`.
+    pub fn new(channel: C) -> Result<Self> {
+        pod::check::<R>(channel.header(), MessageType::arena::<R>())?;
+        Ok(Consumer {
+            channel,
+            _root: PhantomData,
+        })
+    }
+
+    /// The byte-level consumer.
+    pub fn channel(&self) -> &C {
+        &self.channel
+    }
+
+    /// Waits for a message and hands it to `f` in place.
+    pub fn recv_with<F, T>(&mut self, deadline: &Deadline, f: F) -> Result<T>
+    where
+        F: FnOnce(Message<'_, R>) -> T,
+    {
+        self.channel
+            .recv_raw(deadline, |_, data| Message::new(data).map(f))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::Header;
+    use crate::segment::check_len;
+    use crate::seqlock;
+
+    crate::shm_pod! {
+        #[derive(Clone, Copy, Debug, Default)]
+        struct Scan {
+            frame: Str,
+            ranges: Vector<u32>,
+        }
+    }
+
+    /// A message of `len` bytes in an aligned buffer.
+    fn message(len: usize, f: impl FnOnce(&mut Builder<'_, Scan>)) -> Vec<u64> {
+        let mut buf = vec![0u64; len.div_ceil(8)];
+        // SAFETY: `buf` holds at least `len` bytes, and any bit pattern is a
+        // valid `u8`.
+        let bytes = unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<u8>(), len) };
+        let mut builder = Builder::<Scan>::new(bytes).unwrap();
+        f(&mut builder);
+        buf
+    }
+
+    fn bytes(buf: &[u64]) -> &[u8] {
+        // SAFETY: any bit pattern is a valid `u8`.
+        unsafe { std::slice::from_raw_parts(buf.as_ptr().cast::<u8>(), buf.len() * 8) }
+    }
+
+    fn invalid<T>(result: Result<T>) -> bool {
+        matches!(result, Err(Error::Layout(LayoutError::Field { .. })))
+    }
+
+    #[test]
+    fn handles_out_of_bounds_are_rejected() {
+        let buf = message(64, |builder| {
+            let frame = builder.push_str("lidar").unwrap();
+            builder.root_mut().frame = frame;
+        });
+        let message = Message::<Scan>::new(bytes(&buf)).unwrap();
+        assert_eq!(message.str(message.root().frame).unwrap(), "lidar");
+
+        for (offset, len) in [(64, 1), (60, 5), (0, 65), (u32::MAX, 1), (1, u32::MAX)] {
+            assert!(invalid(message.str(Str { offset, len })), "{offset}+{len}");
+        }
+        let ranges = Vector::<u32> {
+            offset: 56,
+            len: 3,
+            ..Default::default()
+        };
+        assert!(invalid(message.slice(ranges)));
+        // Empty handles may point right past the end
+        assert_eq!(message.str(Str { offset: 64, len: 0 }).unwrap(), "");
+    }
+
+    #[test]
+    fn misaligned_handles_are_rejected() {
+        let buf = message(64, |_| {});
+        let message = Message::<Scan>::new(bytes(&buf)).unwrap();
+        let ranges = Vector::<u32> {
+            offset: 18,
+            len: 2,
+            ..Default::default()
+        };
+        assert!(invalid(message.slice(ranges)));
+        assert!(matches!(
+            Message::<Scan>::new(&bytes(&buf)[1..]),
+            Err(Error::Layout(LayoutError::Misaligned { .. }))
+        ));
+    }
+
+    #[test]
+    fn overflowing_handles_are_rejected() {
+        let buf = message(64, |_| {});
+        let message = Message::<Scan>::new(bytes(&buf)).unwrap();
+        // The size of the elements times their number overflows
+        type Huge = [u64; 1 << 30];
+        let huge = Vector::<Huge> {
+            offset: 0,
+            len: u32::MAX,
+            ..Default::default()
+        };
+        assert!(invalid(message.slice(huge)));
+        let huge = Vector::<Huge> {
+            offset: u32::MAX,
+            len: 1,
+            ..Default::default()
+        };
+        assert!(invalid(message.slice(huge)));
+    }
+
+    #[test]
+    fn invalid_utf8_is_rejected() {
+        let buf = message(64, |builder| {
+            let frame =
+                builder.vector_with(2, |bytes: &mut [u8]| bytes.copy_from_slice(&[0xc3, 0x28]));
+            let frame = frame.unwrap();
+            builder.root_mut().frame = Str {
+                offset: frame.offset,
+                len: frame.len,
+            };
+        });
+        let message = Message::<Scan>::new(bytes(&buf)).unwrap();
+        assert!(invalid(message.str(message.root().frame)));
+    }
+
+    #[test]
+    fn short_message_is_rejected() {
+        let buf = message(64, |_| {});
+        assert!(invalid(Message::<Scan>::new(
+            &bytes(&buf)[..std::mem::size_of::<Scan>() - 1]
+        )));
+    }
+
+    #[test]
+    fn reserving_past_the_buffer_fails() {
+        let capacity = std::mem::size_of::<Scan>() + 8;
+        message(capacity, |builder| {
+            let len = builder.len();
+            assert!(matches!(
+                builder.push_str("too long"),
+                Ok(Str { len: 8, .. })
+            ));
+            assert!(matches!(
+                builder.push_slice(&[1u32]),
+                Err(Error::TooLarge { capacity: c, .. }) if c == capacity
+            ));
+            // Overflowing sizes are refused rather than wrapped
+            assert!(matches!(
+                builder.vector_with::<u64, _>(usize::MAX, |_| {}),
+                Err(Error::TooLarge {
+                    len: usize::MAX,
+                    ..
+                })
+            ));
+            assert_eq!(builder.len(), len + 8);
+        });
+        let mut buf = vec![0u64; 8];
+        // SAFETY: `buf` holds 64 bytes.
+        let bytes = unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<u8>(), 64) };
+        assert!(matches!(
+            Builder::<Scan>::new(&mut bytes[1..]),
+            Err(Error::Layout(LayoutError::Misaligned { .. }))
+        ));
+    }
+
+    /// A channel of a single slot, which checks the length returned by the
+    /// closure as channels do.
+    struct Slot {
+        header: Header,
+        buf: Vec<u64>,
+        filled: Option<usize>,
+    }
+
+    impl Slot {
+        fn new(capacity: usize) -> Self {
+            let mut header = Header::new::<seqlock::SharedData>(0, capacity);
+            header.message = MessageType::arena::<Scan>();
+            Slot {
+                header,
+                buf: vec![0; capacity.div_ceil(8)],
+                filled: None,
+            }
+        }
+    }
+
+    impl RawProducer for Slot {
+        type Published = usize;
+
+        fn header(&self) -> &Header {
+            &self.header
+        }
+
+        fn publish_raw<F>(&mut self, _deadline: &Deadline, f: F) -> Result<usize>
+        where
+            F: FnOnce(&mut [u8]) -> usize,
+        {
+            let capacity = self.header.capacity as usize;
+            // SAFETY: `buf` holds at least `capacity` bytes.
+            let buf = unsafe {
+                std::slice::from_raw_parts_mut(self.buf.as_mut_ptr().cast::<u8>(), capacity)
+            };
+            let len = f(buf);
+            self.filled = Some(len);
+            check_len(len, capacity)?;
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn overfilled_slot_is_not_published() {
+        let mut producer = Producer::<Slot, Scan>::new(Slot::new(64)).unwrap();
+        let deadline = Deadline::never();
+        let len = producer
+            .publish_with(&deadline, |builder| {
+                builder.root_mut().frame = builder.push_str("lidar")?;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(producer.channel().filled, Some(len));
+
+        let result = producer.publish_with(&deadline, |builder| {
+            builder.push_slice(&[0u32; 16])?;
+            Ok(())
+        });
+        assert!(matches!(result, Err(Error::TooLarge { capacity: 64, .. })));
+        // The channel was handed a length it refuses, aborting the publication
+        assert_eq!(producer.channel().filled, Some(usize::MAX));
+    }
+}
@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use zshm::cli::{Args, Defaults};
+use zshm::spsc::Consumer;
+use zshm::{CancellationToken, Deadline, Error};
+
+fn main() {
+    let args = Args::parse(
+        "SPSC ring consumer on tokio",
+        Defaults {
+            key_expr: "shm/spsc/buffer",
+            delay: Duration::from_millis(500),
+            ..Default::default()
+        },
+    );
+
+    // Set up Ctrl-C handler
+    let token = CancellationToken::new();
+    let t = token.clone();
+
+    ctrlc::set_handler(move || {
+        println!("\nReceived Ctrl-C! Shutting down gracefully...");
+        t.cancel();
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .expect("Failed to start the runtime")
+        .block_on(run(args, token));
+}
+
+async fn run(args: Args, token: CancellationToken) {
+    let z = zenoh::open(args.config.clone())
+        .await
+        .expect("Failed to open Zenoh session");
+
+    let mut consumer = match Consumer::attach_async(&z, &args.key_expr).await {
+        Ok(consumer) => consumer,
+        Err(e) => {
+            println!("Failed to attach: {e}");
+            return;
+        }
+    };
+    println!("Received SHM buffer");
+
+    let deadline = Deadline::never().cancellable(token);
+    let mut received = 0;
+    loop {
+        let consumed = consumer
+            .recv_async(&deadline, |sn, data| {
+                let sum: u32 = data.iter().map(|&b| b as u32).sum();
+                println!(
+                    "{sn} - Consumed buffer of {} bytes with sum {sum}",
+                    data.len()
+                );
+            })
+            .await;
+        match consumed {
+            Ok(()) => {}
+            Err(Error::Disconnected) => {
+                println!("Producer is gone");
+                break;
+            }
+            Err(Error::Cancelled) => break,
+            Err(e) => {
+                println!("Failed to receive: {e}");
+                break;
+            }
+        }
+        // Just simulate some processing time, without blocking the runtime
+        if !args.delay.is_zero() {
+            tokio::time::sleep(args.delay).await;
+        }
+        received += 1;
+        if args.done(received) {
+            break;
+        }
+    }
+    println!("Async SPSC consumer stopped.");
+}
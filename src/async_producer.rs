@@ -0,0 +1,63 @@
+use rand::random;
+use zshm::Deadline;
+use zshm::cli::{Args, Defaults};
+use zshm::spsc::Producer;
+
+const SLOT_COUNT: usize = 16;
+
+fn main() {
+    let args = Args::parse(
+        "SPSC ring producer on tokio",
+        Defaults {
+            key_expr: "shm/spsc/buffer",
+            rate: 10.0,
+            ..Default::default()
+        },
+    );
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .expect("Failed to start the runtime")
+        .block_on(run(args));
+}
+
+async fn run(args: Args) {
+    let z = zenoh::open(args.config.clone())
+        .await
+        .expect("Failed to open Zenoh session");
+
+    let mut producer = Producer::new_async(&z, &args.key_expr, SLOT_COUNT, args.size)
+        .await
+        .expect("Failed to create producer");
+
+    // producer loop
+    for n in 1.. {
+        let mut sum: usize = 0;
+        let mut len = 0;
+        let sn = producer
+            .publish_async(&Deadline::never(), |data| {
+                len = args.payload_len();
+                for b in &mut data[..len] {
+                    *b = random();
+                    sum += *b as usize;
+                }
+                len
+            })
+            .await
+            .expect("Failed to publish");
+        println!(
+            "{} - Produced buffer of {} bytes with sum of {} ({} in flight)",
+            sn,
+            len,
+            sum,
+            producer.len()
+        );
+        if args.done(n) {
+            break;
+        }
+        if let Some(period) = args.period() {
+            tokio::time::sleep(period).await;
+        }
+    }
+}
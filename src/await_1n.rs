@@ -0,0 +1,588 @@
+//! 1:N futex-await channel (Linux only).
+//!
+//! Same read-credit protocol as [`crate::polling_1n`], but instead of polling
+//! both sides block on shared [`Event`]s. `state` is `1` while a sample is
+//! available and `0` once the slot is free for the producer; `ready` is
+//! notified on every publication and `free` whenever the slot is handed back
+//! or a consumer joins. The producer also wakes up every [`REAP_INTERVAL`] to
+//! reap dead consumers, and consumers every [`CHECK_INTERVAL`] to check that
+//! the producer is alive.
+//!
+//! With [`Backend::EventFd`], blocking calls wait on eventfds instead, which
+//! consumers can also poll along with other descriptors, see
+//! [`crate::eventfd`].
+
+use std::os::fd::{AsFd, BorrowedFd};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use zenoh::Session;
+use zenoh::bytes::ZBytes;
+
+use crate::Error;
+use crate::Result;
+#[cfg(feature = "tokio")]
+use crate::bridge;
+use crate::consumers::{Consumers, Membership};
+use crate::deadline::Deadline;
+use crate::discovery::Channel;
+use crate::eventfd::{Link, Registry};
+use crate::header::{Header, Layout, LayoutError, MessageType, Protocol};
+use crate::lease::{CHECK_INTERVAL, Heartbeat};
+use crate::notify::Event;
+use crate::pod::{self, RawConsumer, RawProducer, ShmPod};
+use crate::segment::{self, Segment, Served, check_len};
+use crate::stats::{ConsumerCounters, ConsumerStats, Report, Stopwatch};
+
+/// How often a blocked producer checks for dead consumers.
+pub const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How the producer and the consumers of a mailbox wake each other up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Backend {
+    /// Futexes living in the segment.
+    #[default]
+    Futex = 0,
+    /// eventfds exchanged over a Unix domain socket, see [`crate::eventfd`].
+    EventFd = 1,
+}
+
+// Shared data, followed by `header.capacity` bytes of payload
+#[repr(C)]
+pub struct SharedData {
+    pub header: Header,
+    pub state: AtomicU32,
+    pub ready: Event,
+    pub free: Event,
+    pub len: AtomicUsize,
+    pub sn: AtomicU64,
+    pub backend: u32,      // A `Backend`
+    pub socket: AtomicU64, // Id of the eventfd socket, with `Backend::EventFd`
+    pub consumers: Consumers,
+}
+
+unsafe impl Layout for SharedData {
+    const PROTOCOL: Protocol = Protocol::Await1N;
+    const VERSION: u32 = 12;
+
+    fn tail_len(&self) -> usize {
+        self.header.capacity as usize
+    }
+}
+
+impl Report for SharedData {
+    fn consumers(shared: &Segment<Self>) -> Vec<ConsumerStats> {
+        shared.consumers.stats()
+    }
+}
+
+fn allocate(
+    capacity: usize,
+    message: MessageType,
+    backend: Backend,
+) -> Result<(Segment<SharedData>, Option<Registry>)> {
+    let shared = Segment::<SharedData>::create_with(capacity, capacity, |shared| {
+        shared.header.message = message;
+        shared.backend = backend as u32;
+    })?;
+    let registry = match backend {
+        Backend::Futex => None,
+        Backend::EventFd => {
+            let s = shared.clone();
+            let registry = Registry::start(move |m| s.consumers.is_member(m))?;
+            // Not served yet, so no consumer can miss it
+            shared.socket.store(registry.socket(), Ordering::Release);
+            Some(registry)
+        }
+    };
+    Ok((shared, registry))
+}
+
+pub struct Producer {
+    shared: Segment<SharedData>,
+    registry: Option<Registry>,
+    _served: Served,
+}
+
+impl Producer {
+    /// Allocates a mailbox holding up to `capacity` bytes and serves it on
+    /// `key_expr`.
+    pub fn new(session: &Session, key_expr: &str, capacity: usize) -> Result<Self> {
+        Self::with_message(session, key_expr, capacity, MessageType::BYTES)
+    }
+
+    /// Allocates a mailbox holding one `M` message and serves it on
+    /// `key_expr`.
+    pub fn typed<M: ShmPod>(session: &Session, key_expr: &str) -> Result<pod::Producer<Self, M>> {
+        let producer = Self::with_message(
+            session,
+            key_expr,
+            std::mem::size_of::<M>(),
+            MessageType::of::<M>(),
+        )?;
+        pod::Producer::new(producer)
+    }
+
+    /// Same as [`Producer::new`], but records the type of the messages
+    /// carried in the header, see [`crate::pod`] and [`crate::arena`].
+    pub fn with_message(
+        session: &Session,
+        key_expr: &str,
+        capacity: usize,
+        message: MessageType,
+    ) -> Result<Self> {
+        Self::with_backend(session, key_expr, capacity, message, Backend::Futex)
+    }
+
+    /// Same as [`Producer::with_message`], but lets the producer and the
+    /// consumers wake each other up through `backend`.
+    pub fn with_backend(
+        session: &Session,
+        key_expr: &str,
+        capacity: usize,
+        message: MessageType,
+        backend: Backend,
+    ) -> Result<Self> {
+        let (shared, registry) = allocate(capacity, message, backend)?;
+        let _served = segment::serve(session, key_expr, &shared)?;
+        Ok(Producer {
+            shared,
+            registry,
+            _served,
+        })
+    }
+
+    /// Payload capacity of the mailbox.
+    pub fn capacity(&self) -> usize {
+        self.shared.header.capacity as usize
+    }
+
+    /// Number of currently registered consumers.
+    pub fn subscribers(&self) -> usize {
+        self.shared.consumers.active()
+    }
+
+    /// With [`Backend::EventFd`], a descriptor that becomes readable when
+    /// the mailbox may have been handed back or a consumer joined, after
+    /// which [`Producer::try_publish_with`] is worth calling.
+    pub fn fd(&self) -> Option<BorrowedFd<'_>> {
+        self.registry.as_ref().map(|r| r.free().as_fd())
+    }
+
+    /// Writes a sample if at least one consumer is registered and the
+    /// previous sample has been read by all of them. Returns the sequence
+    /// number of the new sample, or `None` if the mailbox is not free.
+    pub fn try_publish_with<F>(&mut self, f: F) -> Result<Option<u64>>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        if let Some(registry) = &self.registry {
+            registry.free().drain();
+        }
+        if !self.is_free() {
+            return Ok(None);
+        }
+        self.fill(f).map(Some)
+    }
+
+    /// Copies `data` into the mailbox once it is free, see
+    /// [`Producer::publish_with`]. Fails with [`Error::TooLarge`] if `data`
+    /// exceeds the capacity.
+    pub fn publish(&mut self, deadline: &Deadline, data: &[u8]) -> Result<u64> {
+        check_len(data.len(), self.capacity())?;
+        self.publish_with(deadline, |buf| {
+            buf[..data.len()].copy_from_slice(data);
+            data.len()
+        })
+    }
+
+    /// Waits until at least one consumer is registered and the previous
+    /// sample has been read by all of them, then writes a sample and wakes
+    /// the consumers. `f` fills the buffer and returns the number of bytes
+    /// written. Returns the sequence number of the new sample, or fails with
+    /// [`Error::TooLarge`], publishing nothing, if `f` returns more than the
+    /// capacity.
+    pub fn publish_with<F>(&mut self, deadline: &Deadline, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        // Wait until the subscriber is ready and the data is consumed
+        let mut blocked = Stopwatch::new();
+        loop {
+            let seen = self.shared.free.generation();
+            if let Some(registry) = &self.registry {
+                registry.free().drain();
+            }
+            if self.is_free() {
+                break;
+            }
+            blocked.start();
+            match &self.registry {
+                Some(registry) => registry.free().wait_at_most(deadline, REAP_INTERVAL)?,
+                None => deadline.wait_at_most(&self.shared.free, seen, REAP_INTERVAL)?,
+            }
+        }
+        log::debug!("Done Waiting...");
+        self.shared.header.stats.blocked(&blocked);
+        self.fill(f)
+    }
+
+    /// Reaps dead consumers and checks whether a sample can be written.
+    fn is_free(&self) -> bool {
+        let shared = &*self.shared;
+        if shared.consumers.reap() {
+            // A dead consumer held the last credit
+            shared.state.store(0, Ordering::Release);
+        }
+        if shared.consumers.active() != 0 && shared.state.load(Ordering::Acquire) == 0 {
+            return true;
+        }
+        log::debug!(
+            "Waiting for data to be consumed, state: {}",
+            shared.state.load(Ordering::Acquire)
+        );
+        false
+    }
+
+    fn fill<F>(&mut self, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        let shared = &*self.shared;
+        let capacity = self.capacity();
+        // SAFETY: consumers do not touch the payload while the state is 0.
+        let len = f(unsafe { std::slice::from_raw_parts_mut(self.shared.tail(), capacity) });
+        check_len(len, capacity)?;
+        let sn = shared.sn.fetch_add(1, Ordering::AcqRel) + 1;
+
+        shared.header.stats.published();
+        shared.len.store(len, Ordering::Release);
+
+        log::debug!("{sn} - Data ready, waking up consumers");
+        shared.state.store(1, Ordering::Release);
+        if shared.consumers.assign(sn) {
+            // Every consumer left meanwhile
+            shared.state.store(0, Ordering::Release);
+        }
+        // Notify all consumers that data is ready
+        self.notify_consumers();
+        Ok(sn)
+    }
+
+    fn notify_consumers(&self) {
+        self.shared.ready.notify_all();
+        if let Some(registry) = &self.registry {
+            registry.notify_all(|m| self.shared.consumers.is_member(m));
+        }
+    }
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.shared.header.producer.close();
+        self.notify_consumers();
+    }
+}
+
+pub struct Consumer {
+    shared: Segment<SharedData>,
+    membership: Membership,
+    link: Option<Link>,
+    _heartbeat: Heartbeat,
+}
+
+impl Consumer {
+    /// Fetches the segment served on `key_expr` and registers as a consumer.
+    /// The consumer receives samples published from now on.
+    pub fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        Self::open(segment::fetch(session, key_expr)?)
+    }
+
+    /// Attaches to a channel found by [`crate::discovery`].
+    pub fn from_channel(channel: &Channel) -> Result<Self> {
+        Self::open(channel.payload().clone())
+    }
+
+    fn open(payload: ZBytes) -> Result<Self> {
+        let shared = Segment::<SharedData>::attach(payload)?;
+        let backend = match shared.backend {
+            0 => Backend::Futex,
+            1 => Backend::EventFd,
+            value => {
+                return Err(LayoutError::Field {
+                    name: "backend",
+                    value: value as u64,
+                }
+                .into());
+            }
+        };
+        let membership = shared.consumers.join()?;
+        let _heartbeat = Heartbeat::start(shared.clone(), move |s: &SharedData| {
+            s.consumers.beat(membership)
+        });
+        let mut consumer = Consumer {
+            shared,
+            membership,
+            link: None,
+            _heartbeat,
+        };
+        if backend == Backend::EventFd {
+            // Leaves again through `drop` on failure
+            let socket = consumer.shared.socket.load(Ordering::Acquire);
+            consumer.link = Some(Link::connect(socket, membership)?);
+        }
+        consumer.wake_producer();
+        Ok(consumer)
+    }
+
+    /// Payload capacity of the mailbox.
+    pub fn capacity(&self) -> usize {
+        self.shared.header.capacity as usize
+    }
+
+    /// Whether the producer is still alive and has not closed the channel,
+    /// and has not evicted this consumer.
+    pub fn is_connected(&self) -> bool {
+        !self.shared.header.producer.is_gone() && self.shared.consumers.is_member(self.membership)
+    }
+
+    /// With [`Backend::EventFd`], a descriptor that becomes readable when a
+    /// sample may be available or the producer closed the channel, after
+    /// which [`Consumer::try_recv_with`] is worth calling. A producer that
+    /// dies does not make it readable, so pollers should also check
+    /// [`Consumer::is_connected`] every [`CHECK_INTERVAL`] or so.
+    pub fn fd(&self) -> Option<BorrowedFd<'_>> {
+        self.link.as_ref().map(|l| l.ready.as_fd())
+    }
+
+    /// Hands the current sample and its sequence number to `f` if we have
+    /// not read it yet. Returns `None` otherwise, including once the consumer
+    /// was evicted, which [`Consumer::is_connected`] tells.
+    pub fn try_recv_with<F, R>(&mut self, f: F) -> Option<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        if let Some(link) = &self.link {
+            link.ready.drain();
+        }
+        let sn = self.owed()?;
+        Some(self.consume(sn, f))
+    }
+
+    /// Blocks until a sample we have not read yet is available and hands it
+    /// and its sequence number to `f`. Fails with [`Error::Disconnected`] once
+    /// the producer is gone and every sample has been read, and with
+    /// [`Error::Evicted`] once the producer reaped this consumer.
+    pub fn recv_with<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let mut waited = Stopwatch::new();
+        loop {
+            let seen = self.shared.ready.generation();
+            if let Some(link) = &self.link {
+                link.ready.drain();
+            }
+            let gone = self.shared.header.producer.is_gone();
+            if let Some(sn) = self.owed() {
+                self.counters().waited(&self.shared.header.stats, &waited);
+                return Ok(self.consume(sn, f));
+            }
+            if gone {
+                return Err(Error::Disconnected);
+            }
+            if !self.shared.consumers.is_member(self.membership) {
+                return Err(Error::Evicted);
+            }
+            waited.start();
+            match &self.link {
+                Some(link) => link.ready.wait_at_most(deadline, CHECK_INTERVAL)?,
+                None => deadline.wait_at_most(&self.shared.ready, seen, CHECK_INTERVAL)?,
+            }
+        }
+    }
+
+    /// Sequence number of the current sample if we hold a credit on it,
+    /// i.e. have not read it yet.
+    fn owed(&self) -> Option<u64> {
+        log::debug!(
+            "Waiting for data to be produced -- state: {}",
+            self.shared.state.load(Ordering::Acquire)
+        );
+        self.shared.consumers.owed(self.membership)
+    }
+
+    fn counters(&self) -> &ConsumerCounters {
+        self.shared.consumers.counters(self.membership)
+    }
+
+    fn consume<F, R>(&mut self, sn: u64, f: F) -> R
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let len = self.shared.len.load(Ordering::Acquire).min(self.capacity());
+        // SAFETY: the producer does not touch the payload while the state is 1.
+        let r = f(sn, unsafe {
+            std::slice::from_raw_parts(self.shared.tail(), len)
+        });
+        self.counters().read(&self.shared.header.stats);
+        if self.shared.consumers.release(self.membership, sn) {
+            log::debug!("{sn} - Last read, resetting length");
+            self.release();
+        }
+        r
+    }
+
+    /// Hands the slot back to the producer.
+    fn release(&self) {
+        self.shared.state.store(0, Ordering::Release);
+        // Notify the producer that we are done consuming
+        self.wake_producer();
+    }
+
+    fn wake_producer(&self) {
+        self.shared.free.notify_one();
+        if let Some(link) = &self.link {
+            link.free.notify();
+        }
+    }
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        if self.shared.consumers.leave(self.membership) {
+            self.release();
+        }
+    }
+}
+
+impl RawProducer for Producer {
+    type Published = u64;
+
+    fn header(&self) -> &Header {
+        &self.shared.header
+    }
+
+    fn publish_raw<F>(&mut self, deadline: &Deadline, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        self.publish_with(deadline, f)
+    }
+}
+
+impl RawConsumer for Consumer {
+    type Shared = SharedData;
+
+    fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        Consumer::attach(session, key_expr)
+    }
+
+    fn open(payload: ZBytes) -> Result<Self> {
+        Consumer::open(payload)
+    }
+
+    fn header(&self) -> &Header {
+        &self.shared.header
+    }
+
+    fn recv_raw<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        self.recv_with(deadline, f)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Producer {
+    /// Async version of [`Producer::new`].
+    pub async fn new_async(session: &Session, key_expr: &str, capacity: usize) -> Result<Self> {
+        Self::with_message_async(session, key_expr, capacity, MessageType::BYTES).await
+    }
+
+    /// Async version of [`Producer::with_message`].
+    pub async fn with_message_async(
+        session: &Session,
+        key_expr: &str,
+        capacity: usize,
+        message: MessageType,
+    ) -> Result<Self> {
+        Self::with_backend_async(session, key_expr, capacity, message, Backend::Futex).await
+    }
+
+    /// Async version of [`Producer::with_backend`].
+    pub async fn with_backend_async(
+        session: &Session,
+        key_expr: &str,
+        capacity: usize,
+        message: MessageType,
+        backend: Backend,
+    ) -> Result<Self> {
+        let (shared, registry) = allocate(capacity, message, backend)?;
+        let _served = bridge::serve(session, key_expr, &shared).await?;
+        Ok(Producer {
+            shared,
+            registry,
+            _served,
+        })
+    }
+
+    /// Async version of [`Producer::publish_with`].
+    pub async fn publish_async<F>(&mut self, deadline: &Deadline, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        let mut blocked = Stopwatch::new();
+        loop {
+            let seen = self.shared.free.generation();
+            // Consumers notify the futex with either backend
+            if let Some(registry) = &self.registry {
+                registry.free().drain();
+            }
+            if self.is_free() {
+                self.shared.header.stats.blocked(&blocked);
+                return self.fill(f);
+            }
+            blocked.start();
+            bridge::wait(&self.shared, |s| &s.free, seen, deadline).await?;
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Consumer {
+    /// Async version of [`Consumer::attach`].
+    pub async fn attach_async(session: &Session, key_expr: &str) -> Result<Self> {
+        Self::open(bridge::fetch(session, key_expr).await?)
+    }
+
+    /// Async version of [`Consumer::recv_with`].
+    pub async fn recv_async<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let mut waited = Stopwatch::new();
+        loop {
+            let seen = self.shared.ready.generation();
+            // The producer notifies the futex with either backend
+            if let Some(link) = &self.link {
+                link.ready.drain();
+            }
+            let gone = self.shared.header.producer.is_gone();
+            if let Some(sn) = self.owed() {
+                self.counters().waited(&self.shared.header.stats, &waited);
+                return Ok(self.consume(sn, f));
+            }
+            if gone {
+                return Err(Error::Disconnected);
+            }
+            if !self.shared.consumers.is_member(self.membership) {
+                return Err(Error::Evicted);
+            }
+            waited.start();
+            bridge::wait(&self.shared, |s| &s.ready, seen, deadline).await?;
+        }
+    }
+}
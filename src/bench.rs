@@ -0,0 +1,591 @@
+//! Latency and throughput of every protocol, with the producer and each
+//! consumer in a process of its own.
+//!
+//! For each protocol, payload size and consumer count, the benchmark serves a
+//! channel, spawns the consumers by running itself again with `--consumer`
+//! and, once they are all attached, publishes `--warmup` samples followed by
+//! `-n` measured ones, as fast as possible or at `-r` Hz. Every sample starts
+//! with the time it was published, read from the system clock, from which
+//! consumers measure one-way latency. `put` is the baseline of plain Zenoh
+//! publications of SHM buffers, as done by `put_shm` and `sub_shm`.
+//!
+//! Throughput is the sum over consumers of the samples each received per
+//! second, so that it counts every copy of a broadcast sample and every share
+//! of an `mpmc` queue. Percentiles are those of the slowest consumer.
+
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdout, Command as Process, Stdio};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::{Arg, ArgAction, ArgMatches, Command, value_parser};
+use zenoh::shm::ShmProviderBuilder;
+use zenoh::{Session, Wait};
+use zshm::cli::{Args, Defaults};
+use zshm::pod::{RawConsumer, RawProducer};
+use zshm::{Deadline, Error, Protocol, broadcast, mpmc, polling, polling_1n, seqlock, spsc};
+
+/// How long a publication may block on consumers before the run is aborted.
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long `put` consumers wait for a sample before giving up.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time given to `put` subscriptions to reach the producer.
+const SUBSCRIBE_DELAY: Duration = Duration::from_millis(500);
+
+/// Publication time and sequence number, at the start of every sample.
+const STAMP_LEN: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Channel(Protocol),
+    Put,
+}
+
+impl Kind {
+    fn parse(name: &str) -> Result<Self, String> {
+        if name == "put" {
+            return Ok(Kind::Put);
+        }
+        (1u32..)
+            .map_while(Protocol::from_u32)
+            .find(|p| p.to_string() == name)
+            .map(Kind::Channel)
+            .ok_or_else(|| format!("unknown protocol '{name}'"))
+    }
+
+    /// Whether the protocol serves a single consumer.
+    fn is_one_to_one(self) -> bool {
+        matches!(self, Kind::Channel(Protocol::Polling | Protocol::Spsc))
+    }
+}
+
+impl std::fmt::Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Kind::Channel(protocol) => protocol.fmt(f),
+            Kind::Put => f.write_str("put"),
+        }
+    }
+}
+
+/// One point of the sweep.
+struct Run {
+    kind: Kind,
+    key_expr: String,
+    size: usize,
+    consumers: usize,
+    slots: usize,
+    warmup: u64,
+    count: u64,
+    period: Option<Duration>,
+}
+
+impl Run {
+    fn total(&self) -> u64 {
+        self.warmup + self.count
+    }
+}
+
+/// What a consumer measured, as reported to the producer on one line.
+struct Stats {
+    received: u64,
+    bytes: u64,
+    elapsed: Duration,
+    p50: u64, // Nanoseconds
+    p99: u64,
+    p999: u64,
+}
+
+impl Stats {
+    fn to_line(&self) -> String {
+        format!(
+            "result {} {} {} {} {} {}",
+            self.received,
+            self.bytes,
+            self.elapsed.as_nanos(),
+            self.p50,
+            self.p99,
+            self.p999
+        )
+    }
+
+    fn from_line(line: &str) -> Result<Self, String> {
+        let fields: Vec<u64> = line
+            .strip_prefix("result ")
+            .ok_or_else(|| line.to_string())?
+            .split(' ')
+            .map(|f| f.parse().map_err(|_| format!("malformed result '{line}'")))
+            .collect::<Result<_, _>>()?;
+        let [received, bytes, elapsed, p50, p99, p999] = fields[..] else {
+            return Err(format!("malformed result '{line}'"));
+        };
+        Ok(Stats {
+            received,
+            bytes,
+            elapsed: Duration::from_nanos(elapsed),
+            p50,
+            p99,
+            p999,
+        })
+    }
+
+    fn rate(&self, n: u64) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 { n as f64 / secs } else { 0.0 }
+    }
+}
+
+/// Collects the latencies of the samples received after the warmup.
+struct Recorder {
+    warmup: u64,
+    latencies: Vec<u64>,
+    bytes: u64,
+    first: Option<Instant>,
+    last: Option<Instant>,
+}
+
+impl Recorder {
+    fn new(warmup: u64, count: u64) -> Self {
+        Recorder {
+            warmup,
+            latencies: Vec::with_capacity(count as usize),
+            bytes: 0,
+            first: None,
+            last: None,
+        }
+    }
+
+    fn record(&mut self, sn: u64, data: &[u8]) {
+        let received = now_nanos();
+        if sn <= self.warmup || data.len() < STAMP_LEN {
+            return;
+        }
+        let published = u64::from_le_bytes(data[..8].try_into().unwrap());
+        self.latencies.push(received.saturating_sub(published));
+        self.bytes += data.len() as u64;
+        let now = Instant::now();
+        self.first.get_or_insert(now);
+        self.last = Some(now);
+    }
+
+    fn finish(mut self) -> Stats {
+        self.latencies.sort_unstable();
+        let percentile = |q: f64| match self.latencies.len() {
+            0 => 0,
+            n => self.latencies[((n - 1) as f64 * q).round() as usize],
+        };
+        Stats {
+            received: self.latencies.len() as u64,
+            bytes: self.bytes,
+            elapsed: match (self.first, self.last) {
+                (Some(first), Some(last)) => last - first,
+                _ => Duration::ZERO,
+            },
+            p50: percentile(0.5),
+            p99: percentile(0.99),
+            p999: percentile(0.999),
+        }
+    }
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// Writes the publication time and sequence number at the start of `buf`.
+fn stamp(buf: &mut [u8], sn: u64) {
+    buf[8..STAMP_LEN].copy_from_slice(&sn.to_le_bytes());
+    buf[..8].copy_from_slice(&now_nanos().to_le_bytes());
+}
+
+fn options(command: Command) -> Command {
+    let list = |name: &'static str, value_name: &'static str, help: &'static str| {
+        Arg::new(name)
+            .long(name)
+            .value_name(value_name)
+            .value_delimiter(',')
+            .help(help)
+    };
+    command
+        .arg(
+            list(
+                "protocols",
+                "LIST",
+                "Protocols to run, `put` for plain Zenoh puts",
+            )
+            .default_value("polling,polling-1n,await-1n,spsc,broadcast,mpmc,seqlock,put"),
+        )
+        .arg(
+            list("sizes", "BYTES", "Payload sizes to sweep")
+                .value_parser(value_parser!(usize))
+                .default_value("64,4096,65536,1048576"),
+        )
+        .arg(
+            list("consumers", "N", "Consumer counts to sweep")
+                .value_parser(value_parser!(usize))
+                .default_value("1,4"),
+        )
+        .arg(
+            Arg::new("warmup")
+                .long("warmup")
+                .value_name("N")
+                .value_parser(value_parser!(u64))
+                .default_value("100")
+                .help("Samples published before measuring"),
+        )
+        .arg(
+            Arg::new("slots")
+                .long("slots")
+                .value_name("N")
+                .value_parser(value_parser!(usize))
+                .default_value("16")
+                .help("Slots of the rings and queues"),
+        )
+        .arg(
+            Arg::new("consumer")
+                .long("consumer")
+                .action(ArgAction::SetTrue)
+                .hide(true),
+        )
+        .arg(Arg::new("protocol").long("protocol").hide(true))
+}
+
+fn main() {
+    let (args, m) = Args::parse_with(
+        "Latency and throughput benchmark of every protocol",
+        Defaults {
+            key_expr: "bench",
+            ..Default::default()
+        },
+        options,
+    );
+    let warmup = *m.get_one::<u64>("warmup").unwrap();
+    let count = args.count.unwrap_or(10_000);
+
+    let z = args.open().expect("Failed to open Zenoh session");
+
+    if m.get_flag("consumer") {
+        let kind = m
+            .get_one::<String>("protocol")
+            .map(|name| Kind::parse(name))
+            .expect("--consumer requires --protocol");
+        let result = kind.and_then(|kind| {
+            consumer(&z, kind, &args.key_expr, warmup, count).map_err(|e| e.to_string())
+        });
+        match result {
+            Ok(stats) => println!("{}", stats.to_line()),
+            Err(e) => println!("error {e}"),
+        }
+        return;
+    }
+
+    let kinds: Vec<Kind> = m
+        .get_many::<String>("protocols")
+        .unwrap()
+        .map(|name| Kind::parse(name))
+        .collect::<Result<_, _>>()
+        .unwrap_or_else(|e| {
+            eprintln!("{e}");
+            std::process::exit(2);
+        });
+    println!(
+        "{:<10} {:>8} {:>4} {:>9} {:>11} {:>9} {:>10} {:>10} {:>10}",
+        "protocol",
+        "size",
+        "cons",
+        "received",
+        "msg/s",
+        "MB/s",
+        "p50 (us)",
+        "p99 (us)",
+        "p999 (us)"
+    );
+    for &kind in &kinds {
+        for &size in m.get_many::<usize>("sizes").unwrap() {
+            for &consumers in m.get_many::<usize>("consumers").unwrap() {
+                let run = Run {
+                    kind,
+                    key_expr: format!("{}/{kind}/{size}/{consumers}", args.key_expr),
+                    size: size.max(STAMP_LEN),
+                    consumers,
+                    slots: *m.get_one::<usize>("slots").unwrap(),
+                    warmup,
+                    count,
+                    period: args.period(),
+                };
+                report(&run, producer(&z, &m, &run));
+            }
+        }
+    }
+}
+
+fn report(run: &Run, result: Result<Vec<Stats>, String>) {
+    let head = format!(
+        "{:<10} {:>8} {:>4}",
+        run.kind.to_string(),
+        run.size,
+        run.consumers
+    );
+    let stats = match result {
+        Ok(stats) => stats,
+        Err(e) => {
+            println!("{head} {e}");
+            return;
+        }
+    };
+    let received: u64 = stats.iter().map(|s| s.received).sum();
+    let rate: f64 = stats.iter().map(|s| s.rate(s.received)).sum();
+    let bandwidth: f64 = stats.iter().map(|s| s.rate(s.bytes)).sum();
+    let worst = |f: fn(&Stats) -> u64| stats.iter().map(f).max().unwrap_or(0) as f64 / 1e3;
+    println!(
+        "{head} {received:>9} {rate:>11.0} {:>9.1} {:>10.1} {:>10.1} {:>10.1}",
+        bandwidth / 1e6,
+        worst(|s| s.p50),
+        worst(|s| s.p99),
+        worst(|s| s.p999)
+    );
+}
+
+/// Serves the channel of `run`, publishes to its consumers and gathers what
+/// they measured.
+fn producer(z: &Session, m: &ArgMatches, run: &Run) -> Result<Vec<Stats>, String> {
+    if run.kind.is_one_to_one() && run.consumers > 1 {
+        return Err("skipped, single consumer only".to_string());
+    }
+    let (size, slots, key) = (run.size, run.slots, run.key_expr.as_str());
+    let consumers = run.consumers;
+    let e = |e: Error| e.to_string();
+    match run.kind {
+        Kind::Channel(Protocol::Polling) => {
+            serve(m, run, polling::Producer::new(z, key, size).map_err(e)?)
+        }
+        Kind::Channel(Protocol::Polling1N) => {
+            serve(m, run, polling_1n::Producer::new(z, key, size).map_err(e)?)
+        }
+        #[cfg(target_os = "linux")]
+        Kind::Channel(Protocol::Await1N) => serve(
+            m,
+            run,
+            zshm::await_1n::Producer::new(z, key, size).map_err(e)?,
+        ),
+        #[cfg(not(target_os = "linux"))]
+        Kind::Channel(Protocol::Await1N) => Err("skipped, Linux only".to_string()),
+        Kind::Channel(Protocol::Spsc) => {
+            serve(m, run, spsc::Producer::new(z, key, slots, size).map_err(e)?)
+        }
+        Kind::Channel(Protocol::Broadcast) => {
+            let policy = broadcast::Policy::Block;
+            let producer = broadcast::Producer::new(z, key, slots, size, consumers, policy);
+            serve(m, run, producer.map_err(e)?)
+        }
+        Kind::Channel(Protocol::Mpmc) => {
+            serve(m, run, mpmc::Producer::new(z, key, slots, size).map_err(e)?)
+        }
+        Kind::Channel(Protocol::Seqlock) => {
+            serve(m, run, seqlock::Producer::new(z, key, size).map_err(e)?)
+        }
+        Kind::Put => put(z, m, run),
+    }
+}
+
+/// Publishes the samples of `run` on `producer` once its consumers attached.
+fn serve<P: RawProducer>(m: &ArgMatches, run: &Run, mut producer: P) -> Result<Vec<Stats>, String> {
+    let mut consumers = spawn(m, run)?;
+    for n in 1..=run.total() {
+        let deadline = Deadline::after(PUBLISH_TIMEOUT);
+        let published = producer.publish_raw(&deadline, |buf| {
+            stamp(buf, n);
+            run.size
+        });
+        if let Err(e) = published {
+            consumers.iter_mut().for_each(Consumer::kill);
+            return Err(format!("failed to publish: {e}"));
+        }
+        if let Some(period) = run.period {
+            std::thread::sleep(period);
+        }
+    }
+    // Closes the channel, so that consumers stop once they drained it
+    drop(producer);
+    consumers.into_iter().map(Consumer::collect).collect()
+}
+
+/// Puts SHM buffers allocated for every sample, like `put_shm` does.
+fn put(z: &Session, m: &ArgMatches, run: &Run) -> Result<Vec<Stats>, String> {
+    let provider = ShmProviderBuilder::default_backend(run.size * run.slots)
+        .wait()
+        .map_err(|e| e.to_string())?;
+    let mut consumers = spawn(m, run)?;
+    std::thread::sleep(SUBSCRIBE_DELAY);
+    for n in 1..=run.total() {
+        // Buffers come back once every consumer dropped them
+        let deadline = Deadline::after(PUBLISH_TIMEOUT);
+        let mut buf = loop {
+            match provider.alloc(run.size).wait() {
+                Ok(buf) => break buf,
+                Err(_) if deadline.check().is_ok() => std::thread::yield_now(),
+                Err(e) => {
+                    consumers.iter_mut().for_each(Consumer::kill);
+                    return Err(format!("failed to allocate: {e}"));
+                }
+            }
+        };
+        stamp(&mut buf[..], n);
+        z.put(&run.key_expr, buf)
+            .wait()
+            .map_err(|e| e.to_string())?;
+        if let Some(period) = run.period {
+            std::thread::sleep(period);
+        }
+    }
+    // An empty sample marks the end of the stream
+    z.put(&run.key_expr, Vec::<u8>::new())
+        .wait()
+        .map_err(|e| e.to_string())?;
+    consumers.into_iter().map(Consumer::collect).collect()
+}
+
+/// A consumer process.
+struct Consumer {
+    child: Child,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Consumer {
+    fn read_line(&mut self) -> Result<String, String> {
+        let mut line = String::new();
+        match self.stdout.read_line(&mut line) {
+            Ok(0) => Err("consumer exited".to_string()),
+            Ok(_) => Ok(line.trim_end().to_string()),
+            Err(e) => Err(format!("failed to read from consumer: {e}")),
+        }
+    }
+
+    fn collect(mut self) -> Result<Stats, String> {
+        let line = self.read_line();
+        let _ = self.child.wait();
+        Stats::from_line(&line?)
+    }
+
+    fn kill(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Runs the consumers of `run` and waits until they all attached.
+fn spawn(m: &ArgMatches, run: &Run) -> Result<Vec<Consumer>, String> {
+    let exe = std::env::current_exe().map_err(|e| e.to_string())?;
+    let mut consumers = Vec::with_capacity(run.consumers);
+    for _ in 0..run.consumers {
+        let mut process = Process::new(&exe);
+        process
+            .args(session_args(m))
+            .args(["--consumer", "--protocol", &run.kind.to_string()])
+            .args(["-k", &run.key_expr])
+            .args(["-n", &run.count.to_string()])
+            .args(["--warmup", &run.warmup.to_string()])
+            .stdout(Stdio::piped());
+        let mut child = process.spawn().map_err(|e| e.to_string())?;
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        consumers.push(Consumer { child, stdout });
+    }
+    for i in 0..consumers.len() {
+        let ready = consumers[i].read_line();
+        if ready.as_deref() != Ok("ready") {
+            consumers.iter_mut().for_each(Consumer::kill);
+            return Err(ready.unwrap_or_else(|e| e));
+        }
+    }
+    Ok(consumers)
+}
+
+/// The session options of the benchmark, passed on to the consumers. Listen
+/// endpoints are not, as consumers cannot all listen on them.
+fn session_args(m: &ArgMatches) -> Vec<String> {
+    let mut args = Vec::new();
+    for (name, flag) in [("config", "-c"), ("mode", "-m"), ("connect", "-e")] {
+        for value in m.get_many::<String>(name).into_iter().flatten() {
+            args.extend([flag.to_string(), value.clone()]);
+        }
+    }
+    args
+}
+
+/// Tells the producer that this consumer attached.
+fn ready() {
+    println!("ready");
+    std::io::stdout().flush().unwrap();
+}
+
+/// Receives the samples of a run in a consumer process.
+fn consumer(
+    z: &Session,
+    kind: Kind,
+    key_expr: &str,
+    warmup: u64,
+    count: u64,
+) -> zshm::Result<Stats> {
+    match kind {
+        Kind::Channel(Protocol::Polling) => {
+            receive::<polling::Consumer>(z, key_expr, warmup, count)
+        }
+        Kind::Channel(Protocol::Polling1N) => {
+            receive::<polling_1n::Consumer>(z, key_expr, warmup, count)
+        }
+        #[cfg(target_os = "linux")]
+        Kind::Channel(Protocol::Await1N) => {
+            receive::<zshm::await_1n::Consumer>(z, key_expr, warmup, count)
+        }
+        #[cfg(not(target_os = "linux"))]
+        Kind::Channel(Protocol::Await1N) => Err(Error::Disconnected),
+        Kind::Channel(Protocol::Spsc) => receive::<spsc::Consumer>(z, key_expr, warmup, count),
+        Kind::Channel(Protocol::Broadcast) => {
+            receive::<broadcast::Consumer>(z, key_expr, warmup, count)
+        }
+        Kind::Channel(Protocol::Mpmc) => receive::<mpmc::Consumer>(z, key_expr, warmup, count),
+        Kind::Channel(Protocol::Seqlock) => {
+            receive::<seqlock::Consumer>(z, key_expr, warmup, count)
+        }
+        Kind::Put => subscribe(z, key_expr, warmup, count),
+    }
+}
+
+fn receive<C: RawConsumer>(
+    z: &Session,
+    key_expr: &str,
+    warmup: u64,
+    count: u64,
+) -> zshm::Result<Stats> {
+    let mut consumer = C::attach(z, key_expr)?;
+    ready();
+    let mut recorder = Recorder::new(warmup, count);
+    loop {
+        match consumer.recv_raw(&Deadline::never(), |sn, data| recorder.record(sn, data)) {
+            Ok(()) => {}
+            Err(Error::Disconnected) => return Ok(recorder.finish()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn subscribe(z: &Session, key_expr: &str, warmup: u64, count: u64) -> zshm::Result<Stats> {
+    let subscriber = z.declare_subscriber(key_expr).wait()?;
+    ready();
+    let mut recorder = Recorder::new(warmup, count);
+    while let Some(sample) = subscriber.recv_timeout(IDLE_TIMEOUT)? {
+        let payload = sample.payload().to_bytes();
+        if payload.is_empty() {
+            break;
+        }
+        if payload.len() < STAMP_LEN {
+            continue;
+        }
+        let sn = u64::from_le_bytes(payload[8..STAMP_LEN].try_into().unwrap());
+        recorder.record(sn, &payload);
+    }
+    Ok(recorder.finish())
+}
@@ -0,0 +1,79 @@
+//! Bridges waits on shared [`Event`]s to the tokio runtime.
+//!
+//! Futex waits block the calling thread, so async operations run them on
+//! tokio's blocking pool instead of an executor worker. Each wait is bounded
+//! by [`CHECK_INTERVAL`] and the caller re-checks its condition in between,
+//! so a future dropped while waiting keeps a blocking thread busy for at most
+//! that long.
+
+use zenoh::Session;
+use zenoh::bytes::ZBytes;
+
+use crate::deadline::Deadline;
+use crate::lease::CHECK_INTERVAL;
+use crate::notify::Event;
+use crate::segment::{self, Segment, Served};
+use crate::stats::{self, Report};
+use crate::{Error, Result};
+
+/// Waits until the generation of the event selected by `event` differs from
+/// `seen`, or [`CHECK_INTERVAL`] elapsed.
+pub(crate) async fn wait<T>(
+    shared: &Segment<T>,
+    event: fn(&T) -> &Event,
+    seen: u32,
+    deadline: &Deadline,
+) -> Result<()>
+where
+    T: Sync + 'static,
+{
+    deadline.check()?;
+    let shared = shared.clone();
+    let deadline = deadline.clone();
+    tokio::task::spawn_blocking(move || deadline.wait_at_most(event(&shared), seen, CHECK_INTERVAL))
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+/// Async version of [`segment::fetch`].
+pub(crate) async fn fetch(session: &Session, key_expr: &str) -> Result<ZBytes> {
+    let replies = session.get(segment::selector(key_expr)).await?;
+    let reply = replies
+        .recv_async()
+        .await
+        .map_err(|_| Error::NoProducer(key_expr.to_string()))?;
+    let sample = reply
+        .into_result()
+        .map_err(|e| Error::Reply(format!("{e:?}")))?;
+    Ok(sample.payload().clone())
+}
+
+/// Async version of [`segment::serve`].
+pub(crate) async fn serve<T: Report + 'static>(
+    session: &Session,
+    key_expr: &str,
+    shared: &Segment<T>,
+) -> Result<Served> {
+    let replies = session
+        .liveliness()
+        .get(key_expr)
+        .timeout(segment::PROBE_TIMEOUT)
+        .await?;
+    if replies
+        .recv_async()
+        .await
+        .is_ok_and(|reply| reply.result().is_ok())
+    {
+        return Err(Error::ProducerExists(key_expr.to_string()));
+    }
+    let queryable = session
+        .declare_queryable(key_expr)
+        .callback(segment::replier(key_expr, shared))
+        .await?;
+    let stats = session
+        .declare_queryable(stats::stats_key(key_expr))
+        .callback(stats::replier(key_expr, shared))
+        .await?;
+    let token = session.liveliness().declare_token(key_expr).await?;
+    Ok(Served::new(queryable, stats, token, shared))
+}
@@ -0,0 +1,1022 @@
+//! Broadcast (1:N) ring where every consumer owns a read cursor.
+//!
+//! Unlike [`crate::polling_1n`], the producer does not wait for every
+//! consumer to read each sample: it only waits for the slowest cursor when
+//! the ring is full, or, with [`Policy::Overwrite`], moves lagging cursors
+//! forward and counts the samples they missed.
+//!
+//! A cursor holds the next sequence its consumer will read. While the
+//! consumer reads a slot it sets [`READING`] on its cursor, which the
+//! producer never moves; the producer only waits on such a cursor for the
+//! duration of that single read.
+//!
+//! Like the slots of [`crate::consumers`], each cursor records the PID and
+//! heartbeat of its owner. The producer reaps the cursors of dead consumers
+//! that hold it up, so that a consumer killed with SIGKILL, possibly in the
+//! middle of a read, does not stall the ring. A consumer reaped while still
+//! alive fails with [`Error::Evicted`].
+//!
+//! A consumer joining sets its cursor active, then reads `head` with an RMW,
+//! which the producer's swap of `head` on every publication is ordered with:
+//! either the producer checks the cursor before writing the next slot, or the
+//! consumer sees the sample being written and moves its cursor past the slot
+//! it overwrites. The cursors and `head` are handled through a [`Ring`],
+//! which only relies on its own atomics, so that it can be model checked
+//! under [loom](https://docs.rs/loom) by
+//! `RUSTFLAGS="--cfg loom" cargo test --release --test loom`.
+
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+#[cfg(not(loom))]
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+
+use zenoh::Session;
+use zenoh::bytes::ZBytes;
+
+#[cfg(feature = "tokio")]
+use crate::bridge;
+use crate::cache_padded::CachePadded;
+use crate::consumers::Membership;
+use crate::deadline::Deadline;
+use crate::discovery::Channel;
+use crate::header::{Header, Layout, LayoutError, MessageType, Protocol};
+use crate::lease::{CHECK_INTERVAL, Heartbeat, expired, now_millis, pid_namespace, process_gone};
+use crate::notify::Event;
+use crate::pod::{self, RawConsumer, RawProducer, ShmPod};
+use crate::segment::{self, Segment, Served, check_len};
+use crate::stats::{ConsumerCounters, ConsumerStats, Counters, Report, Stopwatch};
+use crate::{Error, Result};
+
+/// Bytes in front of each slot's payload, holding its length.
+const SLOT_HEADER: usize = std::mem::size_of::<u64>();
+
+/// How often a blocked producer checks for dead consumers.
+pub const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Set on a cursor while its consumer reads the slot it points to.
+pub const READING: u64 = 1 << 63;
+
+// Cursor states
+pub const FREE: u32 = 0;
+pub const CLAIMED: u32 = 1;
+pub const ACTIVE: u32 = 2;
+
+/// What the producer does when the slowest consumer is a full ring behind.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Policy {
+    /// Wait for the slowest consumer.
+    #[default]
+    Block,
+    /// Skip lagging consumers ahead and record the samples they missed.
+    Overwrite,
+}
+
+#[repr(C)]
+#[cfg_attr(loom, derive(Default))]
+pub struct Cursor {
+    pub state: AtomicU32,
+    pub pid: AtomicU32,
+    pub pid_ns: AtomicU64,       // PID namespace of `pid`, 0 if unknown
+    pub id: AtomicU64,           // Identifies the current owner, 0 once it is gone
+    pub heartbeat: AtomicU64,    // Milliseconds since the UNIX epoch
+    pub position: AtomicU64,     // Next sequence to read, possibly with READING
+    pub stats: ConsumerCounters, // Skipped samples were overwritten before being read
+}
+
+// Shared data, followed by `max_consumers` cursors and `slot_count` slots
+#[repr(C)]
+pub struct SharedData {
+    pub header: Header,
+    pub slot_count: u64,
+    pub max_consumers: u64,
+    pub next_id: AtomicU64,
+    pub head: CachePadded<AtomicU64>,
+    pub published: Event, // Notified when a sample is published
+    pub released: Event,  // Notified when a consumer finishes a read or leaves
+}
+
+impl SharedData {
+    /// Distance between two consecutive slots, padded to a cache line.
+    pub fn slot_stride(&self) -> usize {
+        slot_stride(self.header.capacity as usize)
+    }
+}
+
+/// Saturates rather than overflowing on the capacity read from a header.
+fn slot_stride(slot_size: usize) -> usize {
+    SLOT_HEADER
+        .saturating_add(slot_size)
+        .checked_next_multiple_of(64)
+        .unwrap_or(usize::MAX)
+}
+
+fn tail_len(slot_count: usize, slot_size: usize, max_consumers: usize) -> usize {
+    max_consumers
+        .saturating_mul(std::mem::size_of::<CachePadded<Cursor>>())
+        .saturating_add(slot_count.saturating_mul(slot_stride(slot_size)))
+}
+
+unsafe impl Layout for SharedData {
+    const PROTOCOL: Protocol = Protocol::Broadcast;
+    const VERSION: u32 = 10;
+
+    fn tail_len(&self) -> usize {
+        tail_len(
+            self.slot_count as usize,
+            self.header.capacity as usize,
+            self.max_consumers as usize,
+        )
+    }
+
+    fn slot_count(&self) -> usize {
+        self.slot_count as usize
+    }
+}
+
+impl Report for SharedData {
+    fn consumers(shared: &Segment<Self>) -> Vec<ConsumerStats> {
+        let head = shared.head.load(Ordering::Acquire);
+        cursors(shared)
+            .iter()
+            .filter(|c| c.state.load(Ordering::Acquire) == ACTIVE)
+            .map(|c| {
+                let position = c.position.load(Ordering::Acquire) & !READING;
+                let lag = head.saturating_sub(position);
+                ConsumerStats::new(c.pid.load(Ordering::Acquire), &c.stats, lag)
+            })
+            .collect()
+    }
+}
+
+pub(crate) fn cursors(shared: &Segment<SharedData>) -> &[CachePadded<Cursor>] {
+    // SAFETY: the tail starts with `max_consumers` cursors, 64-byte aligned
+    // since `SharedData` is, and all-zero is a valid `Cursor`.
+    unsafe {
+        std::slice::from_raw_parts(
+            shared.tail() as *const CachePadded<Cursor>,
+            shared.max_consumers as usize,
+        )
+    }
+}
+
+/// Pointers to the length and payload of the slot holding `seq`.
+pub(crate) fn slot(shared: &Segment<SharedData>, seq: u64) -> (*mut u64, *mut u8) {
+    let index = (seq % shared.slot_count) as usize;
+    let offset = shared.max_consumers as usize * std::mem::size_of::<CachePadded<Cursor>>()
+        + index * shared.slot_stride();
+    // SAFETY: `index < slot_count`, so the slot lies within the tail.
+    unsafe {
+        let base = shared.tail().add(offset);
+        (base as *mut u64, base.add(SLOT_HEADER))
+    }
+}
+
+fn allocate(
+    slot_count: usize,
+    slot_size: usize,
+    max_consumers: usize,
+    message: MessageType,
+) -> Result<Segment<SharedData>> {
+    if slot_count == 0 {
+        return Err(Error::Alloc("a ring needs at least one slot".to_string()));
+    }
+    let tail = slot_count
+        .checked_mul(slot_stride(slot_size))
+        .and_then(|slots| {
+            max_consumers
+                .checked_mul(std::mem::size_of::<CachePadded<Cursor>>())?
+                .checked_add(slots)
+        })
+        .ok_or_else(|| {
+            Error::Alloc(format!(
+                "{slot_count} slots of {slot_size} bytes and {max_consumers} cursors are too large"
+            ))
+        })?;
+    Segment::<SharedData>::create_with(slot_size, tail, |shared| {
+        shared.header.message = message;
+        shared.slot_count = slot_count as u64;
+        shared.max_consumers = max_consumers as u64;
+    })
+}
+
+/// The ring's sequence numbers and the cursors of its consumers, through
+/// which the producer and the consumers synchronize.
+pub struct Ring<'a> {
+    pub head: &'a AtomicU64, // Next sequence to write
+    pub next_id: &'a AtomicU64,
+    pub cursors: &'a [CachePadded<Cursor>],
+    pub slot_count: u64,
+}
+
+fn ring(shared: &Segment<SharedData>) -> Ring<'_> {
+    Ring {
+        head: &shared.head,
+        next_id: &shared.next_id,
+        cursors: cursors(shared),
+        slot_count: shared.slot_count,
+    }
+}
+
+impl Ring<'_> {
+    /// Claims a free cursor for the calling process, positioned at the next
+    /// sample published.
+    pub fn join(&self) -> Option<Membership> {
+        let index = self.cursors.iter().position(|c| {
+            c.state
+                .compare_exchange(FREE, CLAIMED, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        })?;
+        let cursor = &self.cursors[index];
+        let id = self.next_id.fetch_add(1, Ordering::AcqRel) + 1;
+        cursor.id.store(id, Ordering::Release);
+        cursor.pid.store(std::process::id(), Ordering::Release);
+        cursor.pid_ns.store(pid_namespace(), Ordering::Release);
+        cursor.heartbeat.store(now_millis(), Ordering::Release);
+        cursor.stats.reset();
+        cursor
+            .position
+            .store(self.head.load(Ordering::Acquire), Ordering::Release);
+        cursor.state.store(ACTIVE, Ordering::Release);
+        // Read with an RMW, which the producer's swap of `head` is ordered
+        // with: if this reads `head` before a publication, the producer sees
+        // the cursor active when checking for room for the next one
+        let head = self.head.fetch_add(0, Ordering::AcqRel);
+        // The producer may be writing a slot without having seen us, wrapping
+        // around past our position
+        let position = cursor.position.load(Ordering::Acquire);
+        if head - position >= self.slot_count {
+            let _ = cursor.position.compare_exchange(
+                position,
+                head,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            );
+        }
+        Some(Membership { index, id })
+    }
+
+    /// Checks that no active cursor still needs the slot about to hold
+    /// sequence `head`, reaping those of dead consumers and moving lagging
+    /// ones forward under [`Policy::Overwrite`], their missed samples counted
+    /// in `stats`.
+    pub fn make_room(&self, head: u64, policy: Policy, stats: &Counters) -> bool {
+        let Some(oldest) = (head + 1).checked_sub(self.slot_count) else {
+            // The ring has not wrapped yet
+            return true;
+        };
+        let mut room = true;
+        for cursor in self.cursors {
+            if cursor.state.load(Ordering::Acquire) != ACTIVE {
+                continue;
+            }
+            let position = cursor.position.load(Ordering::Acquire);
+            if position & !READING >= oldest || reap(cursor, oldest) {
+                continue;
+            }
+            match policy {
+                Policy::Block => room = false,
+                // A read in progress cannot be interrupted
+                Policy::Overwrite if position & READING != 0 => room = false,
+                Policy::Overwrite => {
+                    if cursor
+                        .position
+                        .compare_exchange(position, oldest, Ordering::AcqRel, Ordering::Acquire)
+                        .is_ok()
+                    {
+                        cursor.stats.skip(stats, oldest - position);
+                    } else {
+                        // The consumer started reading meanwhile
+                        room = false;
+                    }
+                }
+            }
+        }
+        room
+    }
+
+    /// Makes the samples before `head` visible to consumers. A swap, so that
+    /// it is ordered with the RMW of consumers joining.
+    pub fn publish(&self, head: u64) {
+        self.head.swap(head, Ordering::AcqRel);
+    }
+
+    /// Marks the next unread sample of `m` as being read, so that the
+    /// producer cannot overwrite it. Returns `None` if `m` is up to date or
+    /// its cursor was reaped.
+    pub fn claim(&self, m: Membership) -> Option<u64> {
+        let cursor = &self.cursors[m.index];
+        loop {
+            if cursor.id.load(Ordering::Acquire) != m.id {
+                return None;
+            }
+            let position = cursor.position.load(Ordering::Acquire);
+            if position >= self.head.load(Ordering::Acquire) {
+                return None;
+            }
+            if cursor
+                .position
+                .compare_exchange(
+                    position,
+                    position | READING,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_ok()
+            {
+                return Some(position);
+            }
+            // The producer moved us forward, try again from there
+        }
+    }
+
+    /// Moves the cursor of `m` past `position` once its sample is read.
+    pub fn release(&self, m: Membership, position: u64) {
+        // Fails if the cursor was reaped during the read, in which case it
+        // may belong to another consumer by now
+        let _ = self.cursors[m.index].position.compare_exchange(
+            position | READING,
+            position + 1,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+    }
+
+    /// Whether `m` still holds its cursor, i.e. was neither reaped nor left.
+    pub fn is_member(&self, m: Membership) -> bool {
+        self.cursors[m.index].id.load(Ordering::Acquire) == m.id
+    }
+
+    /// Frees the cursor of `m`, unless it was reaped.
+    pub fn leave(&self, m: Membership) {
+        let cursor = &self.cursors[m.index];
+        if cursor
+            .id
+            .compare_exchange(m.id, 0, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            cursor.state.store(FREE, Ordering::Release);
+        }
+    }
+
+    /// Refreshes the heartbeat of `m`. Returns `false` once its cursor was
+    /// reaped.
+    fn beat(&self, m: Membership) -> bool {
+        let cursor = &self.cursors[m.index];
+        if cursor.id.load(Ordering::Acquire) != m.id {
+            return false;
+        }
+        cursor.heartbeat.store(now_millis(), Ordering::Release);
+        true
+    }
+}
+
+/// Frees `cursor`, moved to `oldest` with its READING mark cleared, if its
+/// consumer is dead or its lease expired. Returns whether it was reaped.
+fn reap(cursor: &Cursor, oldest: u64) -> bool {
+    let id = cursor.id.load(Ordering::Acquire);
+    let pid = cursor.pid.load(Ordering::Acquire);
+    let expired = expired(cursor.heartbeat.load(Ordering::Acquire));
+    let dead = expired || process_gone(pid, cursor.pid_ns.load(Ordering::Acquire));
+    if id == 0 || !dead {
+        return false;
+    }
+    if cursor
+        .id
+        .compare_exchange(id, 0, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        // Left or reaped meanwhile
+        return false;
+    }
+    log::warn!("Reaping broadcast consumer (pid {pid}), lease expired: {expired}");
+    cursor.position.store(oldest, Ordering::Release);
+    cursor.state.store(FREE, Ordering::Release);
+    true
+}
+
+pub struct Producer {
+    shared: Segment<SharedData>,
+    _served: Served,
+    policy: Policy,
+    head: u64,
+}
+
+impl Producer {
+    /// Allocates a ring of `slot_count` slots holding up to `slot_size`
+    /// bytes each, with room for `max_consumers` cursors, and serves it on
+    /// `key_expr`.
+    pub fn new(
+        session: &Session,
+        key_expr: &str,
+        slot_count: usize,
+        slot_size: usize,
+        max_consumers: usize,
+        policy: Policy,
+    ) -> Result<Self> {
+        Self::with_message(
+            session,
+            key_expr,
+            slot_count,
+            slot_size,
+            max_consumers,
+            policy,
+            MessageType::BYTES,
+        )
+    }
+
+    /// Allocates a ring of `slot_count` slots holding one `M` message each,
+    /// with room for `max_consumers` cursors, and serves it on `key_expr`.
+    pub fn typed<M: ShmPod>(
+        session: &Session,
+        key_expr: &str,
+        slot_count: usize,
+        max_consumers: usize,
+        policy: Policy,
+    ) -> Result<pod::Producer<Self, M>> {
+        let producer = Self::with_message(
+            session,
+            key_expr,
+            slot_count,
+            std::mem::size_of::<M>(),
+            max_consumers,
+            policy,
+            MessageType::of::<M>(),
+        )?;
+        pod::Producer::new(producer)
+    }
+
+    /// Same as [`Producer::new`], but records the type of the messages
+    /// carried in the header, see [`crate::pod`] and [`crate::arena`].
+    pub fn with_message(
+        session: &Session,
+        key_expr: &str,
+        slot_count: usize,
+        slot_size: usize,
+        max_consumers: usize,
+        policy: Policy,
+        message: MessageType,
+    ) -> Result<Self> {
+        let shared = allocate(slot_count, slot_size, max_consumers, message)?;
+        let _served = segment::serve(session, key_expr, &shared)?;
+        Ok(Producer {
+            shared,
+            _served,
+            policy,
+            head: 0,
+        })
+    }
+
+    /// Payload capacity of each slot.
+    pub fn slot_size(&self) -> usize {
+        self.shared.header.capacity as usize
+    }
+
+    /// Number of currently registered consumers.
+    pub fn subscribers(&self) -> usize {
+        cursors(&self.shared)
+            .iter()
+            .filter(|c| c.state.load(Ordering::Acquire) == ACTIVE)
+            .count()
+    }
+
+    /// Writes a sample into the next slot if no consumer prevents it. `f`
+    /// fills the slot and returns the number of bytes written. Returns the
+    /// sequence number of the new sample, or `None` if the ring is full (or,
+    /// with [`Policy::Overwrite`], a lagging consumer is reading the slot).
+    pub fn try_publish_with<F>(&mut self, f: F) -> Result<Option<u64>>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        if !self.make_room() {
+            return Ok(None);
+        }
+        self.fill(f).map(Some)
+    }
+
+    /// Waits until the next slot can be written and copies `data` into it.
+    /// Fails with [`Error::TooLarge`] if `data` exceeds the slot size.
+    pub fn publish(&mut self, deadline: &Deadline, data: &[u8]) -> Result<u64> {
+        check_len(data.len(), self.slot_size())?;
+        self.publish_with(deadline, |buf| {
+            buf[..data.len()].copy_from_slice(data);
+            data.len()
+        })
+    }
+
+    /// Waits until the next slot can be written and writes a sample into it.
+    /// Fails with [`Error::TooLarge`], publishing nothing, if `f` returns
+    /// more than the slot size.
+    pub fn publish_with<F>(&mut self, deadline: &Deadline, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        let mut blocked = Stopwatch::new();
+        loop {
+            let seen = self.shared.released.generation();
+            if self.make_room() {
+                self.shared.header.stats.blocked(&blocked);
+                return self.fill(f);
+            }
+            blocked.start();
+            // Dead consumers do not notify, check for them now and then
+            deadline.wait_at_most(&self.shared.released, seen, REAP_INTERVAL)?;
+        }
+    }
+
+    fn make_room(&self) -> bool {
+        make_room(&self.shared, self.head, self.policy)
+    }
+
+    fn fill<F>(&mut self, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        self.head = fill(&self.shared, self.head, f)?;
+        Ok(self.head)
+    }
+}
+
+/// Whether the slot about to hold sequence `head` can be written, see
+/// [`Ring::make_room`].
+fn make_room(shared: &Segment<SharedData>, head: u64, policy: Policy) -> bool {
+    ring(shared).make_room(head, policy, &shared.header.stats)
+}
+
+/// Writes sequence `head` into its slot, once [`make_room`] allowed it, and
+/// returns the next sequence to write.
+fn fill<F>(shared: &Segment<SharedData>, head: u64, f: F) -> Result<u64>
+where
+    F: FnOnce(&mut [u8]) -> usize,
+{
+    let slot_size = shared.header.capacity as usize;
+    let (len, data) = slot(shared, head);
+    // SAFETY: `make_room` checked that every active cursor is past the
+    // sequence previously held by this slot.
+    let written = f(unsafe { std::slice::from_raw_parts_mut(data, slot_size) });
+    check_len(written, slot_size)?;
+    unsafe { len.write(written as u64) };
+    shared.header.stats.published();
+    ring(shared).publish(head + 1);
+    shared.published.notify_all();
+    Ok(head + 1)
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.shared.header.producer.close();
+        self.shared.published.notify_all();
+    }
+}
+
+pub struct Consumer {
+    shared: Segment<SharedData>,
+    membership: Membership,
+    _heartbeat: Heartbeat,
+}
+
+impl Consumer {
+    /// Fetches the ring served on `key_expr` and claims a cursor. The
+    /// consumer receives samples published from now on.
+    pub fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        Self::open(segment::fetch(session, key_expr)?)
+    }
+
+    /// Attaches to a channel found by [`crate::discovery`].
+    pub fn from_channel(channel: &Channel) -> Result<Self> {
+        Self::open(channel.payload().clone())
+    }
+
+    fn open(payload: ZBytes) -> Result<Self> {
+        Self::join(Segment::attach(payload)?)
+    }
+
+    /// Claims a cursor in a mapped ring.
+    fn join(shared: Segment<SharedData>) -> Result<Self> {
+        if shared.slot_count == 0 {
+            return Err(LayoutError::Field {
+                name: "slot_count",
+                value: 0,
+            }
+            .into());
+        }
+        let membership = ring(&shared)
+            .join()
+            .ok_or(Error::Full(shared.max_consumers as usize))?;
+        let segment = shared.clone();
+        let _heartbeat = Heartbeat::start(shared.clone(), move |_: &SharedData| {
+            ring(&segment).beat(membership)
+        });
+        Ok(Consumer {
+            shared,
+            membership,
+            _heartbeat,
+        })
+    }
+
+    /// Payload capacity of each slot.
+    pub fn slot_size(&self) -> usize {
+        self.shared.header.capacity as usize
+    }
+
+    /// Whether the producer is still alive and has not closed the channel,
+    /// and has not evicted this consumer.
+    pub fn is_connected(&self) -> bool {
+        !self.shared.header.producer.is_gone() && ring(&self.shared).is_member(self.membership)
+    }
+
+    /// Samples the producer overwrote before this consumer could read them.
+    pub fn missed(&self) -> u64 {
+        self.cursor().stats.skipped.load(Ordering::Acquire)
+    }
+
+    /// Hands the next unread sample and its sequence number to `f`. Returns
+    /// `None` if the consumer is up to date, or was evicted, which
+    /// [`Consumer::is_connected`] tells. Samples skipped by the producer show
+    /// up as gaps in the sequence numbers and in [`Consumer::missed`].
+    pub fn try_recv_with<F, R>(&mut self, f: F) -> Option<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let position = self.claim()?;
+        Some(self.consume(position, f))
+    }
+
+    /// Waits for a sample and hands it to `f`. Fails with
+    /// [`Error::Disconnected`] once the producer is gone and every sample
+    /// has been read, and with [`Error::Evicted`] once the producer reaped
+    /// this consumer.
+    pub fn recv_with<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let mut waited = Stopwatch::new();
+        loop {
+            let seen = self.shared.published.generation();
+            let gone = self.shared.header.producer.is_gone();
+            if let Some(position) = self.claim() {
+                self.cursor()
+                    .stats
+                    .waited(&self.shared.header.stats, &waited);
+                return Ok(self.consume(position, f));
+            }
+            if gone {
+                return Err(Error::Disconnected);
+            }
+            if !ring(&self.shared).is_member(self.membership) {
+                return Err(Error::Evicted);
+            }
+            waited.start();
+            deadline.wait_at_most(&self.shared.published, seen, CHECK_INTERVAL)?;
+        }
+    }
+
+    fn cursor(&self) -> &Cursor {
+        &cursors(&self.shared)[self.membership.index]
+    }
+
+    /// Marks the next unread sample as being read, see [`Ring::claim`].
+    fn claim(&self) -> Option<u64> {
+        ring(&self.shared).claim(self.membership)
+    }
+
+    fn consume<F, R>(&mut self, position: u64, f: F) -> R
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let (len, data) = slot(&self.shared, position);
+        // SAFETY: the producer published this slot (Acquire on `head`) and
+        // does not reuse it while our cursor is marked READING on it. The
+        // length is clamped in case the producer misbehaves.
+        let r = unsafe {
+            let len = (len.read() as usize).min(self.slot_size());
+            f(position + 1, std::slice::from_raw_parts(data, len))
+        };
+        self.cursor().stats.read(&self.shared.header.stats);
+        ring(&self.shared).release(self.membership, position);
+        self.shared.released.notify_one();
+        r
+    }
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        ring(&self.shared).leave(self.membership);
+        self.shared.released.notify_one();
+    }
+}
+
+impl RawProducer for Producer {
+    type Published = u64;
+
+    fn header(&self) -> &Header {
+        &self.shared.header
+    }
+
+    fn publish_raw<F>(&mut self, deadline: &Deadline, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        self.publish_with(deadline, f)
+    }
+}
+
+impl RawConsumer for Consumer {
+    type Shared = SharedData;
+
+    fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        Consumer::attach(session, key_expr)
+    }
+
+    fn open(payload: ZBytes) -> Result<Self> {
+        Consumer::open(payload)
+    }
+
+    fn header(&self) -> &Header {
+        &self.shared.header
+    }
+
+    fn recv_raw<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        self.recv_with(deadline, f)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Producer {
+    /// Async version of [`Producer::new`].
+    pub async fn new_async(
+        session: &Session,
+        key_expr: &str,
+        slot_count: usize,
+        slot_size: usize,
+        max_consumers: usize,
+        policy: Policy,
+    ) -> Result<Self> {
+        Self::with_message_async(
+            session,
+            key_expr,
+            slot_count,
+            slot_size,
+            max_consumers,
+            policy,
+            MessageType::BYTES,
+        )
+        .await
+    }
+
+    /// Async version of [`Producer::with_message`].
+    pub async fn with_message_async(
+        session: &Session,
+        key_expr: &str,
+        slot_count: usize,
+        slot_size: usize,
+        max_consumers: usize,
+        policy: Policy,
+        message: MessageType,
+    ) -> Result<Self> {
+        let shared = allocate(slot_count, slot_size, max_consumers, message)?;
+        let _served = bridge::serve(session, key_expr, &shared).await?;
+        Ok(Producer {
+            shared,
+            _served,
+            policy,
+            head: 0,
+        })
+    }
+
+    /// Async version of [`Producer::publish_with`].
+    pub async fn publish_async<F>(&mut self, deadline: &Deadline, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        let mut blocked = Stopwatch::new();
+        loop {
+            let seen = self.shared.released.generation();
+            if self.make_room() {
+                self.shared.header.stats.blocked(&blocked);
+                return self.fill(f);
+            }
+            blocked.start();
+            bridge::wait(&self.shared, |s| &s.released, seen, deadline).await?;
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Consumer {
+    /// Async version of [`Consumer::attach`].
+    pub async fn attach_async(session: &Session, key_expr: &str) -> Result<Self> {
+        Self::open(bridge::fetch(session, key_expr).await?)
+    }
+
+    /// Async version of [`Consumer::recv_with`].
+    pub async fn recv_async<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let mut waited = Stopwatch::new();
+        loop {
+            let seen = self.shared.published.generation();
+            let gone = self.shared.header.producer.is_gone();
+            if let Some(position) = self.claim() {
+                self.cursor()
+                    .stats
+                    .waited(&self.shared.header.stats, &waited);
+                return Ok(self.consume(position, f));
+            }
+            if gone {
+                return Err(Error::Disconnected);
+            }
+            if !ring(&self.shared).is_member(self.membership) {
+                return Err(Error::Evicted);
+            }
+            waited.start();
+            bridge::wait(&self.shared, |s| &s.published, seen, deadline).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+
+    use super::*;
+    use crate::stats::Stats;
+
+    const WORDS: usize = 8;
+
+    fn local(slot_count: usize, max_consumers: usize) -> Segment<SharedData> {
+        let slot_size = WORDS * 8;
+        let tail = tail_len(slot_count, slot_size, max_consumers);
+        Segment::local_with(slot_size, tail, |shared: &mut SharedData| {
+            shared.slot_count = slot_count as u64;
+            shared.max_consumers = max_consumers as u64;
+        })
+    }
+
+    /// Publishes the next sample, every word of which holds its sequence
+    /// number, if there is room for it.
+    fn publish(shared: &Segment<SharedData>, head: &mut u64, policy: Policy) -> bool {
+        if !make_room(shared, *head, policy) {
+            return false;
+        }
+        let sn = *head + 1;
+        *head = fill(shared, *head, |buf| {
+            buf.copy_from_slice(&sn.to_ne_bytes().repeat(WORDS));
+            buf.len()
+        })
+        .unwrap();
+        true
+    }
+
+    /// Receives the next sample, checking that it is the one announced.
+    fn recv(consumer: &mut Consumer) -> Option<u64> {
+        consumer.try_recv_with(|sn, data| {
+            let words: Vec<u64> = data
+                .chunks(8)
+                .map(|w| u64::from_ne_bytes(w.try_into().unwrap()))
+                .collect();
+            assert_eq!(words, vec![sn; WORDS], "torn sample");
+            sn
+        })
+    }
+
+    #[test]
+    fn overwrite_counts_missed_samples() {
+        let shared = local(4, 2);
+        let mut consumer = Consumer::join(shared.clone()).unwrap();
+        let mut head = 0;
+        for _ in 0..10 {
+            assert!(publish(&shared, &mut head, Policy::Overwrite));
+        }
+        // Only the last 4 samples are left in the ring
+        assert_eq!(recv(&mut consumer), Some(7));
+        assert_eq!(consumer.missed(), 6);
+        assert_eq!(recv(&mut consumer), Some(8));
+        assert_eq!(recv(&mut consumer), Some(9));
+        assert_eq!(recv(&mut consumer), Some(10));
+        assert_eq!(recv(&mut consumer), None);
+
+        // Consumers joining late miss nothing
+        let mut late = Consumer::join(shared.clone()).unwrap();
+        for _ in 0..3 {
+            assert!(publish(&shared, &mut head, Policy::Overwrite));
+        }
+        assert_eq!(recv(&mut late), Some(11));
+        assert_eq!(late.missed(), 0);
+        assert_eq!(recv(&mut consumer), Some(11));
+        assert_eq!(consumer.missed(), 6);
+
+        let stats = &shared.header.stats;
+        assert_eq!(stats.skipped.load(Ordering::Relaxed), 6);
+        assert_eq!(stats.consumed.load(Ordering::Relaxed), 6);
+        assert_eq!(stats.produced.load(Ordering::Relaxed), 13);
+    }
+
+    #[test]
+    fn slot_being_read_is_not_overwritten() {
+        let shared = local(2, 1);
+        let mut consumer = Consumer::join(shared.clone()).unwrap();
+        let mut head = 0;
+        assert!(publish(&shared, &mut head, Policy::Overwrite));
+        assert!(publish(&shared, &mut head, Policy::Overwrite));
+
+        // The producer has to wait for the read of sample 1 to end
+        let sn = consumer.try_recv_with(|sn, _| {
+            assert!(!make_room(&shared, head, Policy::Overwrite));
+            sn
+        });
+        assert_eq!(sn, Some(1));
+        assert_eq!(consumer.missed(), 0);
+
+        // Then overwrites sample 2, which was not being read
+        assert!(publish(&shared, &mut head, Policy::Overwrite));
+        assert!(publish(&shared, &mut head, Policy::Overwrite));
+        assert_eq!(consumer.missed(), 1);
+        assert_eq!(recv(&mut consumer), Some(3));
+        assert_eq!(recv(&mut consumer), Some(4));
+    }
+
+    #[test]
+    fn blocking_ring_waits_for_lagging_consumer() {
+        let shared = local(2, 1);
+        let mut consumer = Consumer::join(shared.clone()).unwrap();
+        let mut head = 0;
+        assert!(publish(&shared, &mut head, Policy::Block));
+        assert!(publish(&shared, &mut head, Policy::Block));
+        assert!(!publish(&shared, &mut head, Policy::Block));
+        assert_eq!(recv(&mut consumer), Some(1));
+        assert!(publish(&shared, &mut head, Policy::Block));
+        assert_eq!(consumer.missed(), 0);
+    }
+
+    #[test]
+    fn stats_list_active_cursors() {
+        let shared = local(4, 2);
+        let mut consumer = Consumer::join(shared.clone()).unwrap();
+        let mut head = 0;
+        for _ in 0..3 {
+            assert!(publish(&shared, &mut head, Policy::Block));
+        }
+        assert_eq!(recv(&mut consumer), Some(1));
+
+        let stats = Stats::of(&shared);
+        let expected = ConsumerStats {
+            pid: std::process::id(),
+            consumed: 1,
+            skipped: 0,
+            waited: Duration::ZERO,
+            lag: 2,
+        };
+        assert_eq!(stats.consumers, vec![expected]);
+        assert_eq!(stats.lag, 2);
+        drop(consumer);
+        assert!(Stats::of(&shared).consumers.is_empty());
+    }
+
+    #[test]
+    fn lagging_consumer_sees_whole_samples() {
+        const SAMPLES: u64 = 20_000;
+        let shared = local(4, 1);
+        let mut consumer = Consumer::join(shared.clone()).unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+
+        let producer = {
+            let shared = shared.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                let mut head = 0;
+                while head < SAMPLES {
+                    if !publish(&shared, &mut head, Policy::Overwrite) {
+                        std::thread::yield_now();
+                    }
+                }
+                done.store(true, Ordering::Release);
+            })
+        };
+        let mut read = 0;
+        let mut last = 0;
+        loop {
+            // Checked before reading, for the last samples to be read too
+            let finished = done.load(Ordering::Acquire);
+            match recv(&mut consumer) {
+                Some(sn) => {
+                    assert!(sn > last);
+                    last = sn;
+                    read += 1;
+                }
+                None if finished => break,
+                None => std::hint::spin_loop(),
+            }
+        }
+        producer.join().unwrap();
+        assert_eq!(last, SAMPLES);
+        assert_eq!(read + consumer.missed(), SAMPLES);
+        let stats = &shared.header.stats;
+        assert_eq!(stats.skipped.load(Ordering::Relaxed), consumer.missed());
+    }
+}
@@ -0,0 +1,70 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use zshm::broadcast::Consumer;
+use zshm::cli::{Args, Defaults};
+
+fn main() {
+    let args = Args::parse(
+        "Broadcast ring consumer",
+        Defaults {
+            key_expr: "shm/broadcast/buffer",
+            delay: Duration::from_millis(500),
+            ..Default::default()
+        },
+    );
+
+    // Set up Ctrl-C handler
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+
+    ctrlc::set_handler(move || {
+        println!("\nReceived Ctrl-C! Shutting down gracefully...");
+        r.store(false, Ordering::Release);
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    let z = args.open().expect("Failed to open Zenoh session");
+
+    let mut consumer = match Consumer::attach(&z, &args.key_expr) {
+        Ok(consumer) => consumer,
+        Err(e) => {
+            println!("Failed to attach: {e}");
+            return;
+        }
+    };
+    println!("Received SHM buffer");
+
+    let mut received = 0;
+    while running.load(Ordering::Acquire) {
+        // Checked first, so that the samples published before leaving are read
+        let connected = consumer.is_connected();
+        let consumed = consumer.try_recv_with(|sn, data| {
+            let sum: u32 = data.iter().map(|&b| b as u32).sum();
+            println!(
+                "{sn} - Consumed buffer of {} bytes with sum {sum}",
+                data.len()
+            );
+            // Just simulate some processing time
+            args.process();
+        });
+        if consumed.is_some() {
+            received += 1;
+            if args.done(received) {
+                break;
+            }
+        } else {
+            if !connected {
+                println!("Producer is gone");
+                break;
+            }
+            // No data to read, wait for a while
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+    println!(
+        "Broadcast consumer stopped, {} samples missed.",
+        consumer.missed()
+    );
+}
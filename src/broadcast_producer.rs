@@ -0,0 +1,73 @@
+use clap::{Arg, ArgAction};
+use rand::random;
+use zshm::Deadline;
+use zshm::broadcast::{Policy, Producer};
+use zshm::cli::{Args, Defaults};
+
+const SLOT_COUNT: usize = 16;
+const MAX_CONSUMERS: usize = 8;
+
+fn main() {
+    let (args, m) = Args::parse_with(
+        "Broadcast ring producer",
+        Defaults {
+            key_expr: "shm/broadcast/buffer",
+            rate: 10.0,
+            ..Default::default()
+        },
+        |command| {
+            command.arg(
+                Arg::new("block")
+                    .long("block")
+                    .action(ArgAction::SetTrue)
+                    .help("Wait for the slowest consumer instead of overwriting its samples"),
+            )
+        },
+    );
+    let policy = if m.get_flag("block") {
+        Policy::Block
+    } else {
+        Policy::Overwrite
+    };
+
+    let z = args.open().expect("Failed to open Zenoh session");
+
+    let mut producer = Producer::new(
+        &z,
+        &args.key_expr,
+        SLOT_COUNT,
+        args.size,
+        MAX_CONSUMERS,
+        policy,
+    )
+    .expect("Failed to create producer");
+
+    // producer loop
+    for n in 1.. {
+        let mut sum: usize = 0;
+        let mut len = 0;
+        let sn = producer
+            .publish_with(&Deadline::never(), |data| {
+                len = args.payload_len();
+                for b in &mut data[..len] {
+                    *b = random();
+                    sum += *b as usize;
+                }
+                len
+            })
+            .expect("Failed to publish");
+        println!(
+            "{} - Produced buffer of {} bytes with sum of {} for {} subs",
+            sn,
+            len,
+            sum,
+            producer.subscribers()
+        );
+        if args.done(n) {
+            break;
+        }
+        if let Some(period) = args.period() {
+            std::thread::sleep(period);
+        }
+    }
+}
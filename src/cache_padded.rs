@@ -0,0 +1,13 @@
+/// Pads and aligns a value to a cache line, so that fields written by
+/// different processes do not share one.
+#[repr(C, align(64))]
+#[derive(Default)]
+pub struct CachePadded<T>(pub T);
+
+impl<T> std::ops::Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
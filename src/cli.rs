@@ -0,0 +1,312 @@
+//! Command-line arguments shared by the example binaries.
+//!
+//! Every binary accepts the options that pick a channel and open a session,
+//! parsed into [`SessionArgs`]:
+//!
+//! ```text
+//! -k, --key <KEYEXPR>        key expression of the channel
+//! -c, --config <FILE>        Zenoh configuration file
+//! -m, --mode <MODE>          peer, client or router
+//! -e, --connect <ENDPOINT>   endpoint to connect to (repeatable)
+//! -l, --listen <ENDPOINT>    endpoint to listen on (repeatable)
+//! ```
+//!
+//! Producers and consumers also accept those that shape the stream of
+//! samples, parsed along with the above into [`Args`], with defaults of their
+//! own:
+//!
+//! ```text
+//! -s, --size <BYTES>         largest payload written by producers
+//!     --min-size <BYTES>     smallest payload written by producers
+//! -r, --rate <HZ>            samples published per second, 0 for no limit
+//! -n, --count <N>            stop after N samples
+//! -d, --delay <MS>           simulated processing time per received sample
+//! ```
+//!
+//! Options that only make sense for some binaries are added by them through
+//! [`Args::parse_with`] or [`SessionArgs::parse_with`].
+
+use std::time::Duration;
+
+use clap::{Arg, ArgAction, ArgMatches, Command, error::ErrorKind, value_parser};
+use rand::random_range;
+use zenoh::{Config, Session, Wait};
+
+/// Per-binary defaults of the shared options.
+#[derive(Clone, Debug)]
+pub struct Defaults {
+    pub key_expr: &'static str,
+    pub size: usize,
+    pub rate: f64, // 0 for no limit
+    pub delay: Duration,
+}
+
+impl Default for Defaults {
+    fn default() -> Self {
+        Defaults {
+            key_expr: "shm/buffer",
+            size: 1024,
+            rate: 0.0,
+            delay: Duration::ZERO,
+        }
+    }
+}
+
+/// The options every binary accepts.
+#[derive(Clone, Debug)]
+pub struct SessionArgs {
+    pub key_expr: String,
+    pub config: Config,
+}
+
+impl SessionArgs {
+    /// Parses the process arguments, with the options `extend` adds to the
+    /// session ones, whose values are read from the returned matches. Exits
+    /// with a usage message on error.
+    pub fn parse_with<F>(
+        about: &'static str,
+        key_expr: &'static str,
+        extend: F,
+    ) -> (Self, ArgMatches)
+    where
+        F: FnOnce(Command) -> Command,
+    {
+        let mut command = extend(session_command(about, key_expr));
+        let matches = command.get_matches_mut();
+        let args = Self::from_matches(&matches, key_expr)
+            .unwrap_or_else(|e| command.error(ErrorKind::ValueValidation, e).exit());
+        (args, matches)
+    }
+
+    fn from_matches(m: &ArgMatches, key_expr: &str) -> Result<Self, String> {
+        Ok(SessionArgs {
+            key_expr: m
+                .get_one::<String>("key")
+                .cloned()
+                .unwrap_or_else(|| key_expr.to_string()),
+            config: config(m)?,
+        })
+    }
+
+    /// Opens a Zenoh session with the configured topology.
+    pub fn open(&self) -> zenoh::Result<Session> {
+        zenoh::open(self.config.clone()).wait()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Args {
+    pub key_expr: String,
+    pub config: Config,
+    pub size: usize,
+    pub min_size: usize,
+    pub rate: f64,
+    pub count: Option<u64>,
+    pub delay: Duration,
+}
+
+impl Args {
+    /// Parses the process arguments, exiting with a usage message on error.
+    pub fn parse(about: &'static str, defaults: Defaults) -> Self {
+        Self::parse_with(about, defaults, |command| command).0
+    }
+
+    /// Same as [`Args::parse`], with the options `extend` adds to the shared
+    /// ones, whose values are read from the returned matches.
+    pub fn parse_with<F>(about: &'static str, defaults: Defaults, extend: F) -> (Self, ArgMatches)
+    where
+        F: FnOnce(Command) -> Command,
+    {
+        let mut command = extend(command(about, &defaults));
+        let matches = command.get_matches_mut();
+        let args = Self::from_matches(&matches, defaults)
+            .unwrap_or_else(|e| command.error(ErrorKind::ValueValidation, e).exit());
+        (args, matches)
+    }
+
+    fn from_matches(m: &ArgMatches, defaults: Defaults) -> Result<Self, String> {
+        let size = m.get_one::<usize>("size").copied().unwrap_or(defaults.size);
+        let min_size = m.get_one::<usize>("min-size").copied().unwrap_or(size / 2);
+        if min_size > size {
+            return Err(format!("--min-size {min_size} exceeds --size {size}"));
+        }
+        let SessionArgs { key_expr, config } = SessionArgs::from_matches(m, defaults.key_expr)?;
+        Ok(Args {
+            key_expr,
+            config,
+            size,
+            min_size,
+            rate: m.get_one::<f64>("rate").copied().unwrap_or(defaults.rate),
+            count: m.get_one::<u64>("count").copied(),
+            delay: m
+                .get_one::<u64>("delay")
+                .map_or(defaults.delay, |&ms| Duration::from_millis(ms)),
+        })
+    }
+
+    /// Opens a Zenoh session with the configured topology.
+    pub fn open(&self) -> zenoh::Result<Session> {
+        zenoh::open(self.config.clone()).wait()
+    }
+
+    /// A random payload length between `--min-size` and `--size`.
+    pub fn payload_len(&self) -> usize {
+        random_range(self.min_size..=self.size)
+    }
+
+    /// Time to wait between two publications to honour `--rate`.
+    pub fn period(&self) -> Option<Duration> {
+        (self.rate > 0.0).then(|| Duration::from_secs_f64(1.0 / self.rate))
+    }
+
+    /// Whether `n` samples are enough to stop.
+    pub fn done(&self, n: u64) -> bool {
+        self.count.is_some_and(|count| n >= count)
+    }
+
+    /// Simulates processing a received sample.
+    pub fn process(&self) {
+        if !self.delay.is_zero() {
+            std::thread::sleep(self.delay);
+        }
+    }
+}
+
+fn session_command(about: &'static str, key_expr: &str) -> Command {
+    Command::new(bin_name())
+        .about(about)
+        .arg(
+            Arg::new("key")
+                .short('k')
+                .long("key")
+                .value_name("KEYEXPR")
+                .help(format!(
+                    "Key expression of the channel [default: {key_expr}]"
+                )),
+        )
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .value_name("FILE")
+                .help("Zenoh configuration file"),
+        )
+        .arg(
+            Arg::new("mode")
+                .short('m')
+                .long("mode")
+                .value_name("MODE")
+                .value_parser(["peer", "client", "router"])
+                .help("Zenoh session mode"),
+        )
+        .arg(
+            Arg::new("connect")
+                .short('e')
+                .long("connect")
+                .value_name("ENDPOINT")
+                .action(ArgAction::Append)
+                .help("Endpoint to connect to, e.g. tcp/192.168.1.1:7447"),
+        )
+        .arg(
+            Arg::new("listen")
+                .short('l')
+                .long("listen")
+                .value_name("ENDPOINT")
+                .action(ArgAction::Append)
+                .help("Endpoint to listen on"),
+        )
+}
+
+fn command(about: &'static str, defaults: &Defaults) -> Command {
+    session_command(about, defaults.key_expr)
+        .arg(
+            Arg::new("size")
+                .short('s')
+                .long("size")
+                .value_name("BYTES")
+                .value_parser(value_parser!(usize))
+                .help(format!(
+                    "Largest payload written by producers [default: {}]",
+                    defaults.size
+                )),
+        )
+        .arg(
+            Arg::new("min-size")
+                .long("min-size")
+                .value_name("BYTES")
+                .value_parser(value_parser!(usize))
+                .help("Smallest payload written by producers [default: half of --size]"),
+        )
+        .arg(
+            Arg::new("rate")
+                .short('r')
+                .long("rate")
+                .value_name("HZ")
+                .value_parser(value_parser!(f64))
+                .help(format!(
+                    "Samples published per second, 0 for no limit [default: {}]",
+                    defaults.rate
+                )),
+        )
+        .arg(
+            Arg::new("count")
+                .short('n')
+                .long("count")
+                .value_name("N")
+                .value_parser(value_parser!(u64))
+                .help("Stop after N samples"),
+        )
+        .arg(
+            Arg::new("delay")
+                .short('d')
+                .long("delay")
+                .value_name("MS")
+                .value_parser(value_parser!(u64))
+                .help(format!(
+                    "Simulated processing time per received sample [default: {}]",
+                    defaults.delay.as_millis()
+                )),
+        )
+}
+
+/// Name of the running binary, shown by `--help` and in usage errors.
+fn bin_name() -> String {
+    std::env::args_os()
+        .next()
+        .as_deref()
+        .map(std::path::Path::new)
+        .and_then(|path| path.file_stem())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_string())
+}
+
+fn config(m: &ArgMatches) -> Result<Config, String> {
+    let mut config = match m.get_one::<String>("config") {
+        Some(path) => {
+            Config::from_file(path).map_err(|e| format!("invalid config '{path}': {e}"))?
+        }
+        None => Config::default(),
+    };
+    let mut insert = |key: &str, value: String| {
+        config
+            .insert_json5(key, &value)
+            .map_err(|e| format!("invalid {key} {value}: {e}"))
+    };
+    if let Some(mode) = m.get_one::<String>("mode") {
+        insert("mode", json_string(mode))?;
+    }
+    for (arg, key) in [
+        ("connect", "connect/endpoints"),
+        ("listen", "listen/endpoints"),
+    ] {
+        if let Some(endpoints) = m.get_many::<String>(arg) {
+            let list: Vec<_> = endpoints.map(|e| json_string(e)).collect();
+            insert(key, format!("[{}]", list.join(",")))?;
+        }
+    }
+    Ok(config)
+}
+
+fn json_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
@@ -0,0 +1,307 @@
+//! Table of the consumers registered on a 1:N mailbox, shared by
+//! [`crate::polling_1n`] and [`crate::await_1n`].
+//!
+//! Each consumer owns a [`Slot`] recording its PID, a heartbeat refreshed by
+//! a background thread (see [`crate::lease`]), and the sample it still owes a
+//! read on. On every
+//! publication the producer gives each active slot a read credit, and it
+//! reaps slots whose process is gone or whose lease expired, returning the
+//! credit they held. A consumer killed with SIGKILL thus no longer stalls the
+//! channel. A consumer that was reaped while still alive, e.g. stopped in a
+//! debugger, finds out through [`Consumers::is_member`] and its channel
+//! reports [`Error::Evicted`].
+//!
+//! A credit is returned by whoever clears [`Slot::owed`] with a CAS, so it is
+//! returned exactly once even if the producer reaps a consumer that is still
+//! reading. The holder of the last credit hands the mailbox back.
+//!
+//! Membership changes never disturb the sample being read. A consumer that
+//! joins receives samples from the one after the last given out, recorded in
+//! [`Slot::first`]: a credit on an earlier sample, which the producer may
+//! hand to a slot it saw owned by a consumer that just left, is not read by
+//! the newcomer and is taken back by the producer. When a sample is given out
+//! while a consumer joins, `first` is settled by a CAS from [`PENDING`]: the
+//! producer sets it to that sample if it sees the slot active first, along
+//! with a credit on it, and the consumer to the next one otherwise. A
+//! consumer that leaves, or is reaped, returns the credit it holds, if any,
+//! and nothing else.
+//!
+//! The table only relies on its own atomics, so that it can be model checked
+//! under [loom](https://docs.rs/loom), with fewer slots, by
+//! `RUSTFLAGS="--cfg loom" cargo test --release --test loom`.
+
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicI32, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+#[cfg(not(loom))]
+use std::sync::atomic::{AtomicI32, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use crate::cache_padded::CachePadded;
+use crate::lease::{expired, now_millis, pid_namespace, process_gone};
+use crate::stats::{ConsumerCounters, ConsumerStats};
+use crate::{Error, Result};
+
+/// Maximum number of consumers of a 1:N mailbox.
+#[cfg(not(loom))]
+pub const MAX_CONSUMERS: usize = 32;
+#[cfg(loom)]
+pub const MAX_CONSUMERS: usize = 2;
+
+// Slot states
+pub const FREE: u32 = 0;
+pub const JOINING: u32 = 1;
+pub const ACTIVE: u32 = 2;
+
+/// [`Slot::first`] of a consumer that is joining and has not yet read the
+/// last sequence number given out.
+pub const PENDING: u64 = u64::MAX;
+
+#[repr(C)]
+#[cfg_attr(loom, derive(Default))]
+pub struct Slot {
+    pub state: AtomicU32,
+    pub pid: AtomicU32,
+    pub pid_ns: AtomicU64,    // PID namespace of `pid`, 0 if unknown
+    pub id: AtomicU64,        // Membership of the current owner, 0 once it is gone
+    pub heartbeat: AtomicU64, // Milliseconds since the UNIX epoch
+    pub owed: AtomicU64,      // Sequence number still to be read, 0 if none
+    pub first: AtomicU64,     // First sequence number the owner receives
+    pub stats: ConsumerCounters,
+}
+
+#[repr(C)]
+#[cfg_attr(loom, derive(Default))]
+pub struct Consumers {
+    pub next_id: AtomicU64,
+    pub sn: AtomicU64,          // Sequence number of the last sample given out
+    pub read_count: AtomicI32,  // Credits not yet returned on the current sample
+    pub sub_count: AtomicUsize, // Total number of active consumers
+    pub slots: [CachePadded<Slot>; MAX_CONSUMERS],
+}
+
+/// A consumer's claim on a slot.
+#[derive(Clone, Copy, Debug)]
+pub struct Membership {
+    pub(crate) index: usize,
+    pub(crate) id: u64,
+}
+
+impl Consumers {
+    /// Claims a free slot for the calling process, which receives samples
+    /// from the one after the last given out.
+    pub fn join(&self) -> Result<Membership> {
+        let index = self
+            .slots
+            .iter()
+            .position(|s| {
+                s.state
+                    .compare_exchange(FREE, JOINING, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+            })
+            .ok_or(Error::Full(MAX_CONSUMERS))?;
+        let id = self.next_id.fetch_add(1, Ordering::AcqRel) + 1;
+        let slot = &self.slots[index];
+        // Before the id, so that a producer seeing the id does not read the
+        // previous owner's `first`
+        slot.first.store(PENDING, Ordering::Release);
+        slot.id.store(id, Ordering::Release);
+        slot.pid.store(std::process::id(), Ordering::Release);
+        slot.pid_ns.store(pid_namespace(), Ordering::Release);
+        slot.heartbeat.store(now_millis(), Ordering::Release);
+        slot.stats.reset();
+        // `owed` is left alone: it was cleared when the slot was vacated, and
+        // a producer may have just given a credit to the previous owner that
+        // it takes back, unless this consumer joined early enough to read it
+        slot.state.store(ACTIVE, Ordering::Release);
+        // Read with an RMW, which the producer's swap of `sn` is ordered with:
+        // if this reads the last sample given out, the producer giving out the
+        // next one sees the slot active
+        let sn = self.sn.fetch_add(0, Ordering::AcqRel);
+        // Fails if a producer saw the slot active first and set `first` to the
+        // sample it gives out
+        let _ = slot
+            .first
+            .compare_exchange(PENDING, sn + 1, Ordering::AcqRel, Ordering::Acquire);
+        self.sub_count.fetch_add(1, Ordering::AcqRel);
+        Ok(Membership { index, id })
+    }
+
+    /// Gives the slot back. Returns `true` if this returned the last credit
+    /// on the current sample, in which case the caller hands the mailbox back.
+    pub fn leave(&self, m: Membership) -> bool {
+        let slot = &self.slots[m.index];
+        if slot
+            .id
+            .compare_exchange(m.id, 0, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            // Already reaped by the producer
+            return false;
+        }
+        self.vacate(slot)
+    }
+
+    /// Refreshes the heartbeat of `m`. Returns `false` once the slot was
+    /// reaped.
+    pub(crate) fn beat(&self, m: Membership) -> bool {
+        let slot = &self.slots[m.index];
+        if slot.id.load(Ordering::Acquire) != m.id {
+            return false;
+        }
+        slot.heartbeat.store(now_millis(), Ordering::Release);
+        true
+    }
+
+    /// Whether `m` still holds its slot, i.e. was neither reaped nor left.
+    pub fn is_member(&self, m: Membership) -> bool {
+        m.id != 0
+            && self
+                .slots
+                .get(m.index)
+                .is_some_and(|s| s.id.load(Ordering::Acquire) == m.id)
+    }
+
+    /// Number of currently registered consumers.
+    pub fn active(&self) -> usize {
+        self.sub_count.load(Ordering::Acquire)
+    }
+
+    /// First sequence number `m` receives.
+    pub fn first(&self, m: Membership) -> u64 {
+        self.slots[m.index].first.load(Ordering::Acquire)
+    }
+
+    /// Counters of `m`, updated by the consumer itself.
+    pub(crate) fn counters(&self, m: Membership) -> &ConsumerCounters {
+        &self.slots[m.index].stats
+    }
+
+    /// Statistics of the active consumers, whose lag is the sample they
+    /// still have to read, if any.
+    pub(crate) fn stats(&self) -> Vec<ConsumerStats> {
+        self.slots
+            .iter()
+            .filter(|s| {
+                s.state.load(Ordering::Acquire) == ACTIVE && s.id.load(Ordering::Acquire) != 0
+            })
+            .map(|s| {
+                let owed = s.owed.load(Ordering::Acquire);
+                let lag = (owed != 0 && owed >= s.first.load(Ordering::Acquire)) as u64;
+                ConsumerStats::new(s.pid.load(Ordering::Acquire), &s.stats, lag)
+            })
+            .collect()
+    }
+
+    /// Sample the consumer still has to read, if any. `None` as well once
+    /// `m` no longer holds its slot, see [`Consumers::is_member`].
+    pub fn owed(&self, m: Membership) -> Option<u64> {
+        let slot = &self.slots[m.index];
+        match slot.owed.load(Ordering::Acquire) {
+            0 => None,
+            // Given to the previous owner, the producer takes it back
+            sn if sn < slot.first.load(Ordering::Acquire) => None,
+            sn if slot.id.load(Ordering::Acquire) == m.id => Some(sn),
+            _ => None,
+        }
+    }
+
+    /// Returns the consumer's credit on `sn`. Returns `true` if it was the
+    /// last one.
+    pub fn release(&self, m: Membership, sn: u64) -> bool {
+        self.slots[m.index]
+            .owed
+            .compare_exchange(sn, 0, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+            && self.return_credit()
+    }
+
+    /// Gives a credit on `sn` to every active consumer that joined before
+    /// it. Must be called once the sample is visible to consumers and the
+    /// previous one is fully read. Returns `true` if no credit is outstanding
+    /// afterwards.
+    pub fn assign(&self, sn: u64) -> bool {
+        // Consumers joining from now on start with the next sample. A swap,
+        // so that it is ordered with the RMW of consumers joining
+        self.sn.swap(sn, Ordering::AcqRel);
+        // The producer holds a credit itself until every slot is handled, so
+        // that early readers cannot hand the mailbox back too soon
+        self.read_count.store(1, Ordering::Release);
+        for slot in &self.slots {
+            let id = slot.id.load(Ordering::Acquire);
+            if slot.state.load(Ordering::Acquire) != ACTIVE || id == 0 {
+                continue;
+            }
+            // A consumer that is joining starts with this sample, unless it
+            // took the slot over since `id` was read, in which case it joined
+            // after the sample was given out
+            let claimed =
+                slot.first
+                    .compare_exchange(PENDING, sn, Ordering::AcqRel, Ordering::Acquire);
+            if claimed.is_ok() && slot.id.load(Ordering::Acquire) != id {
+                let _ =
+                    slot.first
+                        .compare_exchange(sn, sn + 1, Ordering::AcqRel, Ordering::Acquire);
+                continue;
+            }
+            if claimed.is_err_and(|first| first > sn) {
+                continue;
+            }
+            self.read_count.fetch_add(1, Ordering::AcqRel);
+            // A swap rather than a store, so that it synchronizes with the
+            // swap of a consumer vacating the slot: either that one sees the
+            // credit, or this one is ordered after the slot was cleared
+            slot.owed.swap(sn, Ordering::AcqRel);
+            // The consumer may have left meanwhile, take the credit back
+            // unless it returned it on its way out
+            if (slot.state.load(Ordering::Acquire) != ACTIVE
+                || slot.id.load(Ordering::Acquire) != id)
+                && slot
+                    .owed
+                    .compare_exchange(sn, 0, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+            {
+                self.read_count.fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+        self.return_credit()
+    }
+
+    /// Frees the slots of dead consumers and returns their credits. Returns
+    /// `true` if this returned the last credit on the current sample.
+    pub fn reap(&self) -> bool {
+        let mut last = false;
+        for (index, slot) in self.slots.iter().enumerate() {
+            if slot.state.load(Ordering::Acquire) != ACTIVE {
+                continue;
+            }
+            let id = slot.id.load(Ordering::Acquire);
+            let pid = slot.pid.load(Ordering::Acquire);
+            let expired = expired(slot.heartbeat.load(Ordering::Acquire));
+            let dead = expired || process_gone(pid, slot.pid_ns.load(Ordering::Acquire));
+            if id == 0 || !dead {
+                continue;
+            }
+            if slot
+                .id
+                .compare_exchange(id, 0, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                log::warn!("Reaping consumer {index} (pid {pid}), lease expired: {expired}");
+                last |= self.vacate(slot);
+            }
+        }
+        last
+    }
+
+    /// Returns the credit held by a slot whose `id` was just cleared, and
+    /// frees it.
+    fn vacate(&self, slot: &Slot) -> bool {
+        let last = slot.owed.swap(0, Ordering::AcqRel) != 0 && self.return_credit();
+        slot.state.store(FREE, Ordering::Release);
+        self.sub_count.fetch_sub(1, Ordering::AcqRel);
+        last
+    }
+
+    fn return_credit(&self) -> bool {
+        self.read_count.fetch_sub(1, Ordering::AcqRel) == 1
+    }
+}
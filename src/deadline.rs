@@ -0,0 +1,187 @@
+//! Deadlines and cancellation for blocking channel operations.
+//!
+//! Every blocking `publish_with`/`recv_with` takes a [`Deadline`], which
+//! bounds the wait in time and/or ties it to a [`CancellationToken`]. An
+//! expired deadline yields [`Error::Timeout`] and a cancelled token
+//! [`Error::Cancelled`]. Cancelling wakes threads blocked on a shared
+//! [`Event`] right away; polling waits notice it at their next poll.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::notify::Event;
+use crate::{Error, Result};
+
+/// Cancels the waits of every [`Deadline`] it was attached to.
+///
+/// Clones share the same state, so a token can be cancelled from a signal
+/// handler thread while channel operations block on another.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    waiting: Mutex<Vec<EventPtr>>, // Events with a waiter bound to this token
+}
+
+#[derive(PartialEq)]
+struct EventPtr(*const Event);
+
+// SAFETY: only dereferenced while registered, i.e. while the waiter (who
+// holds a reference to the event) is blocked on it.
+unsafe impl Send for EventPtr {}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels all current and future waits bound to this token.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        let waiting = self.inner.waiting.lock().unwrap();
+        for event in waiting.iter() {
+            // SAFETY: see `EventPtr`.
+            unsafe { &*event.0 }.notify_all();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn register(&self, event: &Event) {
+        self.inner.waiting.lock().unwrap().push(EventPtr(event));
+    }
+
+    fn unregister(&self, event: &Event) {
+        let mut waiting = self.inner.waiting.lock().unwrap();
+        if let Some(i) = waiting.iter().position(|e| *e == EventPtr(event)) {
+            waiting.swap_remove(i);
+        }
+    }
+}
+
+/// How long a blocking operation may wait.
+#[derive(Clone, Default)]
+pub struct Deadline {
+    at: Option<Instant>,
+    token: Option<CancellationToken>,
+}
+
+impl Deadline {
+    /// Waits for as long as needed.
+    pub fn never() -> Self {
+        Self::default()
+    }
+
+    /// Gives up at `instant`.
+    pub fn at(instant: Instant) -> Self {
+        Deadline {
+            at: Some(instant),
+            token: None,
+        }
+    }
+
+    /// Gives up once `timeout` has elapsed from now.
+    pub fn after(timeout: Duration) -> Self {
+        Self::at(Instant::now() + timeout)
+    }
+
+    /// Also gives up as soon as `token` is cancelled.
+    pub fn cancellable(mut self, token: CancellationToken) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    pub fn instant(&self) -> Option<Instant> {
+        self.at
+    }
+
+    /// Fails if the token was cancelled or the deadline has passed.
+    pub fn check(&self) -> Result<()> {
+        if self.token.as_ref().is_some_and(|t| t.is_cancelled()) {
+            return Err(Error::Cancelled);
+        }
+        if self.at.is_some_and(|at| Instant::now() >= at) {
+            return Err(Error::Timeout);
+        }
+        Ok(())
+    }
+
+    /// Sleeps for `interval`, or less if the deadline comes first.
+    pub fn sleep(&self, interval: Duration) -> Result<()> {
+        self.check()?;
+        let interval = match self.at {
+            Some(at) => interval.min(at.saturating_duration_since(Instant::now())),
+            None => interval,
+        };
+        std::thread::sleep(interval);
+        self.check()
+    }
+
+    /// Blocks until the generation of `event` differs from `seen`.
+    pub fn wait(&self, event: &Event, seen: u32) -> Result<()> {
+        self.check()?;
+        let Some(token) = &self.token else {
+            return self.wait_event(event, seen);
+        };
+        // Registered before checking the flag, so a concurrent `cancel`
+        // either is seen here or bumps the event we are about to wait on
+        token.register(event);
+        let result = if token.is_cancelled() {
+            Err(Error::Cancelled)
+        } else {
+            self.wait_event(event, seen)
+        };
+        token.unregister(event);
+        if token.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        result
+    }
+
+    /// Like [`Deadline::wait`], but also returns after `interval`, for callers
+    /// that must re-check a condition nobody notifies them about.
+    pub fn wait_at_most(&self, event: &Event, seen: u32, interval: Duration) -> Result<()> {
+        let limit = Instant::now() + interval;
+        let bounded = Deadline {
+            at: Some(self.at.map_or(limit, |at| at.min(limit))),
+            token: self.token.clone(),
+        };
+        match bounded.wait(event, seen) {
+            Err(Error::Timeout) => self.check(),
+            result => result,
+        }
+    }
+
+    fn wait_event(&self, event: &Event, seen: u32) -> Result<()> {
+        if event.wait_until(seen, self.at) {
+            Ok(())
+        } else {
+            Err(Error::Timeout)
+        }
+    }
+}
+
+impl From<Duration> for Deadline {
+    fn from(timeout: Duration) -> Self {
+        Deadline::after(timeout)
+    }
+}
+
+impl From<Instant> for Deadline {
+    fn from(instant: Instant) -> Self {
+        Deadline::at(instant)
+    }
+}
+
+impl From<CancellationToken> for Deadline {
+    fn from(token: CancellationToken) -> Self {
+        Deadline::never().cancellable(token)
+    }
+}
@@ -0,0 +1,194 @@
+//! Discovery of the channels served under a key expression.
+//!
+//! Producers answer queries on their key expression with their segment, so a
+//! `get` on a key expression with wildcards, e.g. `shm/**`, reaches all of
+//! them at once. [`list`] gathers every reply and reads the [`Header`] of
+//! each segment, and [`wait_for`] retries until a producer shows up, woken
+//! by the liveliness token each producer declares. The consumer then picks
+//! one of the [`Channel`]s found and attaches to it with the `from_channel`
+//! constructor of the matching consumer, e.g.
+//! [`crate::spsc::Consumer::from_channel`].
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use zenoh::bytes::ZBytes;
+use zenoh::sample::SampleKind;
+use zenoh::{Session, Wait};
+
+use crate::deadline::Deadline;
+use crate::header::{Header, Layout, LayoutError, MessageType, Protocol};
+use crate::notify::Event;
+use crate::segment::{self, Segment};
+use crate::{Error, Result};
+
+/// How long [`wait_for`] lets each query collect replies.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often [`wait_for`] queries again if no liveliness token shows up,
+/// e.g. because the producer is in a process whose session does not route
+/// liveliness to us.
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A channel found by [`list`], along with the segment it replied with.
+#[derive(Clone, Debug)]
+pub struct Channel {
+    /// Key expression the producer serves.
+    pub key_expr: String,
+    /// `None` for protocols this build does not know.
+    pub protocol: Option<Protocol>,
+    /// Version of the layout, see [`Layout::VERSION`].
+    pub version: u32,
+    /// Payload capacity of each slot, in bytes.
+    pub capacity: u64,
+    /// Number of slots, or why this build cannot attach to the segment.
+    pub slot_count: std::result::Result<u64, LayoutError>,
+    pub message: MessageType,
+    /// PID of the producer process, 0 when several processes produce.
+    pub producer_pid: u32,
+    /// Whether the producer is alive and has not closed the channel.
+    pub producer_alive: bool,
+    payload: ZBytes,
+}
+
+impl Channel {
+    fn new(key_expr: String, payload: ZBytes) -> Result<Self> {
+        let header = segment::header(&payload)?;
+        let protocol = Protocol::from_u32(header.protocol);
+        let slot_count = match protocol {
+            Some(protocol) => slot_count(protocol, &payload),
+            None => Err(LayoutError::Field {
+                name: "protocol",
+                value: header.protocol as u64,
+            }),
+        };
+        Ok(Channel {
+            key_expr,
+            protocol,
+            version: header.version,
+            capacity: header.capacity,
+            slot_count,
+            message: header.message,
+            producer_pid: header.producer.pid.load(Ordering::Acquire),
+            producer_alive: !header.producer.is_gone(),
+            payload,
+        })
+    }
+
+    /// Whether this build understands the layout of the segment.
+    pub fn is_compatible(&self) -> bool {
+        self.slot_count.is_ok()
+    }
+
+    /// Header of the segment, as maintained by the producer.
+    pub fn header(&self) -> &Header {
+        segment::header(&self.payload).expect("checked by `Channel::new`")
+    }
+
+    pub(crate) fn payload(&self) -> &ZBytes {
+        &self.payload
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.protocol {
+            Some(protocol) => write!(f, "{} ({protocol} v{}", self.key_expr, self.version)?,
+            None => write!(f, "{} (unknown protocol v{}", self.key_expr, self.version)?,
+        }
+        match &self.slot_count {
+            Ok(slots) => write!(f, ", {slots} x {} bytes", self.capacity)?,
+            Err(e) => write!(f, ", incompatible: {e}")?,
+        }
+        if self.message != MessageType::BYTES {
+            write!(f, ", {}", self.message)?;
+        }
+        let state = if self.producer_alive { "alive" } else { "gone" };
+        write!(f, ", producer pid {} {state})", self.producer_pid)
+    }
+}
+
+/// Checks the segment against the layout of `protocol` known to this build.
+fn slot_count(protocol: Protocol, payload: &ZBytes) -> std::result::Result<u64, LayoutError> {
+    fn read<T: Layout>(payload: &ZBytes) -> std::result::Result<u64, LayoutError> {
+        match Segment::<T>::attach(payload.clone()) {
+            Ok(segment) => Ok(segment.slot_count() as u64),
+            Err(Error::Layout(e)) => Err(e),
+            Err(e) => unreachable!("the header was read already: {e}"),
+        }
+    }
+    match protocol {
+        Protocol::Polling => read::<crate::polling::SharedData>(payload),
+        Protocol::Polling1N => read::<crate::polling_1n::SharedData>(payload),
+        #[cfg(target_os = "linux")]
+        Protocol::Await1N => read::<crate::await_1n::SharedData>(payload),
+        #[cfg(not(target_os = "linux"))]
+        Protocol::Await1N => Err(LayoutError::Field {
+            name: "protocol",
+            value: protocol as u64,
+        }),
+        Protocol::Spsc => read::<crate::spsc::SharedData>(payload),
+        Protocol::Broadcast => read::<crate::broadcast::SharedData>(payload),
+        Protocol::Mpmc => read::<crate::mpmc::SharedData>(payload),
+        Protocol::Seqlock => read::<crate::seqlock::SharedData>(payload),
+    }
+}
+
+/// Queries `key_expr`, which may contain wildcards, and returns the channels
+/// of every producer that replied within `timeout`. Replies that are not
+/// segments, e.g. from producers on another host, are skipped.
+pub fn list(session: &Session, key_expr: &str, timeout: Duration) -> Result<Vec<Channel>> {
+    let replies = session
+        .get(segment::selector(key_expr))
+        .timeout(timeout)
+        .wait()?;
+    let mut channels = Vec::new();
+    while let Ok(reply) = replies.recv() {
+        let sample = match reply.into_result() {
+            Ok(sample) => sample,
+            Err(e) => {
+                log::debug!("Skipping error reply on '{key_expr}': {e:?}");
+                continue;
+            }
+        };
+        let key = sample.key_expr().to_string();
+        match Channel::new(key.clone(), sample.payload().clone()) {
+            Ok(channel) => channels.push(channel),
+            Err(e) => log::debug!("Skipping reply on '{key}': {e}"),
+        }
+    }
+    channels.sort_by(|a, b| a.key_expr.cmp(&b.key_expr));
+    Ok(channels)
+}
+
+/// Waits until at least one producer serves `key_expr`, then returns the
+/// channels found as [`list`] does. Fails with [`Error::Timeout`] or
+/// [`Error::Cancelled`] according to `deadline`.
+pub fn wait_for(session: &Session, key_expr: &str, deadline: &Deadline) -> Result<Vec<Channel>> {
+    let appeared = Arc::new(Event::new());
+    let a = appeared.clone();
+    let _subscriber = session
+        .liveliness()
+        .declare_subscriber(key_expr)
+        .callback(move |sample| {
+            if sample.kind() == SampleKind::Put {
+                a.notify_all();
+            }
+        })
+        .wait()?;
+    loop {
+        let seen = appeared.generation();
+        let timeout = match deadline.instant() {
+            Some(at) => QUERY_TIMEOUT.min(at.saturating_duration_since(std::time::Instant::now())),
+            None => QUERY_TIMEOUT,
+        };
+        let channels = list(session, key_expr, timeout)?;
+        if !channels.is_empty() {
+            return Ok(channels);
+        }
+        log::debug!("No producer on '{key_expr}' yet");
+        deadline.wait_at_most(&appeared, seen, RETRY_INTERVAL)?;
+    }
+}
@@ -0,0 +1,142 @@
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::os::fd::{AsRawFd, RawFd};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use zshm::await_1n::Consumer;
+    use zshm::cli::{Args, Defaults};
+    use zshm::lease::CHECK_INTERVAL;
+
+    // Tags of the descriptors in the epoll set
+    const CHANNEL: u64 = 0;
+    const STDIN: u64 = 1;
+
+    pub(crate) fn main() {
+        let args = Args::parse(
+            "1:N eventfd consumer multiplexing the channel and stdin with epoll",
+            Defaults {
+                key_expr: "shm/await/buffer_1n",
+                ..Default::default()
+            },
+        );
+
+        // Set up Ctrl-C handler
+        let running = Arc::new(AtomicBool::new(true));
+        let r = running.clone();
+
+        ctrlc::set_handler(move || {
+            println!("\nReceived Ctrl-C! Shutting down gracefully...");
+            r.store(false, Ordering::Release);
+        })
+        .expect("Error setting Ctrl-C handler");
+
+        let z = args.open().expect("Failed to open Zenoh session");
+
+        let mut consumer = match Consumer::attach(&z, &args.key_expr) {
+            Ok(consumer) => consumer,
+            Err(e) => {
+                println!("Failed to attach: {e}");
+                return;
+            }
+        };
+        let Some(fd) = consumer.fd().map(|fd| fd.as_raw_fd()) else {
+            println!("The producer does not use eventfds, start it with --eventfd");
+            return;
+        };
+        println!("Received SHM buffer, lines typed on stdin are echoed meanwhile");
+
+        // SAFETY: plain syscalls on descriptors that outlive the loop.
+        let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        assert!(epoll >= 0, "Failed to create epoll instance");
+        add(epoll, fd, CHANNEL);
+        add(epoll, libc::STDIN_FILENO, STDIN);
+
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; 2];
+        let mut received = 0;
+        while running.load(Ordering::Acquire) {
+            // Checked first, so that the last sample published is read
+            let connected = consumer.is_connected();
+            let n = unsafe {
+                libc::epoll_wait(
+                    epoll,
+                    events.as_mut_ptr(),
+                    events.len() as i32,
+                    CHECK_INTERVAL.as_millis() as i32,
+                )
+            };
+            for event in &events[..n.max(0) as usize] {
+                match event.u64 {
+                    CHANNEL => {
+                        if consume(&mut consumer) {
+                            received += 1;
+                        }
+                    }
+                    _ => {
+                        let mut line = [0u8; 256];
+                        let len = unsafe {
+                            libc::read(libc::STDIN_FILENO, line.as_mut_ptr().cast(), line.len())
+                        };
+                        if len <= 0 {
+                            // End of input, keep polling the channel alone
+                            unsafe {
+                                libc::epoll_ctl(
+                                    epoll,
+                                    libc::EPOLL_CTL_DEL,
+                                    libc::STDIN_FILENO,
+                                    std::ptr::null_mut(),
+                                )
+                            };
+                        } else {
+                            let line = String::from_utf8_lossy(&line[..len as usize]);
+                            println!("stdin: {}", line.trim_end());
+                        }
+                    }
+                }
+            }
+            if args.done(received) {
+                break;
+            }
+            if !connected && !consume(&mut consumer) {
+                println!("Producer is gone");
+                break;
+            }
+        }
+        unsafe { libc::close(epoll) };
+        println!("Epoll consumer stopped.");
+    }
+
+    /// Reads the current sample if it is new. Returns whether there was one.
+    fn consume(consumer: &mut Consumer) -> bool {
+        consumer
+            .try_recv_with(|sn, data| {
+                let sum: u32 = data.iter().map(|&b| b as u32).sum();
+                println!(
+                    "{sn} - Consumed buffer of {} bytes with sum {sum}",
+                    data.len()
+                );
+            })
+            .is_some()
+    }
+
+    fn add(epoll: RawFd, fd: RawFd, tag: u64) {
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: tag,
+        };
+        let r = unsafe { libc::epoll_ctl(epoll, libc::EPOLL_CTL_ADD, fd, &mut event) };
+        assert!(r == 0, "Failed to register descriptor {fd}");
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    pub(crate) fn main() {
+        println!("This program only runs on Linux due to eventfd usage.");
+        std::process::exit(1);
+    }
+}
+
+fn main() {
+    platform::main();
+}
@@ -0,0 +1,95 @@
+use std::fmt;
+
+use crate::header::LayoutError;
+
+/// Errors returned while setting up, attaching to or waiting on a channel.
+#[derive(Debug)]
+pub enum Error {
+    /// A Zenoh operation (session, queryable, get) failed.
+    Zenoh(zenoh::Error),
+    /// The SHM provider could not be created or could not allocate the segment.
+    Alloc(String),
+    /// Another producer already serves the key expression.
+    ProducerExists(String),
+    /// No producer replied to the bootstrap query on the given key expression.
+    NoProducer(String),
+    /// The producer replied with an error.
+    Reply(String),
+    /// The reply was not a shared-memory buffer (e.g. producer on another host).
+    NotShm,
+    /// Every consumer slot of the segment is taken.
+    Full(usize),
+    /// A blocking operation reached its deadline.
+    Timeout,
+    /// A blocking operation was cancelled through its token.
+    Cancelled,
+    /// The producer closed the channel or died, and no sample is left.
+    Disconnected,
+    /// The producer reaped this consumer, whose heartbeat it deemed expired,
+    /// e.g. because the consumer was stopped in a debugger. It receives
+    /// nothing more and has to attach again.
+    Evicted,
+    /// A sample does not fit in the slots of the channel.
+    TooLarge { len: usize, capacity: usize },
+    /// The received buffer does not hold the expected layout.
+    Layout(LayoutError),
+    /// Setting up or using a notification descriptor failed.
+    Io(std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Zenoh(e) => write!(f, "zenoh error: {e}"),
+            Error::Alloc(e) => write!(f, "failed to allocate shared segment: {e}"),
+            Error::ProducerExists(key) => write!(f, "a producer already serves '{key}'"),
+            Error::NoProducer(key) => write!(f, "no producer replied on '{key}'"),
+            Error::Reply(e) => write!(f, "producer replied with an error: {e}"),
+            Error::NotShm => write!(f, "received a non-SHM buffer"),
+            Error::Full(n) => write!(f, "all {n} consumer slots are taken"),
+            Error::Timeout => write!(f, "timed out"),
+            Error::Cancelled => write!(f, "cancelled"),
+            Error::Disconnected => write!(f, "the producer is gone"),
+            Error::Evicted => write!(f, "the producer evicted this consumer"),
+            Error::TooLarge { len, capacity } => {
+                write!(
+                    f,
+                    "sample of {len} bytes exceeds the capacity of {capacity} bytes"
+                )
+            }
+            Error::Layout(e) => write!(f, "incompatible segment: {e}"),
+            Error::Io(e) => write!(f, "notification error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Zenoh(e) => Some(e.as_ref()),
+            Error::Layout(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<zenoh::Error> for Error {
+    fn from(e: zenoh::Error) -> Self {
+        Error::Zenoh(e)
+    }
+}
+
+impl From<LayoutError> for Error {
+    fn from(e: LayoutError) -> Self {
+        Error::Layout(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
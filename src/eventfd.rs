@@ -0,0 +1,331 @@
+//! eventfd notifications for the 1:N await mailbox (Linux only).
+//!
+//! Futex waits cannot be multiplexed with sockets or timers. With
+//! [`crate::await_1n::Backend::EventFd`], every consumer owns an eventfd that
+//! the producer writes on each publication, and the producer owns one that
+//! consumers write when they hand the mailbox back or join. A descriptor is
+//! readable exactly when its owner has something to check, so it can sit in
+//! an epoll set (or be registered with mio through `SourceFd`) next to any
+//! other file descriptor.
+//!
+//! The descriptors are exchanged with `SCM_RIGHTS` over a Unix domain socket
+//! in the abstract namespace, named after a random id the producer stores in
+//! the segment: a consumer connects right after joining, sends its slot and
+//! its eventfd, and receives the producer's eventfd in return.
+//!
+//! The shared [`crate::notify::Event`]s are still notified alongside, so
+//! waits relying on them keep working on an eventfd mailbox.
+
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::Result;
+use crate::consumers::{MAX_CONSUMERS, Membership};
+use crate::deadline::Deadline;
+
+/// How long either side waits for the other during the exchange.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Bytes sent by a consumer along with its eventfd: slot index and id.
+const REQUEST_LEN: usize = 2 * std::mem::size_of::<u64>();
+
+/// A non-blocking eventfd, readable once notified until drained.
+pub struct EventFd {
+    fd: OwnedFd,
+}
+
+impl EventFd {
+    pub fn new() -> io::Result<Self> {
+        // SAFETY: plain syscall, the descriptor is owned from here on.
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EventFd {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    /// Makes the descriptor readable.
+    pub fn notify(&self) {
+        let one = 1u64;
+        // Only fails when the counter would overflow, i.e. it is readable
+        // anyway
+        unsafe { libc::write(self.fd.as_raw_fd(), (&raw const one).cast(), 8) };
+    }
+
+    /// Resets the descriptor. Returns whether it was notified.
+    pub fn drain(&self) -> bool {
+        let mut count = 0u64;
+        let n = unsafe { libc::read(self.fd.as_raw_fd(), (&raw mut count).cast(), 8) };
+        n == 8
+    }
+
+    /// Blocks until the descriptor is readable or `timeout` elapses. Returns
+    /// `false` on timeout.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let mut pfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let millis = timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32;
+        // An interrupted poll counts as a spurious wakeup
+        unsafe { libc::poll(&mut pfd, 1, millis) > 0 }
+    }
+
+    /// Counterpart of [`Deadline::wait_at_most`]: blocks until the descriptor
+    /// is readable, `interval` elapses or the deadline passes. A cancelled
+    /// token cannot interrupt the poll, so it is noticed after `interval` at
+    /// the latest.
+    pub fn wait_at_most(&self, deadline: &Deadline, interval: Duration) -> Result<()> {
+        deadline.check()?;
+        let timeout = match deadline.instant() {
+            Some(at) => interval.min(at.saturating_duration_since(Instant::now())),
+            None => interval,
+        };
+        self.wait_timeout(timeout);
+        deadline.check()
+    }
+}
+
+impl AsFd for EventFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+fn address(socket: u64) -> io::Result<SocketAddr> {
+    SocketAddr::from_abstract_name(format!("zshm/{socket:016x}"))
+}
+
+/// Consumer eventfds known to the producer, by slot.
+type Links = Mutex<Vec<Option<(Membership, EventFd)>>>;
+
+/// Producer side of the exchange: accepts consumers from a background thread
+/// and keeps their eventfds.
+pub(crate) struct Registry {
+    socket: u64,
+    free: Arc<EventFd>,
+    consumers: Arc<Links>,
+    listener: Arc<UnixListener>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Registry {
+    /// Binds a fresh socket. `is_member` tells whether a slot index and id
+    /// sent by a consumer match a registered consumer.
+    pub(crate) fn start<F>(is_member: F) -> Result<Self>
+    where
+        F: Fn(Membership) -> bool + Send + 'static,
+    {
+        let (socket, listener) = loop {
+            let socket = rand::random::<u64>();
+            match UnixListener::bind_addr(&address(socket)?) {
+                Ok(listener) => break (socket, Arc::new(listener)),
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let free = Arc::new(EventFd::new()?);
+        let consumers: Arc<Links> = Arc::new(Mutex::new(Vec::new()));
+        consumers
+            .lock()
+            .unwrap()
+            .resize_with(MAX_CONSUMERS, || None);
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let (l, f, c, s) = (
+            listener.clone(),
+            free.clone(),
+            consumers.clone(),
+            stop.clone(),
+        );
+        std::thread::spawn(move || {
+            for stream in l.incoming() {
+                if s.load(Ordering::Acquire) {
+                    break;
+                }
+                let accepted = stream.and_then(|stream| accept(&stream, &f, &c, &is_member));
+                if let Err(e) = accepted {
+                    log::warn!("Failed to exchange eventfds with a consumer: {e}");
+                }
+            }
+        });
+        Ok(Registry {
+            socket,
+            free,
+            consumers,
+            listener,
+            stop,
+        })
+    }
+
+    /// Id of the socket, to be stored in the segment.
+    pub(crate) fn socket(&self) -> u64 {
+        self.socket
+    }
+
+    /// The eventfd consumers write when they hand the mailbox back or join.
+    pub(crate) fn free(&self) -> &EventFd {
+        &self.free
+    }
+
+    /// Writes the eventfd of every consumer still holding its slot, and
+    /// forgets the others.
+    pub(crate) fn notify_all(&self, is_member: impl Fn(Membership) -> bool) {
+        for link in self.consumers.lock().unwrap().iter_mut() {
+            match link {
+                Some((m, ready)) if is_member(*m) => ready.notify(),
+                Some(_) => *link = None,
+                None => {}
+            }
+        }
+    }
+}
+
+impl Drop for Registry {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        // Wakes the thread blocked in `accept`
+        unsafe { libc::shutdown(self.listener.as_raw_fd(), libc::SHUT_RDWR) };
+    }
+}
+
+fn accept(
+    stream: &UnixStream,
+    free: &EventFd,
+    consumers: &Links,
+    is_member: &impl Fn(Membership) -> bool,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut request = [0; REQUEST_LEN];
+    let ready = recv_fd(stream, &mut request)?;
+    let (index, id) = request.split_at(REQUEST_LEN / 2);
+    let m = Membership {
+        index: u64::from_le_bytes(index.try_into().unwrap()) as usize,
+        id: u64::from_le_bytes(id.try_into().unwrap()),
+    };
+    if m.index >= MAX_CONSUMERS || !is_member(m) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("slot {} is not held by consumer {}", m.index, m.id),
+        ));
+    }
+    consumers.lock().unwrap()[m.index] = Some((m, EventFd { fd: ready }));
+    send_fd(stream, &[1], free.as_fd())
+}
+
+/// Consumer side of the exchange: the consumer's own eventfd, and the
+/// producer's.
+pub(crate) struct Link {
+    pub(crate) ready: EventFd,
+    pub(crate) free: EventFd,
+}
+
+impl Link {
+    /// Registers consumer `m` with the producer listening on `socket`.
+    pub(crate) fn connect(socket: u64, m: Membership) -> Result<Self> {
+        let stream = UnixStream::connect_addr(&address(socket)?)?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let ready = EventFd::new()?;
+        let mut request = [0; REQUEST_LEN];
+        request[..REQUEST_LEN / 2].copy_from_slice(&(m.index as u64).to_le_bytes());
+        request[REQUEST_LEN / 2..].copy_from_slice(&m.id.to_le_bytes());
+        send_fd(&stream, &request, ready.as_fd())?;
+        let free = recv_fd(&stream, &mut [0])?;
+        // A publication may have slipped in before the producer knew us
+        ready.notify();
+        Ok(Link {
+            ready,
+            free: EventFd { fd: free },
+        })
+    }
+}
+
+/// Control buffer with room for a single descriptor, aligned for `cmsghdr`.
+type Control = [u64; 4];
+
+fn message(iov: &mut libc::iovec, control: &mut Control) -> libc::msghdr {
+    // SAFETY: `msghdr` is plain data, all zeros is a valid empty header.
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as _;
+    msg
+}
+
+/// Sends `data` along with a duplicate of `fd`.
+fn send_fd(stream: &UnixStream, data: &[u8], fd: BorrowedFd<'_>) -> io::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut _,
+        iov_len: data.len(),
+    };
+    let mut control = Control::default();
+    let msg = message(&mut iov, &mut control);
+    // SAFETY: the control buffer has room for one descriptor.
+    let n = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+        libc::CMSG_DATA(cmsg)
+            .cast::<RawFd>()
+            .write_unaligned(fd.as_raw_fd());
+        libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL)
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if n as usize != data.len() {
+        return Err(io::ErrorKind::WriteZero.into());
+    }
+    Ok(())
+}
+
+/// Receives exactly `data.len()` bytes along with a descriptor.
+fn recv_fd(stream: &UnixStream, data: &mut [u8]) -> io::Result<OwnedFd> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr().cast(),
+        iov_len: data.len(),
+    };
+    let mut control = Control::default();
+    let mut msg = message(&mut iov, &mut control);
+    let n = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the kernel filled in the control buffer, and a descriptor it
+    // passed is ours to close.
+    let fd = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no descriptor received",
+            ));
+        }
+        OwnedFd::from_raw_fd(libc::CMSG_DATA(cmsg).cast::<RawFd>().read_unaligned())
+    };
+    if n as usize != data.len() || msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(fd)
+}
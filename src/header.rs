@@ -0,0 +1,271 @@
+//! Header placed at the start of every shared segment.
+//!
+//! The producer fills it before serving the segment; consumers validate it
+//! before interpreting the rest of the buffer, so that a consumer pointed at
+//! the wrong key, or built against a different layout, refuses to attach
+//! instead of misreading the producer's atomics. The header also records the
+//! payload capacity of the slots, chosen by the producer, from which consumers
+//! size their views, the [`MessageType`] of typed channels, and the
+//! producer's [`ProducerLease`], through which consumers learn that the
+//! stream ended, and the [`Counters`] of the channel.
+
+use std::fmt;
+
+use crate::lease::ProducerLease;
+use crate::pod::{self, ShmPod};
+use crate::stats::Counters;
+
+/// `"ZSHMSEG\0"` read as a little-endian integer.
+pub const MAGIC: u64 = u64::from_le_bytes(*b"ZSHMSEG\0");
+
+/// Synchronization protocol implemented by a segment.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Polling = 1,
+    Polling1N = 2,
+    Await1N = 3,
+    Spsc = 4,
+    Broadcast = 5,
+    Mpmc = 6,
+    Seqlock = 7,
+}
+
+impl Protocol {
+    pub fn from_u32(v: u32) -> Option<Self> {
+        match v {
+            1 => Some(Protocol::Polling),
+            2 => Some(Protocol::Polling1N),
+            3 => Some(Protocol::Await1N),
+            4 => Some(Protocol::Spsc),
+            5 => Some(Protocol::Broadcast),
+            6 => Some(Protocol::Mpmc),
+            7 => Some(Protocol::Seqlock),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Protocol::Polling => "polling",
+            Protocol::Polling1N => "polling-1n",
+            Protocol::Await1N => "await-1n",
+            Protocol::Spsc => "spsc",
+            Protocol::Broadcast => "broadcast",
+            Protocol::Mpmc => "mpmc",
+            Protocol::Seqlock => "seqlock",
+        };
+        f.write_str(name)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct Header {
+    pub magic: u64,
+    pub protocol: u32,
+    pub version: u32,
+    pub size: u64,     // Total size of the segment, header and tail included
+    pub align: u64,    // Alignment of the layout
+    pub capacity: u64, // Payload capacity of each slot, in bytes
+    pub message: MessageType,
+    pub producer: ProducerLease,
+    pub stats: Counters,
+}
+
+impl Header {
+    pub(crate) fn new<T: Layout>(size: usize, capacity: usize) -> Self {
+        Header {
+            magic: MAGIC,
+            protocol: T::PROTOCOL as u32,
+            version: T::VERSION,
+            size: size as u64,
+            align: std::mem::align_of::<T>() as u64,
+            capacity: capacity as u64,
+            message: MessageType::BYTES,
+            producer: ProducerLease::new(),
+            stats: Counters::default(),
+        }
+    }
+
+    /// Checks that this header describes a segment of layout `T`. Whether
+    /// `size` matches the tail announced by `T` is checked once `T` itself
+    /// can be read.
+    pub(crate) fn validate<T: Layout>(&self) -> Result<(), LayoutError> {
+        if self.magic != MAGIC {
+            return Err(LayoutError::BadMagic(self.magic));
+        }
+        if self.protocol != T::PROTOCOL as u32 {
+            return Err(LayoutError::Protocol {
+                expected: T::PROTOCOL,
+                found: self.protocol,
+            });
+        }
+        if self.version != T::VERSION {
+            return Err(LayoutError::Version {
+                expected: T::VERSION,
+                found: self.version,
+            });
+        }
+        let size = std::mem::size_of::<T>() as u64;
+        let align = std::mem::align_of::<T>() as u64;
+        if self.size < size || self.align != align {
+            return Err(LayoutError::Shape {
+                size,
+                align,
+                found_size: self.size,
+                found_align: self.align,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Type of the messages carried by a typed channel, see [`crate::pod`]. All
+/// zeros for channels of raw bytes.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MessageType {
+    pub size: u64,
+    pub align: u64,
+    pub fingerprint: u64,
+}
+
+impl MessageType {
+    /// Raw bytes, no particular type.
+    pub const BYTES: MessageType = MessageType {
+        size: 0,
+        align: 0,
+        fingerprint: 0,
+    };
+
+    pub fn of<M: ShmPod>() -> Self {
+        MessageType {
+            size: std::mem::size_of::<M>() as u64,
+            align: std::mem::align_of::<M>() as u64,
+            fingerprint: M::FINGERPRINT,
+        }
+    }
+
+    /// Variable-length messages rooted at an `R`, see [`crate::arena`].
+    pub fn arena<R: ShmPod>() -> Self {
+        MessageType {
+            fingerprint: pod::mix(R::FINGERPRINT, u64::from_le_bytes(*b"ARENA\0\0\0")),
+            ..Self::of::<R>()
+        }
+    }
+}
+
+impl fmt::Display for MessageType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *self == MessageType::BYTES {
+            return f.write_str("raw bytes");
+        }
+        write!(
+            f,
+            "{} bytes aligned to {} with fingerprint {:#018x}",
+            self.size, self.align, self.fingerprint
+        )
+    }
+}
+
+/// A `#[repr(C)]` layout starting with a [`Header`], optionally followed by
+/// a variable-length tail (e.g. the slots of a ring).
+///
+/// # Safety
+/// Implementors must be `#[repr(C)]` with a [`Header`] as first field, and the
+/// all-zero bit pattern must be a valid value for every other field.
+pub unsafe trait Layout: Sync {
+    const PROTOCOL: Protocol;
+    /// Bumped whenever the layout changes in an incompatible way.
+    const VERSION: u32;
+
+    /// Number of bytes following the layout, as described by its fields.
+    fn tail_len(&self) -> usize {
+        0
+    }
+
+    /// Number of slots of `header.capacity` bytes, 1 for mailboxes.
+    fn slot_count(&self) -> usize {
+        1
+    }
+
+    /// The header the layout starts with.
+    fn header(&self) -> &Header
+    where
+        Self: Sized,
+    {
+        // SAFETY: implementors start with a `Header`.
+        unsafe { &*(self as *const Self as *const Header) }
+    }
+}
+
+/// Why a received buffer was refused.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LayoutError {
+    /// The buffer is shorter than the header or the layout it announces.
+    TooShort { expected: usize, found: usize },
+    /// The buffer is not aligned for the layout.
+    Misaligned { align: usize, addr: usize },
+    /// The buffer does not start with [`MAGIC`].
+    BadMagic(u64),
+    /// The segment implements another protocol.
+    Protocol { expected: Protocol, found: u32 },
+    /// The segment was created with another version of the layout.
+    Version { expected: u32, found: u32 },
+    /// A field describing the layout holds an impossible value.
+    Field { name: &'static str, value: u64 },
+    /// The segment carries messages of another type.
+    Message {
+        expected: MessageType,
+        found: MessageType,
+    },
+    /// Same protocol and version, but size or alignment differ.
+    Shape {
+        size: u64,
+        align: u64,
+        found_size: u64,
+        found_align: u64,
+    },
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutError::TooShort { expected, found } => {
+                write!(
+                    f,
+                    "buffer of {found} bytes is shorter than {expected} bytes"
+                )
+            }
+            LayoutError::Misaligned { align, addr } => {
+                write!(f, "buffer at {addr:#x} is not aligned to {align}")
+            }
+            LayoutError::BadMagic(magic) => write!(f, "bad magic {magic:#018x}"),
+            LayoutError::Protocol { expected, found } => match Protocol::from_u32(*found) {
+                Some(found) => write!(f, "expected a {expected} segment, found {found}"),
+                None => write!(f, "expected a {expected} segment, found protocol {found}"),
+            },
+            LayoutError::Version { expected, found } => {
+                write!(f, "expected layout version {expected}, found {found}")
+            }
+            LayoutError::Field { name, value } => write!(f, "invalid {name} {value}"),
+            LayoutError::Message { expected, found } => {
+                write!(f, "expected messages of {expected}, found {found}")
+            }
+            LayoutError::Shape {
+                size,
+                align,
+                found_size,
+                found_align,
+            } => write!(
+                f,
+                "expected {size} bytes aligned to {align}, found {found_size} bytes aligned to {found_align}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
@@ -0,0 +1,413 @@
+//! Read-only snapshots of live segments, for debugging.
+//!
+//! [`Snapshot::of`] maps the segment of a [`Channel`] found by
+//! [`crate::discovery`] and reads its header, its synchronization state, its
+//! table of consumers and the payload of the newest sample, without ever
+//! writing to it: in particular it does not register as a consumer, so that
+//! it does not disturb the credits and counts it shows. Fields are read one
+//! by one while the producer and consumers keep going, so a snapshot of a
+//! busy channel need not be consistent, and the payload may be torn.
+
+use std::fmt;
+use std::sync::atomic::Ordering;
+
+use crate::broadcast::{self, READING};
+use crate::consumers::{Consumers, FREE};
+use crate::discovery::Channel;
+use crate::header::{LayoutError, Protocol};
+use crate::lease::now_millis;
+use crate::notify::Event;
+use crate::segment::Segment;
+use crate::stats::{ConsumerCounters, Report, Stats};
+use crate::{Result, mpmc, polling, polling_1n, seqlock, spsc};
+
+/// A named field of a segment and the value it held.
+pub type Field = (&'static str, i64);
+
+/// The state of a channel when it was inspected.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub channel: Channel,
+    /// Header fields not already described by the channel.
+    pub header: Vec<Field>,
+    /// Fields of the layout, in layout order.
+    pub state: Vec<Field>,
+    /// Slots of the consumer table, or cursors, that are in use.
+    pub consumers: Vec<(usize, Vec<Field>)>,
+    pub stats: Stats,
+    /// Length of the payload of the newest sample.
+    pub payload_len: usize,
+    /// Copy of its first bytes, up to the limit passed to [`Snapshot::of`].
+    pub payload: Vec<u8>,
+}
+
+impl Snapshot {
+    /// Reads the segment of `channel`, copying up to `max_payload` bytes of
+    /// the newest sample. Fails if this build cannot read the layout.
+    pub fn of(channel: &Channel, max_payload: usize) -> Result<Self> {
+        let protocol = channel.protocol.ok_or(LayoutError::Field {
+            name: "protocol",
+            value: channel.header().protocol as u64,
+        })?;
+        match protocol {
+            Protocol::Polling => read::<polling::SharedData>(channel, max_payload),
+            Protocol::Polling1N => read::<polling_1n::SharedData>(channel, max_payload),
+            #[cfg(target_os = "linux")]
+            Protocol::Await1N => read::<crate::await_1n::SharedData>(channel, max_payload),
+            #[cfg(not(target_os = "linux"))]
+            Protocol::Await1N => Err(LayoutError::Field {
+                name: "protocol",
+                value: protocol as u64,
+            }
+            .into()),
+            Protocol::Spsc => read::<spsc::SharedData>(channel, max_payload),
+            Protocol::Broadcast => read::<broadcast::SharedData>(channel, max_payload),
+            Protocol::Mpmc => read::<mpmc::SharedData>(channel, max_payload),
+            Protocol::Seqlock => read::<seqlock::SharedData>(channel, max_payload),
+        }
+    }
+}
+
+/// What a layout shows beyond its header and counters.
+trait Inspect: Report {
+    fn state(shared: &Segment<Self>) -> Vec<Field>;
+
+    /// Slots of the consumer table, or cursors, that are in use.
+    fn slots(_shared: &Segment<Self>) -> Vec<(usize, Vec<Field>)> {
+        Vec::new()
+    }
+
+    /// Length and start of the payload of the newest sample, if any.
+    fn payload(shared: &Segment<Self>) -> Option<(usize, *const u8)>;
+}
+
+fn read<T: Inspect>(channel: &Channel, max_payload: usize) -> Result<Snapshot> {
+    let shared = Segment::<T>::attach(channel.payload().clone())?;
+    let header = shared.header();
+    let lease = &header.producer;
+    let heartbeat = lease.heartbeat.load(Ordering::Acquire);
+    let (payload_len, payload) = match T::payload(&shared) {
+        Some((len, data)) => {
+            let len = len.min(header.capacity as usize);
+            // SAFETY: `data` points to a slot of `capacity` bytes within the
+            // segment. The copy races with the producer, as documented.
+            let mut copy = vec![0; len.min(max_payload)];
+            unsafe { std::ptr::copy_nonoverlapping(data, copy.as_mut_ptr(), copy.len()) };
+            (len, copy)
+        }
+        None => (0, Vec::new()),
+    };
+    Ok(Snapshot {
+        channel: channel.clone(),
+        header: vec![
+            ("size", header.size as i64),
+            ("align", header.align as i64),
+            ("producer.state", lease.state.load(Ordering::Acquire) as i64),
+            ("producer.heartbeat_age_ms", age(heartbeat)),
+            ("producer.epoch", lease.epoch() as i64),
+        ],
+        state: T::state(&shared),
+        consumers: T::slots(&shared),
+        stats: Stats::of(&shared),
+        payload_len,
+        payload,
+    })
+}
+
+/// Milliseconds elapsed since a heartbeat.
+fn age(heartbeat: u64) -> i64 {
+    now_millis().saturating_sub(heartbeat) as i64
+}
+
+/// The futex word of an event, i.e. its generation, and its waiters.
+fn event(futex: &'static str, waiters: &'static str, event: &Event) -> [Field; 2] {
+    [
+        (futex, event.generation() as i64),
+        (waiters, event.waiters() as i64),
+    ]
+}
+
+fn counters(counters: &ConsumerCounters) -> [Field; 3] {
+    [
+        ("consumed", counters.consumed.load(Ordering::Relaxed) as i64),
+        ("skipped", counters.skipped.load(Ordering::Relaxed) as i64),
+        (
+            "waited_ns",
+            counters.waited_nanos.load(Ordering::Relaxed) as i64,
+        ),
+    ]
+}
+
+/// Fields of the consumer table of a 1:N mailbox.
+fn table_state(consumers: &Consumers) -> [Field; 4] {
+    [
+        ("consumers.sn", consumers.sn.load(Ordering::Acquire) as i64),
+        (
+            "read_count",
+            consumers.read_count.load(Ordering::Acquire) as i64,
+        ),
+        (
+            "sub_count",
+            consumers.sub_count.load(Ordering::Acquire) as i64,
+        ),
+        ("next_id", consumers.next_id.load(Ordering::Acquire) as i64),
+    ]
+}
+
+/// Slots of the consumer table of a 1:N mailbox that are not free.
+fn table(consumers: &Consumers) -> Vec<(usize, Vec<Field>)> {
+    consumers
+        .slots
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| s.state.load(Ordering::Acquire) != FREE)
+        .map(|(index, s)| {
+            let mut fields = vec![
+                ("state", s.state.load(Ordering::Acquire) as i64),
+                ("pid", s.pid.load(Ordering::Acquire) as i64),
+                ("id", s.id.load(Ordering::Acquire) as i64),
+                ("heartbeat_age_ms", age(s.heartbeat.load(Ordering::Acquire))),
+                ("owed", s.owed.load(Ordering::Acquire) as i64),
+                ("first", s.first.load(Ordering::Acquire) as i64),
+            ];
+            fields.extend(counters(&s.stats));
+            (index, fields)
+        })
+        .collect()
+}
+
+impl Inspect for polling::SharedData {
+    fn state(shared: &Segment<Self>) -> Vec<Field> {
+        vec![("len", shared.len.load(Ordering::Acquire) as i64)]
+    }
+
+    fn payload(shared: &Segment<Self>) -> Option<(usize, *const u8)> {
+        Some((shared.len.load(Ordering::Acquire), shared.tail()))
+    }
+}
+
+impl Inspect for polling_1n::SharedData {
+    fn state(shared: &Segment<Self>) -> Vec<Field> {
+        let mut state = vec![
+            ("len", shared.len.load(Ordering::Acquire) as i64),
+            ("sn", shared.sn.load(Ordering::Acquire) as i64),
+        ];
+        state.extend(table_state(&shared.consumers));
+        state
+    }
+
+    fn slots(shared: &Segment<Self>) -> Vec<(usize, Vec<Field>)> {
+        table(&shared.consumers)
+    }
+
+    fn payload(shared: &Segment<Self>) -> Option<(usize, *const u8)> {
+        Some((shared.len.load(Ordering::Acquire), shared.tail()))
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Inspect for crate::await_1n::SharedData {
+    fn state(shared: &Segment<Self>) -> Vec<Field> {
+        let mut state = vec![("state.futex", shared.state.load(Ordering::Acquire) as i64)];
+        state.extend(event("ready.futex", "ready.waiters", &shared.ready));
+        state.extend(event("free.futex", "free.waiters", &shared.free));
+        state.extend([
+            ("len", shared.len.load(Ordering::Acquire) as i64),
+            ("sn", shared.sn.load(Ordering::Acquire) as i64),
+            ("backend", shared.backend as i64),
+            ("socket", shared.socket.load(Ordering::Acquire) as i64),
+        ]);
+        state.extend(table_state(&shared.consumers));
+        state
+    }
+
+    fn slots(shared: &Segment<Self>) -> Vec<(usize, Vec<Field>)> {
+        table(&shared.consumers)
+    }
+
+    fn payload(shared: &Segment<Self>) -> Option<(usize, *const u8)> {
+        Some((shared.len.load(Ordering::Acquire), shared.tail()))
+    }
+}
+
+impl Inspect for spsc::SharedData {
+    fn state(shared: &Segment<Self>) -> Vec<Field> {
+        let mut state = vec![
+            (
+                "consumer.state",
+                shared.consumer.state.load(Ordering::Acquire) as i64,
+            ),
+            (
+                "consumer.pid",
+                shared.consumer.pid.load(Ordering::Acquire) as i64,
+            ),
+            (
+                "consumer.id",
+                shared.consumer.id.load(Ordering::Acquire) as i64,
+            ),
+            ("head", shared.head.load(Ordering::Acquire) as i64),
+            ("tail", shared.tail.load(Ordering::Acquire) as i64),
+        ];
+        state.extend(event(
+            "not_empty.futex",
+            "not_empty.waiters",
+            &shared.not_empty,
+        ));
+        state.extend(event(
+            "not_full.futex",
+            "not_full.waiters",
+            &shared.not_full,
+        ));
+        state
+    }
+
+    fn payload(shared: &Segment<Self>) -> Option<(usize, *const u8)> {
+        let newest = shared.head.load(Ordering::Acquire).checked_sub(1)?;
+        let (len, data) = spsc::slot(shared, newest);
+        // SAFETY: the slot lies within the segment.
+        Some((unsafe { len.read_volatile() } as usize, data))
+    }
+}
+
+impl Inspect for broadcast::SharedData {
+    fn state(shared: &Segment<Self>) -> Vec<Field> {
+        let mut state = vec![("head", shared.head.load(Ordering::Acquire) as i64)];
+        state.extend(event(
+            "published.futex",
+            "published.waiters",
+            &shared.published,
+        ));
+        state.extend(event(
+            "released.futex",
+            "released.waiters",
+            &shared.released,
+        ));
+        state
+    }
+
+    fn slots(shared: &Segment<Self>) -> Vec<(usize, Vec<Field>)> {
+        broadcast::cursors(shared)
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.state.load(Ordering::Acquire) != broadcast::FREE)
+            .map(|(index, c)| {
+                let position = c.position.load(Ordering::Acquire);
+                let mut fields = vec![
+                    ("state", c.state.load(Ordering::Acquire) as i64),
+                    ("pid", c.pid.load(Ordering::Acquire) as i64),
+                    ("id", c.id.load(Ordering::Acquire) as i64),
+                    ("heartbeat_age_ms", age(c.heartbeat.load(Ordering::Acquire))),
+                    ("position", (position & !READING) as i64),
+                    ("reading", (position & READING != 0) as i64),
+                ];
+                fields.extend(counters(&c.stats));
+                (index, fields)
+            })
+            .collect()
+    }
+
+    fn payload(shared: &Segment<Self>) -> Option<(usize, *const u8)> {
+        let newest = shared.head.load(Ordering::Acquire).checked_sub(1)?;
+        let (len, data) = broadcast::slot(shared, newest);
+        // SAFETY: the slot lies within the segment.
+        Some((unsafe { len.read_volatile() } as usize, data))
+    }
+}
+
+impl Inspect for mpmc::SharedData {
+    fn state(shared: &Segment<Self>) -> Vec<Field> {
+        let mut state = vec![
+            ("producers", shared.producers.load(Ordering::Acquire) as i64),
+            ("head", shared.head.load(Ordering::Acquire) as i64),
+            ("tail", shared.tail.load(Ordering::Acquire) as i64),
+        ];
+        state.extend(event(
+            "not_empty.futex",
+            "not_empty.waiters",
+            &shared.not_empty,
+        ));
+        state.extend(event(
+            "not_full.futex",
+            "not_full.waiters",
+            &shared.not_full,
+        ));
+        state
+    }
+
+    fn payload(shared: &Segment<Self>) -> Option<(usize, *const u8)> {
+        let newest = shared.head.load(Ordering::Acquire).checked_sub(1)?;
+        let (header, data) = mpmc::slot(shared, newest);
+        Some((header.len.load(Ordering::Acquire) as usize, data))
+    }
+}
+
+impl Inspect for seqlock::SharedData {
+    fn state(shared: &Segment<Self>) -> Vec<Field> {
+        let mut state = vec![
+            ("seq", shared.seq.load(Ordering::Acquire) as i64),
+            ("len", shared.len.load(Ordering::Acquire) as i64),
+        ];
+        state.extend(event("updated.futex", "updated.waiters", &shared.updated));
+        state
+    }
+
+    fn payload(shared: &Segment<Self>) -> Option<(usize, *const u8)> {
+        Some((shared.len.load(Ordering::Acquire), shared.tail()))
+    }
+}
+
+fn fields(f: &mut fmt::Formatter<'_>, fields: &[Field]) -> fmt::Result {
+    for (i, (name, value)) in fields.iter().enumerate() {
+        let sep = if i == 0 { "" } else { " " };
+        write!(f, "{sep}{name}={value}")?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.channel)?;
+        f.write_str("  header     ")?;
+        fields(f, &self.header)?;
+        f.write_str("\n  state      ")?;
+        fields(f, &self.state)?;
+        let s = &self.stats;
+        writeln!(
+            f,
+            "\n  stats      produced={} consumed={} skipped={} blocked_ns={} waited_ns={} lag={}",
+            s.produced,
+            s.consumed,
+            s.skipped,
+            s.producer_blocked.as_nanos(),
+            s.consumer_waited.as_nanos(),
+            s.lag,
+        )?;
+        for (index, consumer) in &self.consumers {
+            write!(f, "  consumer {index:<2} ")?;
+            fields(f, consumer)?;
+            writeln!(f)?;
+        }
+        write!(f, "  payload    {} bytes", self.payload_len)?;
+        if self.payload.len() < self.payload_len {
+            write!(f, ", first {} shown", self.payload.len())?;
+        }
+        for (i, line) in self.payload.chunks(16).enumerate() {
+            write!(f, "\n    {:08x} ", i * 16)?;
+            for byte in line {
+                write!(f, " {byte:02x}")?;
+            }
+            let ascii: String = line
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            write!(f, "{:width$}  |{ascii}|", "", width = 3 * (16 - line.len()))?;
+        }
+        writeln!(f)
+    }
+}
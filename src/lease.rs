@@ -0,0 +1,187 @@
+//! Leases kept alive by a background heartbeat, used to notice that a process
+//! on the other side of a segment died without cleaning up after itself.
+//!
+//! Heartbeats are milliseconds since the UNIX epoch, so that processes can
+//! compare them. A lease is also considered lost when its PID no longer
+//! exists, which can only be told from the PID namespace the lease was taken
+//! in: leases held from other namespaces, e.g. from another container, are
+//! only lost once their heartbeat expires.
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use zenoh::{Session, Wait, pubsub::Subscriber, sample::SampleKind};
+
+use crate::Result;
+use crate::deadline::CancellationToken;
+use crate::segment::Segment;
+
+/// How long a process may go without a heartbeat before being deemed dead.
+pub const LEASE: Duration = Duration::from_secs(5);
+
+/// How often heartbeats are refreshed.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often a consumer blocked on an event checks that the producer is alive.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Producer states
+pub const OPEN: u32 = 0;
+pub const CLOSED: u32 = 1;
+
+/// Liveness of the producer of a segment, stored in its header.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct ProducerLease {
+    pub state: AtomicU32,
+    pub pid: AtomicU32,
+    pub pid_ns: AtomicU64, // PID namespace of `pid`, 0 if unknown
+    pub heartbeat: AtomicU64,
+    /// Start time of the producer in nanoseconds since the UNIX epoch, which
+    /// tells a restarted producer from the one it replaces.
+    pub epoch: AtomicU64,
+}
+
+impl ProducerLease {
+    /// A lease held by the calling process.
+    pub(crate) fn new() -> Self {
+        ProducerLease {
+            state: AtomicU32::new(OPEN),
+            pid: AtomicU32::new(std::process::id()),
+            pid_ns: AtomicU64::new(pid_namespace()),
+            heartbeat: AtomicU64::new(now_millis()),
+            epoch: AtomicU64::new(now_nanos()),
+        }
+    }
+
+    pub(crate) fn beat(&self) {
+        self.heartbeat.store(now_millis(), Ordering::Release);
+    }
+
+    /// Marks the end of the stream.
+    pub(crate) fn close(&self) {
+        self.state.store(CLOSED, Ordering::Release);
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::Acquire)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == CLOSED
+    }
+
+    /// Whether the producer closed the segment, died or stopped beating.
+    /// A PID of 0 stands for several producer processes, e.g. of
+    /// [`crate::mpmc`], which only the heartbeat tells are gone.
+    pub fn is_gone(&self) -> bool {
+        let pid = self.pid.load(Ordering::Acquire);
+        self.is_closed()
+            || expired(self.heartbeat.load(Ordering::Acquire))
+            || (pid != 0 && process_gone(pid, self.pid_ns.load(Ordering::Acquire)))
+    }
+}
+
+/// Calls a function on a segment every [`HEARTBEAT_INTERVAL`] from a
+/// background thread, until dropped.
+pub(crate) struct Heartbeat {
+    _stop: mpsc::Sender<()>,
+}
+
+impl Heartbeat {
+    /// Starts beating on `shared`. The thread also stops once `beat` returns
+    /// `false`, e.g. because the lease was taken away.
+    pub(crate) fn start<T, F>(shared: Segment<T>, beat: F) -> Self
+    where
+        T: Sync + 'static,
+        F: Fn(&T) -> bool + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel::<()>();
+        std::thread::spawn(move || {
+            // Any message or the sender being dropped stops the thread
+            while let Err(mpsc::RecvTimeoutError::Timeout) =
+                stopped.recv_timeout(HEARTBEAT_INTERVAL)
+            {
+                if !beat(&shared) {
+                    break;
+                }
+            }
+        });
+        Heartbeat { _stop: stop }
+    }
+}
+
+/// Cancels `token` once the producer serving `key_expr` loses its Zenoh
+/// liveliness token, e.g. because its session closed or its host became
+/// unreachable. This complements the heartbeat in the segment, which only
+/// notices a dead producer after [`LEASE`]. The watch lasts as long as the
+/// returned subscriber.
+pub fn cancel_on_producer_loss(
+    session: &Session,
+    key_expr: &str,
+    token: CancellationToken,
+) -> Result<Subscriber<()>> {
+    let key = key_expr.to_string();
+    let subscriber = session
+        .liveliness()
+        .declare_subscriber(key_expr)
+        .callback(move |sample| {
+            if sample.kind() == SampleKind::Delete {
+                log::warn!("Producer on '{key}' lost its liveliness token");
+                token.cancel();
+            }
+        })
+        .wait()?;
+    Ok(subscriber)
+}
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+pub(crate) fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// Whether a heartbeat is older than [`LEASE`].
+pub(crate) fn expired(heartbeat: u64) -> bool {
+    now_millis().saturating_sub(heartbeat) > LEASE.as_millis() as u64
+}
+
+/// Whether process `pid`, of PID namespace `pid_ns`, is known to be gone.
+/// PIDs of another namespace than ours cannot be looked up, so this only
+/// tells about those of ours.
+pub(crate) fn process_gone(pid: u32, pid_ns: u64) -> bool {
+    pid_ns != 0 && pid_ns == pid_namespace() && !process_alive(pid)
+}
+
+/// Identifies the PID namespace of the calling process, 0 where unknown.
+#[cfg(target_os = "linux")]
+pub(crate) fn pid_namespace() -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    use std::sync::OnceLock;
+
+    static PID_NS: OnceLock<u64> = OnceLock::new();
+    *PID_NS.get_or_init(|| std::fs::metadata("/proc/self/ns/pid").map_or(0, |m| m.ino()))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn pid_namespace() -> u64 {
+    0
+}
+
+#[cfg(target_os = "linux")]
+fn process_alive(pid: u32) -> bool {
+    std::path::Path::new(&format!("/proc/{pid}")).exists()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_alive(_pid: u32) -> bool {
+    // Rely on the heartbeat alone
+    true
+}
@@ -0,0 +1,91 @@
+//! Shared-memory producer/consumer channels built on top of Zenoh SHM.
+//!
+//! Every channel follows the same bootstrap: the producer allocates a single
+//! segment through [`zenoh::shm::ShmProviderBuilder`], lays out a `#[repr(C)]`
+//! shared structure in it and answers queries on a key expression with the
+//! (shallow copied) buffer. Consumers issue a `get` on that key, map the reply
+//! and from then on synchronize with the producer only through atomics stored
+//! in the segment. Each segment starts with a [`header::Header`] that consumers
+//! validate before attaching, and which records the payload capacity of the
+//! slots chosen by the producer.
+//!
+//! - [`polling`]: 1:1 single-slot mailbox, both sides poll.
+//! - [`polling_1n`]: 1:N single-slot mailbox with a read credit per consumer.
+//! - [`await_1n`]: same as [`polling_1n`] but blocking on shared events, or on
+//!   eventfds that can be polled with other descriptors (Linux only).
+//! - [`spsc`]: 1:1 bounded ring, the producer runs ahead by up to N samples.
+//! - [`broadcast`]: 1:N bounded ring with a read cursor per consumer, slow
+//!   consumers either hold the producer back or miss samples.
+//! - [`mpmc`]: bounded queue shared by several producer and consumer
+//!   processes, each sample is taken by exactly one consumer.
+//! - [`seqlock`]: 1:N latest value, the producer overwrites it without ever
+//!   waiting and readers retry torn reads.
+//!
+//! The rings block on [`notify::Event`]s living in the segment rather than
+//! sleeping between polls. Every blocking operation takes a [`Deadline`], so
+//! that waits can time out or be cancelled through a [`CancellationToken`].
+//!
+//! Producers keep a [`lease::ProducerLease`] in the header alive and mark it
+//! closed when dropped; consumers then drain what is left and get
+//! [`Error::Disconnected`]. The 1:N mailboxes track their consumers in a
+//! [`consumers`] table with PIDs and heartbeats, so that consumers that die
+//! without detaching do not block the producer forever. Each producer also
+//! records an epoch in its lease, from which [`reattach`] tells a restarted
+//! producer apart and moves its consumers over to the new segment.
+//!
+//! Consumers may attach to a known key expression or look channels up with
+//! [`discovery`], which lists the producers under a key expression with
+//! wildcards along with the layout read from each segment header.
+//! [`inspect`] reads the whole state of such segments, for debugging,
+//! without registering as a consumer.
+//!
+//! Consumers that cannot map the segment, e.g. on another host, can fall
+//! back to Zenoh pub/sub through [`remote`], whose producer wrapper copies
+//! each sample to the network only while such consumers subscribe.
+//!
+//! Every channel counts the samples published, read and skipped and the time
+//! spent waiting in shared memory, and its producer serves a snapshot of
+//! these [`stats`] on `<key>/@stats`.
+//!
+//! With the `tokio` feature, the channels that block on events also offer
+//! async versions of their constructors, `attach` and blocking operations,
+//! which wait on tokio's blocking pool instead of an executor thread.
+//!
+//! All channels but [`mpmc`] assume a single writer: their producers refuse
+//! to start if another producer already serves the same key expression.
+
+pub mod arena;
+#[cfg(feature = "tokio")]
+mod bridge;
+pub mod broadcast;
+mod cache_padded;
+pub mod cli;
+pub mod consumers;
+pub mod deadline;
+pub mod discovery;
+pub mod error;
+pub mod header;
+pub mod inspect;
+pub mod lease;
+pub mod mpmc;
+pub mod notify;
+pub mod pod;
+pub mod polling;
+pub mod polling_1n;
+pub mod reattach;
+pub mod remote;
+mod segment;
+pub mod seqlock;
+pub mod spsc;
+pub mod stats;
+
+#[cfg(target_os = "linux")]
+pub mod await_1n;
+#[cfg(target_os = "linux")]
+pub mod eventfd;
+
+pub use cache_padded::CachePadded;
+pub use deadline::{CancellationToken, Deadline};
+pub use error::{Error, Result};
+pub use header::{LayoutError, MessageType, Protocol};
+pub use pod::ShmPod;
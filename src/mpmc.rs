@@ -0,0 +1,567 @@
+//! Bounded multi-producer/multi-consumer queue.
+//!
+//! Producers and consumers from any number of processes reserve positions
+//! with a CAS on `head` (resp. `tail`) and then synchronize on the `turn` of
+//! the slot holding that position: for lap `l = position / slot_count`, the
+//! slot is free for writing when `turn == 2 * l` and holds a sample when
+//! `turn == 2 * l + 1`. The all-zero segment is thus an empty queue.
+//!
+//! The stream ends when the last of the producers attached leaves, rather
+//! than the one that created the queue. Once several processes produce, the
+//! lease in the header no longer designates one of them: it is kept alive by
+//! the heartbeat of every producer, and only expires once they all stopped.
+//! New consumers can only attach while the creating producer serves the
+//! queue.
+//!
+//! Every sample is delivered to exactly one consumer. A process that dies
+//! between reserving a position and releasing its slot stalls the queue at
+//! that position. A producer whose sample does not fit in the slot still
+//! releases its position, with a length of [`ABANDONED`] that consumers skip.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use zenoh::Session;
+use zenoh::bytes::ZBytes;
+
+#[cfg(feature = "tokio")]
+use crate::bridge;
+use crate::cache_padded::CachePadded;
+use crate::deadline::Deadline;
+use crate::discovery::Channel;
+use crate::header::{Header, Layout, LayoutError, MessageType, Protocol};
+use crate::lease::{CHECK_INTERVAL, Heartbeat};
+use crate::notify::Event;
+use crate::pod::{self, RawConsumer, RawProducer, ShmPod};
+use crate::segment::{self, Segment, Served, check_len};
+use crate::stats::{Report, Stopwatch};
+use crate::{Error, Result};
+
+/// Length of a slot whose producer failed to write it.
+pub const ABANDONED: u64 = u64::MAX;
+
+#[repr(C)]
+pub struct SlotHeader {
+    pub turn: AtomicU64,
+    pub len: AtomicU64,
+}
+
+// Shared data, followed by `slot_count` slots of `slot_stride()` bytes
+#[repr(C)]
+pub struct SharedData {
+    pub header: Header,
+    pub slot_count: u64,
+    pub producers: AtomicU64, // Producers attached, the creating one included
+    pub head: CachePadded<AtomicU64>, // Next position to reserve for writing
+    pub tail: CachePadded<AtomicU64>, // Next position to reserve for reading
+    pub not_empty: Event,     // Notified when a sample is published
+    pub not_full: Event,      // Notified when a slot is freed
+}
+
+impl SharedData {
+    /// Distance between two consecutive slots, padded to a cache line.
+    pub fn slot_stride(&self) -> usize {
+        slot_stride(self.header.capacity as usize)
+    }
+}
+
+/// Saturates rather than overflowing on the capacity read from a header.
+fn slot_stride(slot_size: usize) -> usize {
+    std::mem::size_of::<SlotHeader>()
+        .saturating_add(slot_size)
+        .checked_next_multiple_of(64)
+        .unwrap_or(usize::MAX)
+}
+
+unsafe impl Layout for SharedData {
+    const PROTOCOL: Protocol = Protocol::Mpmc;
+    const VERSION: u32 = 10;
+
+    fn tail_len(&self) -> usize {
+        (self.slot_count as usize).saturating_mul(self.slot_stride())
+    }
+
+    fn slot_count(&self) -> usize {
+        self.slot_count as usize
+    }
+}
+
+impl Report for SharedData {}
+
+/// Header and payload of the slot holding `position`.
+pub(crate) fn slot(shared: &Segment<SharedData>, position: u64) -> (&SlotHeader, *mut u8) {
+    let index = (position % shared.slot_count) as usize;
+    // SAFETY: `index < slot_count`, so the slot lies within the tail, and
+    // slots are 64-byte aligned.
+    unsafe {
+        let base = shared.tail().add(index * shared.slot_stride());
+        (
+            &*(base as *const SlotHeader),
+            base.add(std::mem::size_of::<SlotHeader>()),
+        )
+    }
+}
+
+fn allocate(
+    slot_count: usize,
+    slot_size: usize,
+    message: MessageType,
+) -> Result<Segment<SharedData>> {
+    if slot_count == 0 {
+        return Err(Error::Alloc("a queue needs at least one slot".to_string()));
+    }
+    let tail = slot_count
+        .checked_mul(slot_stride(slot_size))
+        .ok_or_else(|| {
+            Error::Alloc(format!(
+                "{slot_count} slots of {slot_size} bytes are too large"
+            ))
+        })?;
+    Segment::<SharedData>::create_with(slot_size, tail, |shared| {
+        shared.header.message = message;
+        shared.slot_count = slot_count as u64;
+        shared.producers = AtomicU64::new(1);
+    })
+}
+
+fn open(payload: ZBytes) -> Result<Segment<SharedData>> {
+    let shared = Segment::<SharedData>::attach(payload)?;
+    if shared.slot_count == 0 {
+        return Err(LayoutError::Field {
+            name: "slot_count",
+            value: 0,
+        }
+        .into());
+    }
+    Ok(shared)
+}
+
+pub struct Producer {
+    shared: Segment<SharedData>,
+    _served: Option<Served>, // Held by the producer that created the queue
+    _heartbeat: Option<Heartbeat>, // Run by the producers that attached
+}
+
+impl Producer {
+    /// Allocates a queue of `slot_count` slots holding up to `slot_size`
+    /// bytes each and serves it on `key_expr`, so that other producers and
+    /// consumers can attach to it.
+    pub fn new(
+        session: &Session,
+        key_expr: &str,
+        slot_count: usize,
+        slot_size: usize,
+    ) -> Result<Self> {
+        Self::with_message(session, key_expr, slot_count, slot_size, MessageType::BYTES)
+    }
+
+    /// Allocates a queue of `slot_count` slots holding one `M` message each
+    /// and serves it on `key_expr`.
+    pub fn typed<M: ShmPod>(
+        session: &Session,
+        key_expr: &str,
+        slot_count: usize,
+    ) -> Result<pod::Producer<Self, M>> {
+        let producer = Self::with_message(
+            session,
+            key_expr,
+            slot_count,
+            std::mem::size_of::<M>(),
+            MessageType::of::<M>(),
+        )?;
+        pod::Producer::new(producer)
+    }
+
+    /// Same as [`Producer::new`], but records the type of the messages
+    /// carried in the header, see [`crate::pod`] and [`crate::arena`].
+    pub fn with_message(
+        session: &Session,
+        key_expr: &str,
+        slot_count: usize,
+        slot_size: usize,
+        message: MessageType,
+    ) -> Result<Self> {
+        let shared = allocate(slot_count, slot_size, message)?;
+        let served = segment::serve(session, key_expr, &shared)?;
+        Ok(Producer {
+            shared,
+            _served: Some(served),
+            _heartbeat: None,
+        })
+    }
+
+    /// Fetches the queue served on `key_expr` to publish into it alongside
+    /// the producer that created it.
+    pub fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        Ok(Self::join(open(segment::fetch(session, key_expr)?)?))
+    }
+
+    /// Same as [`Producer::attach`], for a channel found by
+    /// [`crate::discovery`].
+    pub fn from_channel(channel: &Channel) -> Result<Self> {
+        Ok(Self::join(open(channel.payload().clone())?))
+    }
+
+    /// Counts a producer in, and keeps the lease alive on its behalf.
+    fn join(shared: Segment<SharedData>) -> Self {
+        shared.producers.fetch_add(1, Ordering::AcqRel);
+        // The lease is shared by several processes from now on, none of
+        // which tells whether the others are alive
+        shared.header.producer.pid.store(0, Ordering::Release);
+        let heartbeat = Heartbeat::start(shared.clone(), |shared: &SharedData| {
+            shared.header.producer.beat();
+            true
+        });
+        Producer {
+            shared,
+            _served: None,
+            _heartbeat: Some(heartbeat),
+        }
+    }
+
+    /// Payload capacity of each slot.
+    pub fn slot_size(&self) -> usize {
+        self.shared.header.capacity as usize
+    }
+
+    /// Reserves the next position and writes a sample into it if the queue
+    /// is not full. `f` fills the slot and returns the number of bytes
+    /// written. Returns the sequence number of the new sample, or `None` if
+    /// the queue is full.
+    pub fn try_publish_with<F>(&mut self, f: F) -> Result<Option<u64>>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        match self.reserve() {
+            Some(position) => self.fill(position, f).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Waits for a free slot and copies `data` into it. Fails with
+    /// [`Error::TooLarge`] if `data` exceeds the slot size.
+    pub fn publish(&mut self, deadline: &Deadline, data: &[u8]) -> Result<u64> {
+        check_len(data.len(), self.slot_size())?;
+        self.publish_with(deadline, |buf| {
+            buf[..data.len()].copy_from_slice(data);
+            data.len()
+        })
+    }
+
+    /// Waits for a free slot and writes a sample into it. Fails with
+    /// [`Error::TooLarge`], abandoning the position, if `f` returns more than
+    /// the slot size.
+    pub fn publish_with<F>(&mut self, deadline: &Deadline, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        let mut blocked = Stopwatch::new();
+        loop {
+            let seen = self.shared.not_full.generation();
+            if let Some(position) = self.reserve() {
+                self.shared.header.stats.blocked(&blocked);
+                return self.fill(position, f);
+            }
+            blocked.start();
+            deadline.wait(&self.shared.not_full, seen)?;
+        }
+    }
+
+    fn reserve(&self) -> Option<u64> {
+        let head = &self.shared.head;
+        let mut position = head.load(Ordering::Acquire);
+        loop {
+            let lap = position / self.shared.slot_count;
+            let turn = slot(&self.shared, position).0.turn.load(Ordering::Acquire);
+            if turn == 2 * lap {
+                match head.compare_exchange_weak(
+                    position,
+                    position + 1,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => return Some(position),
+                    Err(current) => position = current,
+                }
+            } else {
+                let previous = position;
+                position = head.load(Ordering::Acquire);
+                if position == previous {
+                    // The slot still holds a sample from the previous lap
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn fill<F>(&mut self, position: u64, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        let slot_size = self.slot_size();
+        let lap = position / self.shared.slot_count;
+        let (header, data) = slot(&self.shared, position);
+        // SAFETY: we own the slot until we bump its turn.
+        let written = f(unsafe { std::slice::from_raw_parts_mut(data, slot_size) });
+        let result = check_len(written, slot_size);
+        match result {
+            Ok(()) => {
+                header.len.store(written as u64, Ordering::Relaxed);
+                self.shared.header.stats.published();
+            }
+            // Consumers cannot reserve past the position, hand it over anyway
+            Err(_) => header.len.store(ABANDONED, Ordering::Relaxed),
+        }
+        header.turn.store(2 * lap + 1, Ordering::Release);
+        self.shared.not_empty.notify_one();
+        result.map(|()| position + 1)
+    }
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        // The stream ends with the last producer
+        if self.shared.producers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.header.producer.close();
+            self.shared.not_empty.notify_all();
+        }
+    }
+}
+
+pub struct Consumer {
+    shared: Segment<SharedData>,
+}
+
+impl Consumer {
+    /// Fetches the queue served on `key_expr`.
+    pub fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        Ok(Consumer {
+            shared: open(segment::fetch(session, key_expr)?)?,
+        })
+    }
+
+    /// Attaches to a channel found by [`crate::discovery`].
+    pub fn from_channel(channel: &Channel) -> Result<Self> {
+        Ok(Consumer {
+            shared: open(channel.payload().clone())?,
+        })
+    }
+
+    /// Whether the producer that created the queue is still alive and has not closed the channel.
+    pub fn is_connected(&self) -> bool {
+        !self.shared.header.producer.is_gone()
+    }
+
+    /// Payload capacity of each slot.
+    pub fn slot_size(&self) -> usize {
+        self.shared.header.capacity as usize
+    }
+
+    /// Takes the oldest sample not yet taken by any consumer and hands it and
+    /// its sequence number to `f`. Returns `None` if the queue is empty.
+    pub fn try_recv_with<F, R>(&mut self, f: F) -> Option<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let position = self.reserve()?;
+        Some(self.consume(position, f))
+    }
+
+    /// Waits for a sample and hands it to `f`. Fails with
+    /// [`Error::Disconnected`] once the producer is gone and the queue is empty.
+    pub fn recv_with<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let mut waited = Stopwatch::new();
+        loop {
+            let seen = self.shared.not_empty.generation();
+            let gone = self.shared.header.producer.is_gone();
+            if let Some(position) = self.reserve() {
+                self.shared.header.stats.waited(&waited);
+                return Ok(self.consume(position, f));
+            }
+            if gone {
+                return Err(Error::Disconnected);
+            }
+            waited.start();
+            deadline.wait_at_most(&self.shared.not_empty, seen, CHECK_INTERVAL)?;
+        }
+    }
+
+    fn reserve(&self) -> Option<u64> {
+        let tail = &self.shared.tail;
+        let mut position = tail.load(Ordering::Acquire);
+        loop {
+            let lap = position / self.shared.slot_count;
+            let turn = slot(&self.shared, position).0.turn.load(Ordering::Acquire);
+            if turn == 2 * lap + 1 {
+                match tail.compare_exchange_weak(
+                    position,
+                    position + 1,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => {
+                        let header = slot(&self.shared, position).0;
+                        if header.len.load(Ordering::Relaxed) != ABANDONED {
+                            return Some(position);
+                        }
+                        // Releases the slot and moves on to the next position
+                        header.turn.store(2 * lap + 2, Ordering::Release);
+                        self.shared.not_full.notify_one();
+                        position += 1;
+                    }
+                    Err(current) => position = current,
+                }
+            } else {
+                let previous = position;
+                position = tail.load(Ordering::Acquire);
+                if position == previous {
+                    // Nothing written at this position yet
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn consume<F, R>(&mut self, position: u64, f: F) -> R
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let lap = position / self.shared.slot_count;
+        let (header, data) = slot(&self.shared, position);
+        // SAFETY: we own the slot until we bump its turn. The length is
+        // clamped in case a producer misbehaves.
+        let r = unsafe {
+            let len = (header.len.load(Ordering::Relaxed) as usize).min(self.slot_size());
+            f(position + 1, std::slice::from_raw_parts(data, len))
+        };
+        self.shared.header.stats.read();
+        header.turn.store(2 * lap + 2, Ordering::Release);
+        self.shared.not_full.notify_one();
+        r
+    }
+}
+
+impl RawProducer for Producer {
+    type Published = u64;
+
+    fn header(&self) -> &Header {
+        &self.shared.header
+    }
+
+    fn publish_raw<F>(&mut self, deadline: &Deadline, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        self.publish_with(deadline, f)
+    }
+}
+
+impl RawConsumer for Consumer {
+    type Shared = SharedData;
+
+    fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        Consumer::attach(session, key_expr)
+    }
+
+    fn open(payload: ZBytes) -> Result<Self> {
+        Ok(Consumer {
+            shared: open(payload)?,
+        })
+    }
+
+    fn header(&self) -> &Header {
+        &self.shared.header
+    }
+
+    fn recv_raw<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        self.recv_with(deadline, f)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Producer {
+    /// Async version of [`Producer::new`].
+    pub async fn new_async(
+        session: &Session,
+        key_expr: &str,
+        slot_count: usize,
+        slot_size: usize,
+    ) -> Result<Self> {
+        Self::with_message_async(session, key_expr, slot_count, slot_size, MessageType::BYTES).await
+    }
+
+    /// Async version of [`Producer::with_message`].
+    pub async fn with_message_async(
+        session: &Session,
+        key_expr: &str,
+        slot_count: usize,
+        slot_size: usize,
+        message: MessageType,
+    ) -> Result<Self> {
+        let shared = allocate(slot_count, slot_size, message)?;
+        let served = bridge::serve(session, key_expr, &shared).await?;
+        Ok(Producer {
+            shared,
+            _served: Some(served),
+            _heartbeat: None,
+        })
+    }
+
+    /// Async version of [`Producer::attach`].
+    pub async fn attach_async(session: &Session, key_expr: &str) -> Result<Self> {
+        Ok(Self::join(open(bridge::fetch(session, key_expr).await?)?))
+    }
+
+    /// Async version of [`Producer::publish_with`].
+    pub async fn publish_async<F>(&mut self, deadline: &Deadline, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        let mut blocked = Stopwatch::new();
+        loop {
+            let seen = self.shared.not_full.generation();
+            if let Some(position) = self.reserve() {
+                self.shared.header.stats.blocked(&blocked);
+                return self.fill(position, f);
+            }
+            blocked.start();
+            bridge::wait(&self.shared, |s| &s.not_full, seen, deadline).await?;
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Consumer {
+    /// Async version of [`Consumer::attach`].
+    pub async fn attach_async(session: &Session, key_expr: &str) -> Result<Self> {
+        Ok(Consumer {
+            shared: open(bridge::fetch(session, key_expr).await?)?,
+        })
+    }
+
+    /// Async version of [`Consumer::recv_with`].
+    pub async fn recv_async<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let mut waited = Stopwatch::new();
+        loop {
+            let seen = self.shared.not_empty.generation();
+            let gone = self.shared.header.producer.is_gone();
+            if let Some(position) = self.reserve() {
+                self.shared.header.stats.waited(&waited);
+                return Ok(self.consume(position, f));
+            }
+            if gone {
+                return Err(Error::Disconnected);
+            }
+            waited.start();
+            bridge::wait(&self.shared, |s| &s.not_empty, seen, deadline).await?;
+        }
+    }
+}
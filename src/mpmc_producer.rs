@@ -0,0 +1,54 @@
+use rand::random;
+use zshm::cli::{Args, Defaults};
+use zshm::mpmc::Producer;
+use zshm::{Deadline, Error};
+
+const SLOT_COUNT: usize = 16;
+
+fn main() {
+    let args = Args::parse(
+        "MPMC queue producer",
+        Defaults {
+            key_expr: "shm/mpmc/buffer",
+            rate: 10.0,
+            ..Default::default()
+        },
+    );
+
+    let z = args.open().expect("Failed to open Zenoh session");
+
+    // Join the queue if another producer already created it
+    let mut producer = match Producer::attach(&z, &args.key_expr) {
+        Ok(producer) => {
+            println!("Joined existing queue");
+            producer
+        }
+        Err(Error::NoProducer(_)) => Producer::new(&z, &args.key_expr, SLOT_COUNT, args.size)
+            .expect("Failed to create producer"),
+        Err(e) => panic!("Failed to attach: {e}"),
+    };
+
+    // producer loop
+    for n in 1.. {
+        let mut sum: usize = 0;
+        let mut len = 0;
+        let sn = producer
+            .publish_with(&Deadline::never(), |data| {
+                // The queue may have been created with smaller slots
+                len = args.payload_len().min(data.len());
+                for b in &mut data[..len] {
+                    *b = random();
+                    sum += *b as usize;
+                }
+                len
+            })
+            .expect("Failed to publish");
+        println!("{sn} - Produced buffer of {len} bytes with sum of {sum}");
+        if args.done(n) {
+            break;
+        }
+        if let Some(period) = args.period() {
+            std::thread::sleep(period);
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Cross-process wakeups for waiters living in a shared segment.
+//!
+//! An [`Event`] is a generation counter: notifying bumps it, waiting blocks
+//! while it still holds the value observed before checking the condition.
+//! A notification sent between that check and the wait is therefore never
+//! lost, and unlike a 0/1 flag, a reset does not hide a notification from a
+//! waiter that had not woken up yet.
+//!
+//! ```ignore
+//! loop {
+//!     let seen = event.generation();
+//!     if condition() {
+//!         break;
+//!     }
+//!     event.wait(seen);
+//! }
+//! ```
+//!
+//! On Linux waiting uses a process-shared futex. Elsewhere the layout is the
+//! same but waiters poll the counter.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "linux")]
+use linux_futex::{Futex, Shared, TimedWaitError};
+
+#[repr(C)]
+pub struct Event {
+    #[cfg(target_os = "linux")]
+    generation: Futex<Shared>,
+    #[cfg(not(target_os = "linux"))]
+    generation: AtomicU32,
+    waiters: AtomicU32, // Lets notifiers skip the syscall when nobody waits
+}
+
+#[cfg(not(target_os = "linux"))]
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+impl Default for Event {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Event {
+    pub const fn new() -> Self {
+        Event {
+            #[cfg(target_os = "linux")]
+            generation: Futex::new(0),
+            #[cfg(not(target_os = "linux"))]
+            generation: AtomicU32::new(0),
+            waiters: AtomicU32::new(0),
+        }
+    }
+
+    fn counter(&self) -> &AtomicU32 {
+        #[cfg(target_os = "linux")]
+        return &self.generation.value;
+        #[cfg(not(target_os = "linux"))]
+        return &self.generation;
+    }
+
+    /// Current generation, to be read before checking the awaited condition.
+    pub fn generation(&self) -> u32 {
+        self.counter().load(Ordering::SeqCst)
+    }
+
+    /// Blocks until the generation differs from `seen`.
+    pub fn wait(&self, seen: u32) {
+        self.wait_until(seen, None);
+    }
+
+    /// Blocks until the generation differs from `seen` or `timeout` elapses.
+    /// Returns `false` on timeout.
+    pub fn wait_timeout(&self, seen: u32, timeout: Duration) -> bool {
+        self.wait_until(seen, Some(Instant::now() + timeout))
+    }
+
+    /// Blocks until the generation differs from `seen` or `deadline` passes.
+    /// Returns `false` on timeout.
+    pub fn wait_until(&self, seen: u32, deadline: Option<Instant>) -> bool {
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        let notified = self.block(seen, deadline);
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+        notified
+    }
+
+    #[cfg(target_os = "linux")]
+    fn block(&self, seen: u32, deadline: Option<Instant>) -> bool {
+        while self.generation() == seen {
+            match deadline {
+                None => {
+                    let _ = self.generation.wait(seen);
+                }
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    if let Err(TimedWaitError::TimedOut) =
+                        self.generation.wait_for(seen, deadline - now)
+                    {
+                        return self.generation() != seen;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn block(&self, seen: u32, deadline: Option<Instant>) -> bool {
+        while self.generation() == seen {
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                return false;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        true
+    }
+
+    /// Wakes at most one waiter.
+    pub fn notify_one(&self) {
+        self.notify(1);
+    }
+
+    /// Wakes every waiter.
+    pub fn notify_all(&self) {
+        self.notify(i32::MAX);
+    }
+
+    fn notify(&self, _n: i32) {
+        self.counter().fetch_add(1, Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            #[cfg(target_os = "linux")]
+            self.generation.wake(_n);
+        }
+    }
+
+    /// Number of threads currently blocked in a wait.
+    pub fn waiters(&self) -> u32 {
+        self.waiters.load(Ordering::Acquire)
+    }
+}
@@ -0,0 +1,395 @@
+//! Typed zero-copy messages.
+//!
+//! A [`ShmPod`] type is written in place into a slot by the producer and
+//! handed to consumers by reference, without any copy or serialization. The
+//! typed [`Producer`] and [`Consumer`] wrap the byte-level handles of any
+//! channel: the producer records the size, alignment and fingerprint of the
+//! message type in the segment header, and consumers refuse to attach unless
+//! they match the type they expect.
+//!
+//! [`shm_pod!`](crate::shm_pod) implements [`ShmPod`] for a `#[repr(C)]`
+//! struct, checking at compile time that it has no padding:
+//!
+//! ```
+//! zshm::shm_pod! {
+//!     #[derive(Clone, Copy, Debug, Default)]
+//!     pub struct ImuSample {
+//!         pub timestamp: u64,
+//!         pub accel: [f32; 3],
+//!         pub gyro: [f32; 3],
+//!     }
+//! }
+//! ```
+//!
+//! Typed producers are created with the `typed` constructor of each channel,
+//! e.g. [`crate::spsc::Producer::typed`], and consumers with
+//! [`Consumer::attach`]. Messages holding strings or vectors are handled by
+//! [`crate::arena`].
+
+use std::marker::PhantomData;
+
+use zenoh::Session;
+use zenoh::bytes::ZBytes;
+
+use crate::Result;
+use crate::deadline::Deadline;
+use crate::header::{Header, Layout, LayoutError, MessageType};
+
+/// Largest alignment of a message type. Every channel aligns its payloads to
+/// at least 8 bytes.
+pub const MAX_ALIGN: usize = 8;
+
+/// Plain-old-data types that can be shared as is between processes.
+///
+/// # Safety
+/// Implementors must have a stable (`#[repr(C)]` or primitive) layout, no
+/// padding bytes, no pointers or references, and accept every bit pattern,
+/// all zeros included. Use [`shm_pod!`](crate::shm_pod) for structs.
+pub unsafe trait ShmPod: Copy + Send + Sync + 'static {
+    /// Identifies the type across processes. Derived from the names of the
+    /// type and of its fields, and from the fingerprints of the field types.
+    const FINGERPRINT: u64;
+}
+
+macro_rules! impl_primitive {
+    ($($t:ty),*) => {
+        $(
+            unsafe impl ShmPod for $t {
+                const FINGERPRINT: u64 = fnv1a(FNV_OFFSET, stringify!($t).as_bytes());
+            }
+        )*
+    };
+}
+
+impl_primitive!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+unsafe impl<T: ShmPod, const N: usize> ShmPod for [T; N] {
+    const FINGERPRINT: u64 = mix(mix(fnv1a(FNV_OFFSET, b"[]"), T::FINGERPRINT), N as u64);
+}
+
+pub(crate) const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+pub(crate) const fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+        i += 1;
+    }
+    hash
+}
+
+pub(crate) const fn mix(hash: u64, value: u64) -> u64 {
+    fnv1a(hash, &value.to_le_bytes())
+}
+
+/// Fingerprint of a struct named `name` with the given fields and field type
+/// fingerprints, in declaration order. Used by [`shm_pod!`](crate::shm_pod).
+#[doc(hidden)]
+pub const fn fingerprint(name: &str, fields: &[(&str, u64)]) -> u64 {
+    let mut hash = fnv1a(FNV_OFFSET, name.as_bytes());
+    let mut i = 0;
+    while i < fields.len() {
+        hash = mix(fnv1a(hash, fields[i].0.as_bytes()), fields[i].1);
+        i += 1;
+    }
+    hash
+}
+
+/// Declares a `#[repr(C)]` struct and implements [`ShmPod`] for it.
+///
+/// Every field must itself be [`ShmPod`], and the struct must derive `Clone`
+/// and `Copy`. Compilation fails if the fields leave padding bytes: add
+/// explicit padding fields (e.g. `_pad: [u8; 4]`) to fill them.
+#[macro_export]
+macro_rules! shm_pod {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$field_attr:meta])* $field_vis:vis $field:ident : $field_ty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        #[repr(C)]
+        $vis struct $name {
+            $($(#[$field_attr])* $field_vis $field: $field_ty),*
+        }
+
+        // SAFETY: `repr(C)`, made of `ShmPod` fields, and checked below to
+        // have no padding.
+        unsafe impl $crate::pod::ShmPod for $name {
+            const FINGERPRINT: u64 = $crate::pod::fingerprint(
+                stringify!($name),
+                &[$((stringify!($field), <$field_ty as $crate::pod::ShmPod>::FINGERPRINT)),*],
+            );
+        }
+
+        const _: () = assert!(
+            ::core::mem::size_of::<$name>() == 0 $(+ ::core::mem::size_of::<$field_ty>())*,
+            concat!("`", stringify!($name), "` has padding bytes"),
+        );
+    };
+}
+
+/// Byte-level access to the producer side of a channel.
+pub trait RawProducer {
+    /// What a successful publication returns, usually a sequence number.
+    type Published;
+
+    fn header(&self) -> &Header;
+
+    /// Waits for a free slot and lets `f` fill it, see the `publish_with` of
+    /// each channel. Nothing is published if `f` returns more than the slot
+    /// size, which fails with [`crate::Error::TooLarge`].
+    fn publish_raw<F>(&mut self, deadline: &Deadline, f: F) -> Result<Self::Published>
+    where
+        F: FnOnce(&mut [u8]) -> usize;
+}
+
+/// Byte-level access to the consumer side of a channel.
+pub trait RawConsumer: Sized {
+    /// Layout of the segments the consumer attaches to.
+    type Shared: Layout;
+
+    /// Fetches the segment served on `key_expr`, see the `attach` of each
+    /// channel.
+    fn attach(session: &Session, key_expr: &str) -> Result<Self>;
+
+    /// Attaches to a segment fetched already, e.g. by [`crate::remote`].
+    fn open(payload: ZBytes) -> Result<Self>;
+
+    fn header(&self) -> &Header;
+
+    /// Waits for a sample and hands it to `f` along with its sequence number,
+    /// see the `recv_with` of each channel. Mailboxes without sequence
+    /// numbers count the samples received instead.
+    fn recv_raw<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R;
+}
+
+/// Checks that `header` describes a channel of `expected` messages, whose
+/// fixed part is an `M`.
+pub(crate) fn check<M: ShmPod>(header: &Header, expected: MessageType) -> Result<()> {
+    const {
+        assert!(
+            std::mem::size_of::<M>() > 0,
+            "messages cannot be zero-sized"
+        );
+        assert!(
+            std::mem::align_of::<M>() <= MAX_ALIGN,
+            "messages cannot be aligned to more than MAX_ALIGN"
+        );
+    }
+    if header.message != expected {
+        return Err(LayoutError::Message {
+            expected,
+            found: header.message,
+        }
+        .into());
+    }
+    if header.capacity < expected.size {
+        return Err(LayoutError::Field {
+            name: "capacity",
+            value: header.capacity,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// A producer of `M` messages over channel `C`.
+pub struct Producer<C, M> {
+    channel: C,
+    _message: PhantomData<fn(M)>,
+}
+
+impl<C: RawProducer, M: ShmPod> Producer<C, M> {
+    /// Wraps a byte-level producer, after checking that its segment was
+    /// created for `M` messages.
+    pub fn new(channel: C) -> Result<Self> {
+        check::<M>(channel.header(), MessageType::of::<M>())?;
+        Ok(Producer {
+            channel,
+            _message: PhantomData,
+        })
+    }
+
+    /// The byte-level producer.
+    pub fn channel(&self) -> &C {
+        &self.channel
+    }
+
+    /// Waits for a free slot and lets `f` write a message in place. The slot
+    /// still holds whatever was last written to it, or zeros.
+    pub fn publish_with<F>(&mut self, deadline: &Deadline, f: F) -> Result<C::Published>
+    where
+        F: FnOnce(&mut M),
+    {
+        self.channel.publish_raw(deadline, |buf| {
+            debug_assert!(buf.len() >= std::mem::size_of::<M>());
+            debug_assert!(buf.as_ptr().cast::<M>().is_aligned());
+            // SAFETY: `check` ensured that the slot can hold an `M`, payloads
+            // are aligned to `MAX_ALIGN` and any bit pattern is a valid `M`.
+            f(unsafe { &mut *buf.as_mut_ptr().cast::<M>() });
+            std::mem::size_of::<M>()
+        })
+    }
+
+    /// Waits for a free slot and copies `message` into it.
+    pub fn publish(&mut self, deadline: &Deadline, message: &M) -> Result<C::Published> {
+        self.publish_with(deadline, |slot| *slot = *message)
+    }
+}
+
+/// A consumer of `M` messages over channel `C`.
+pub struct Consumer<C, M> {
+    channel: C,
+    _message: PhantomData<fn() -> M>,
+}
+
+impl<C: RawConsumer, M: ShmPod> Consumer<C, M> {
+    /// Fetches the segment served on `key_expr` and checks that it carries
+    /// `M` messages.
+    pub fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        Self::new(C::attach(session, key_expr)?)
+    }
+
+    /// Wraps a byte-level consumer, after checking that its segment carries
+    /// `M` messages.
+    pub fn new(channel: C) -> Result<Self> {
+        check::<M>(channel.header(), MessageType::of::<M>())?;
+        Ok(Consumer {
+            channel,
+            _message: PhantomData,
+        })
+    }
+
+    /// The byte-level consumer.
+    pub fn channel(&self) -> &C {
+        &self.channel
+    }
+
+    /// Waits for a message and hands it to `f` in place.
+    pub fn recv_with<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(&M) -> R,
+    {
+        self.channel.recv_raw(deadline, |_, data| {
+            if data.len() != std::mem::size_of::<M>() {
+                return Err(LayoutError::Field {
+                    name: "len",
+                    value: data.len() as u64,
+                }
+                .into());
+            }
+            debug_assert!(data.as_ptr().cast::<M>().is_aligned());
+            // SAFETY: the sample is exactly an `M`, aligned to `MAX_ALIGN`,
+            // and any bit pattern is a valid `M`.
+            Ok(f(unsafe { &*data.as_ptr().cast::<M>() }))
+        })?
+    }
+
+    /// Waits for a message and copies it out.
+    pub fn recv(&mut self, deadline: &Deadline) -> Result<M> {
+        self.recv_with(deadline, |message| *message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::segment::Segment;
+    use crate::{Error, seqlock};
+
+    crate::shm_pod! {
+        #[derive(Clone, Copy, Debug, Default)]
+        struct Point {
+            x: u32,
+            y: u32,
+        }
+    }
+
+    // Same layout, another field name
+    crate::shm_pod! {
+        #[derive(Clone, Copy, Debug, Default)]
+        struct Renamed {
+            x: u32,
+            z: u32,
+        }
+    }
+
+    // Same layout and fields, another type name
+    crate::shm_pod! {
+        #[derive(Clone, Copy, Debug, Default)]
+        struct Vec2 {
+            x: u32,
+            y: u32,
+        }
+    }
+
+    fn consumer(capacity: usize, message: MessageType) -> seqlock::Consumer {
+        let shared = Segment::<seqlock::SharedData>::local_with(capacity, capacity, |shared| {
+            shared.header.message = message;
+        });
+        seqlock::Consumer::mapped(shared)
+    }
+
+    fn attach<M: ShmPod>(capacity: usize, message: MessageType) -> Result<()> {
+        Consumer::<_, M>::new(consumer(capacity, message)).map(|_| ())
+    }
+
+    #[test]
+    fn fingerprints_tell_types_apart() {
+        assert_ne!(Point::FINGERPRINT, Renamed::FINGERPRINT);
+        assert_ne!(Point::FINGERPRINT, Vec2::FINGERPRINT);
+        assert_ne!(<[u32; 2]>::FINGERPRINT, <[u32; 3]>::FINGERPRINT);
+        assert_ne!(u64::FINGERPRINT, i64::FINGERPRINT);
+    }
+
+    #[test]
+    fn same_type_is_accepted() {
+        assert!(attach::<Point>(8, MessageType::of::<Point>()).is_ok());
+        // Larger slots may carry the message too
+        assert!(attach::<Point>(64, MessageType::of::<Point>()).is_ok());
+    }
+
+    #[test]
+    fn other_types_are_rejected() {
+        let point = MessageType::of::<Point>();
+        let vec2 = MessageType::of::<Vec2>();
+        for (expected, found, result) in [
+            (
+                MessageType::of::<Renamed>(),
+                point,
+                attach::<Renamed>(8, point),
+            ),
+            (vec2, point, attach::<Vec2>(8, point)),
+            (MessageType::of::<u64>(), point, attach::<u64>(8, point)),
+            (point, vec2, attach::<Point>(8, vec2)),
+        ] {
+            let error = LayoutError::Message { expected, found };
+            assert!(
+                matches!(result, Err(Error::Layout(e)) if e == error),
+                "{error}"
+            );
+        }
+        // Byte channels carry no type
+        assert!(matches!(
+            attach::<Point>(8, MessageType::BYTES),
+            Err(Error::Layout(LayoutError::Message { .. }))
+        ));
+    }
+
+    #[test]
+    fn slots_smaller_than_the_type_are_rejected() {
+        assert!(matches!(
+            attach::<Point>(7, MessageType::of::<Point>()),
+            Err(Error::Layout(LayoutError::Field {
+                name: "capacity",
+                value: 7
+            }))
+        ));
+    }
+}
@@ -0,0 +1,270 @@
+//! 1:1 polling channel.
+//!
+//! A single-slot mailbox: the producer fills the `capacity` bytes following
+//! the shared data and publishes `len`, the consumer reads `len` bytes and
+//! hands the slot back by storing `len = 0`.
+//! Both sides poll with a sleep in between. Once the producer is gone, the
+//! consumer gets the last sample, if any, then [`Error::Disconnected`].
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use zenoh::Session;
+use zenoh::bytes::ZBytes;
+
+use crate::Error;
+use crate::Result;
+use crate::deadline::Deadline;
+use crate::discovery::Channel;
+use crate::header::{Header, Layout, MessageType, Protocol};
+use crate::pod::{self, RawConsumer, RawProducer, ShmPod};
+use crate::segment::{self, Segment, Served, check_len};
+use crate::stats::{Report, Stopwatch};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Shared data, followed by `header.capacity` bytes of payload
+#[repr(C)]
+pub struct SharedData {
+    pub header: Header,
+    pub len: AtomicUsize,
+}
+
+unsafe impl Layout for SharedData {
+    const PROTOCOL: Protocol = Protocol::Polling;
+    const VERSION: u32 = 7;
+
+    fn tail_len(&self) -> usize {
+        self.header.capacity as usize
+    }
+}
+
+impl Report for SharedData {}
+
+pub struct Producer {
+    shared: Segment<SharedData>,
+    _served: Served,
+}
+
+impl Producer {
+    /// Allocates a mailbox holding up to `capacity` bytes and serves it on
+    /// `key_expr`.
+    pub fn new(session: &Session, key_expr: &str, capacity: usize) -> Result<Self> {
+        Self::with_message(session, key_expr, capacity, MessageType::BYTES)
+    }
+
+    /// Allocates a mailbox holding one `M` message and serves it on
+    /// `key_expr`.
+    pub fn typed<M: ShmPod>(session: &Session, key_expr: &str) -> Result<pod::Producer<Self, M>> {
+        let producer = Self::with_message(
+            session,
+            key_expr,
+            std::mem::size_of::<M>(),
+            MessageType::of::<M>(),
+        )?;
+        pod::Producer::new(producer)
+    }
+
+    /// Same as [`Producer::new`], but records the type of the messages
+    /// carried in the header, see [`crate::pod`] and [`crate::arena`].
+    pub fn with_message(
+        session: &Session,
+        key_expr: &str,
+        capacity: usize,
+        message: MessageType,
+    ) -> Result<Self> {
+        let shared = Segment::<SharedData>::create_with(capacity, capacity, |shared| {
+            shared.header.message = message;
+        })?;
+        let _served = segment::serve(session, key_expr, &shared)?;
+        Ok(Producer { shared, _served })
+    }
+
+    /// Payload capacity of the mailbox.
+    pub fn capacity(&self) -> usize {
+        self.shared.header.capacity as usize
+    }
+
+    /// Writes a sample if the slot is free. `f` fills the buffer and returns
+    /// the number of bytes written. Returns `false` if the slot is still busy.
+    pub fn try_publish_with<F>(&mut self, f: F) -> Result<bool>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        if self.shared.len.load(Ordering::Acquire) != 0 {
+            return Ok(false);
+        }
+        self.fill(f)?;
+        Ok(true)
+    }
+
+    /// Waits until the slot is free and copies `data` into it. Fails with
+    /// [`Error::TooLarge`] if `data` exceeds the capacity.
+    pub fn publish(&mut self, deadline: &Deadline, data: &[u8]) -> Result<()> {
+        check_len(data.len(), self.capacity())?;
+        self.publish_with(deadline, |buf| {
+            buf[..data.len()].copy_from_slice(data);
+            data.len()
+        })
+    }
+
+    /// Waits until the slot is free and writes a sample into it. Fails with
+    /// [`Error::TooLarge`], leaving the slot free, if `f` returns more than
+    /// the capacity.
+    pub fn publish_with<F>(&mut self, deadline: &Deadline, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        // Wait until the data is consumed
+        let mut blocked = Stopwatch::new();
+        while self.shared.len.load(Ordering::Acquire) != 0 {
+            blocked.start();
+            deadline.sleep(POLL_INTERVAL)?;
+        }
+        self.shared.header.stats.blocked(&blocked);
+        self.fill(f)
+    }
+
+    fn fill<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        let capacity = self.capacity();
+        // SAFETY: the consumer does not touch the payload while `len == 0`.
+        let len = f(unsafe { std::slice::from_raw_parts_mut(self.shared.tail(), capacity) });
+        check_len(len, capacity)?;
+        self.shared.header.stats.published();
+        self.shared.len.store(len, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.shared.header.producer.close();
+    }
+}
+
+pub struct Consumer {
+    shared: Segment<SharedData>,
+    received: u64, // Stands for the sequence numbers the mailbox lacks
+}
+
+impl Consumer {
+    /// Fetches the segment served on `key_expr`.
+    pub fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        Self::open(segment::fetch(session, key_expr)?)
+    }
+
+    /// Attaches to a channel found by [`crate::discovery`].
+    pub fn from_channel(channel: &Channel) -> Result<Self> {
+        Self::open(channel.payload().clone())
+    }
+
+    fn open(payload: ZBytes) -> Result<Self> {
+        Ok(Consumer {
+            shared: Segment::attach(payload)?,
+            received: 0,
+        })
+    }
+
+    /// Payload capacity of the mailbox.
+    pub fn capacity(&self) -> usize {
+        self.shared.header.capacity as usize
+    }
+
+    /// Whether the producer is still alive and has not closed the channel.
+    pub fn is_connected(&self) -> bool {
+        !self.shared.header.producer.is_gone()
+    }
+
+    /// Hands the current sample to `f` and releases the slot once it returns.
+    /// Returns `None` if nothing has been published.
+    pub fn try_recv_with<F, R>(&mut self, f: F) -> Option<R>
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        match self.shared.len.load(Ordering::Acquire) {
+            0 => None,
+            len => Some(self.consume(len, f)),
+        }
+    }
+
+    /// Waits for a sample and hands it to `f`. Fails with
+    /// [`Error::Disconnected`] once the producer is gone and the slot empty.
+    pub fn recv_with<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        // Wait until the data is set
+        let mut waited = Stopwatch::new();
+        let len = loop {
+            // Checked first, so that a last sample published before closing is not lost
+            let gone = self.shared.header.producer.is_gone();
+            match self.shared.len.load(Ordering::Acquire) {
+                0 if gone => return Err(Error::Disconnected),
+                0 => {
+                    waited.start();
+                    deadline.sleep(POLL_INTERVAL)?;
+                }
+                len => break len,
+            }
+        };
+        self.shared.header.stats.waited(&waited);
+        Ok(self.consume(len, f))
+    }
+
+    fn consume<F, R>(&mut self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        // SAFETY: the producer does not touch the payload while `len != 0`.
+        // The length is clamped in case the producer misbehaves.
+        let len = len.min(self.capacity());
+        let r = f(unsafe { std::slice::from_raw_parts(self.shared.tail(), len) });
+        self.shared.header.stats.read();
+        self.shared.len.store(0, Ordering::Release);
+        r
+    }
+}
+
+impl RawProducer for Producer {
+    type Published = ();
+
+    fn header(&self) -> &Header {
+        &self.shared.header
+    }
+
+    fn publish_raw<F>(&mut self, deadline: &Deadline, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        self.publish_with(deadline, f)
+    }
+}
+
+impl RawConsumer for Consumer {
+    type Shared = SharedData;
+
+    fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        Consumer::attach(session, key_expr)
+    }
+
+    fn open(payload: ZBytes) -> Result<Self> {
+        Consumer::open(payload)
+    }
+
+    fn header(&self) -> &Header {
+        &self.shared.header
+    }
+
+    fn recv_raw<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let sn = self.received + 1;
+        let r = self.recv_with(deadline, |data| f(sn, data))?;
+        self.received = sn;
+        Ok(r)
+    }
+}
@@ -0,0 +1,310 @@
+//! 1:N polling channel.
+//!
+//! A single-slot mailbox read by every consumer. When publishing, the
+//! producer gives a read credit to every consumer registered in the
+//! [`Consumers`] table; the consumer returning the last credit hands the slot
+//! back by storing `len = 0`. Credits of consumers that died are returned by
+//! the producer, see [`crate::consumers`]. Once the producer is gone,
+//! consumers get the last sample, if any, then [`Error::Disconnected`].
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use zenoh::Session;
+use zenoh::bytes::ZBytes;
+
+use crate::Error;
+use crate::Result;
+use crate::consumers::{Consumers, Membership};
+use crate::deadline::Deadline;
+use crate::discovery::Channel;
+use crate::header::{Header, Layout, MessageType, Protocol};
+use crate::lease::Heartbeat;
+use crate::pod::{self, RawConsumer, RawProducer, ShmPod};
+use crate::segment::{self, Segment, Served, check_len};
+use crate::stats::{ConsumerCounters, ConsumerStats, Report, Stopwatch};
+
+const PRODUCER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const CONSUMER_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Shared data, followed by `header.capacity` bytes of payload
+#[repr(C)]
+pub struct SharedData {
+    pub header: Header,
+    pub len: AtomicUsize,
+    pub sn: AtomicU64,
+    pub consumers: Consumers,
+}
+
+unsafe impl Layout for SharedData {
+    const PROTOCOL: Protocol = Protocol::Polling1N;
+    const VERSION: u32 = 10;
+
+    fn tail_len(&self) -> usize {
+        self.header.capacity as usize
+    }
+}
+
+impl Report for SharedData {
+    fn consumers(shared: &Segment<Self>) -> Vec<ConsumerStats> {
+        shared.consumers.stats()
+    }
+}
+
+pub struct Producer {
+    shared: Segment<SharedData>,
+    _served: Served,
+}
+
+impl Producer {
+    /// Allocates a mailbox holding up to `capacity` bytes and serves it on
+    /// `key_expr`.
+    pub fn new(session: &Session, key_expr: &str, capacity: usize) -> Result<Self> {
+        Self::with_message(session, key_expr, capacity, MessageType::BYTES)
+    }
+
+    /// Allocates a mailbox holding one `M` message and serves it on
+    /// `key_expr`.
+    pub fn typed<M: ShmPod>(session: &Session, key_expr: &str) -> Result<pod::Producer<Self, M>> {
+        let producer = Self::with_message(
+            session,
+            key_expr,
+            std::mem::size_of::<M>(),
+            MessageType::of::<M>(),
+        )?;
+        pod::Producer::new(producer)
+    }
+
+    /// Same as [`Producer::new`], but records the type of the messages
+    /// carried in the header, see [`crate::pod`] and [`crate::arena`].
+    pub fn with_message(
+        session: &Session,
+        key_expr: &str,
+        capacity: usize,
+        message: MessageType,
+    ) -> Result<Self> {
+        let shared = Segment::<SharedData>::create_with(capacity, capacity, |shared| {
+            shared.header.message = message;
+        })?;
+        let _served = segment::serve(session, key_expr, &shared)?;
+        Ok(Producer { shared, _served })
+    }
+
+    /// Payload capacity of the mailbox.
+    pub fn capacity(&self) -> usize {
+        self.shared.header.capacity as usize
+    }
+
+    /// Number of currently registered consumers.
+    pub fn subscribers(&self) -> usize {
+        self.shared.consumers.active()
+    }
+
+    /// Copies `data` into the mailbox once it is free, see
+    /// [`Producer::publish_with`]. Fails with [`Error::TooLarge`] if `data`
+    /// exceeds the capacity.
+    pub fn publish(&mut self, deadline: &Deadline, data: &[u8]) -> Result<u64> {
+        check_len(data.len(), self.capacity())?;
+        self.publish_with(deadline, |buf| {
+            buf[..data.len()].copy_from_slice(data);
+            data.len()
+        })
+    }
+
+    /// Waits until at least one consumer is registered and the previous
+    /// sample has been read by all of them, then writes a sample. `f` fills
+    /// the buffer and returns the number of bytes written. Returns the
+    /// sequence number of the new sample, or fails with [`Error::TooLarge`],
+    /// publishing nothing, if `f` returns more than the capacity.
+    pub fn publish_with<F>(&mut self, deadline: &Deadline, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        let mut blocked = Stopwatch::new();
+        loop {
+            if self.shared.consumers.reap() {
+                // A dead consumer held the last credit
+                self.shared.len.store(0, Ordering::Release);
+            }
+            // Wait until the subscriber is ready, then until the data is consumed
+            if self.shared.consumers.active() != 0 && self.shared.len.load(Ordering::Acquire) == 0 {
+                break;
+            }
+            blocked.start();
+            deadline.sleep(PRODUCER_POLL_INTERVAL)?;
+        }
+        self.shared.header.stats.blocked(&blocked);
+
+        let capacity = self.capacity();
+        // SAFETY: consumers do not touch the payload while `len == 0`.
+        let len = f(unsafe { std::slice::from_raw_parts_mut(self.shared.tail(), capacity) });
+        check_len(len, capacity)?;
+        let sn = self.shared.sn.fetch_add(1, Ordering::AcqRel) + 1;
+        self.shared.header.stats.published();
+        self.shared.len.store(len, Ordering::Release);
+        if self.shared.consumers.assign(sn) {
+            // Every consumer left meanwhile
+            self.shared.len.store(0, Ordering::Release);
+        }
+        Ok(sn)
+    }
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.shared.header.producer.close();
+    }
+}
+
+pub struct Consumer {
+    shared: Segment<SharedData>,
+    membership: Membership,
+    _heartbeat: Heartbeat,
+}
+
+impl Consumer {
+    /// Fetches the segment served on `key_expr` and registers as a consumer.
+    /// The consumer receives samples published from now on.
+    pub fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        Self::open(segment::fetch(session, key_expr)?)
+    }
+
+    /// Attaches to a channel found by [`crate::discovery`].
+    pub fn from_channel(channel: &Channel) -> Result<Self> {
+        Self::open(channel.payload().clone())
+    }
+
+    fn open(payload: ZBytes) -> Result<Self> {
+        let shared = Segment::<SharedData>::attach(payload)?;
+        let membership = shared.consumers.join()?;
+        let _heartbeat = Heartbeat::start(shared.clone(), move |s: &SharedData| {
+            s.consumers.beat(membership)
+        });
+        Ok(Consumer {
+            shared,
+            membership,
+            _heartbeat,
+        })
+    }
+
+    /// Payload capacity of the mailbox.
+    pub fn capacity(&self) -> usize {
+        self.shared.header.capacity as usize
+    }
+
+    /// Whether the producer is still alive and has not closed the channel,
+    /// and has not evicted this consumer.
+    pub fn is_connected(&self) -> bool {
+        !self.shared.header.producer.is_gone() && self.shared.consumers.is_member(self.membership)
+    }
+
+    /// Hands the current sample and its sequence number to `f` if it has
+    /// not been read yet. Returns `None` if there is nothing new, or if the
+    /// consumer was evicted, which [`Consumer::is_connected`] tells.
+    pub fn try_recv_with<F, R>(&mut self, f: F) -> Option<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        self.poll().map(|(sn, len)| self.consume(sn, len, f))
+    }
+
+    /// Waits for a new sample and hands it to `f`. Fails with
+    /// [`Error::Disconnected`] once the producer is gone and every sample
+    /// has been read, and with [`Error::Evicted`] once the producer reaped
+    /// this consumer.
+    pub fn recv_with<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let mut waited = Stopwatch::new();
+        loop {
+            let gone = self.shared.header.producer.is_gone();
+            if let Some((sn, len)) = self.poll() {
+                self.counters().waited(&self.shared.header.stats, &waited);
+                return Ok(self.consume(sn, len, f));
+            }
+            if gone {
+                return Err(Error::Disconnected);
+            }
+            if !self.shared.consumers.is_member(self.membership) {
+                return Err(Error::Evicted);
+            }
+            // No data to read, wait for a while
+            waited.start();
+            deadline.sleep(CONSUMER_POLL_INTERVAL)?;
+        }
+    }
+
+    fn counters(&self) -> &ConsumerCounters {
+        self.shared.consumers.counters(self.membership)
+    }
+
+    /// Checks whether we hold a credit on the current sample.
+    fn poll(&self) -> Option<(u64, usize)> {
+        let sn = self.shared.consumers.owed(self.membership)?;
+        Some((sn, self.shared.len.load(Ordering::Acquire)))
+    }
+
+    fn consume<F, R>(&mut self, sn: u64, len: usize, f: F) -> R
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        // SAFETY: the producer does not touch the payload while `len != 0`.
+        let len = len.min(self.capacity());
+        let r = f(sn, unsafe {
+            std::slice::from_raw_parts(self.shared.tail(), len)
+        });
+        self.counters().read(&self.shared.header.stats);
+        if self.shared.consumers.release(self.membership, sn) {
+            log::debug!("{sn} - Last read, resetting length");
+            self.shared.len.store(0, Ordering::Release);
+        }
+        r
+    }
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        if self.shared.consumers.leave(self.membership) {
+            self.shared.len.store(0, Ordering::Release);
+        }
+    }
+}
+
+impl RawProducer for Producer {
+    type Published = u64;
+
+    fn header(&self) -> &Header {
+        &self.shared.header
+    }
+
+    fn publish_raw<F>(&mut self, deadline: &Deadline, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        self.publish_with(deadline, f)
+    }
+}
+
+impl RawConsumer for Consumer {
+    type Shared = SharedData;
+
+    fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        Consumer::attach(session, key_expr)
+    }
+
+    fn open(payload: ZBytes) -> Result<Self> {
+        Consumer::open(payload)
+    }
+
+    fn header(&self) -> &Header {
+        &self.shared.header
+    }
+
+    fn recv_raw<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        self.recv_with(deadline, f)
+    }
+}
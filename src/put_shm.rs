@@ -2,10 +2,17 @@ use std::io::Write;
 
 use zenoh::Wait;
 use zenoh::shm::{ShmProviderBuilder, ZShm};
+use zshm::cli::{Args, Defaults};
 fn main() {
-    let z = zenoh::open(zenoh::Config::default())
-        .wait()
-        .expect("Failed to open Zenoh session");
+    let args = Args::parse(
+        "Puts a string in shared memory",
+        Defaults {
+            key_expr: "zenoh/shm/buffer",
+            rate: 1.0,
+            ..Default::default()
+        },
+    );
+    let z = args.open().expect("Failed to open Zenoh session");
 
     let provider = ShmProviderBuilder::default_backend(64 * 1024)
         .wait()
@@ -18,12 +25,17 @@ fn main() {
     // Make the buf immutable so that we can (shallow) clone it.
     let data: ZShm = buf.into();
 
-    loop {
-        z.put("zenoh/shm/buffer", data.clone())
+    for n in 1.. {
+        z.put(&args.key_expr, data.clone())
             .wait()
             .expect("Failed to put SHM buffer");
         print!(".");
         std::io::stdout().flush().unwrap();
-        std::thread::sleep(std::time::Duration::from_secs(1));
+        if args.done(n) {
+            break;
+        }
+        if let Some(period) = args.period() {
+            std::thread::sleep(period);
+        }
     }
 }
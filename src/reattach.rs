@@ -0,0 +1,169 @@
+//! Consumers that survive a restart of their producer.
+//!
+//! A restarted producer allocates a new segment, so the consumers of the old
+//! one only see their producer go away. [`Reattach`] wraps any consumer: once
+//! the old segment is drained and its producer gone, it waits for a producer
+//! to serve the key expression again, woken by its liveliness token, re-runs
+//! the bootstrap query and attaches to the new segment. Producers tell their
+//! incarnations apart by the epoch of their [`ProducerLease`], and the switch
+//! is handed to the application as a [`Recv::Restarted`]: the samples
+//! published in between are lost, and sequence numbers start over.
+//!
+//! [`ProducerLease`]: crate::lease::ProducerLease
+
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use zenoh::pubsub::Subscriber;
+use zenoh::sample::SampleKind;
+use zenoh::{Session, Wait};
+
+use crate::deadline::Deadline;
+use crate::discovery::RETRY_INTERVAL;
+use crate::notify::Event;
+use crate::pod::RawConsumer;
+use crate::segment;
+use crate::{Error, Result};
+
+/// A switch from one producer to the next.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Restart {
+    /// Epoch of the producer that went away.
+    pub previous: u64,
+    /// Epoch of the producer now attached to.
+    pub epoch: u64,
+    /// Whether the previous producer closed the channel rather than dying,
+    /// in which case every sample it published was received.
+    pub closed: bool,
+    /// Time from the last heartbeat of the previous producer to the start of
+    /// the new one.
+    pub downtime: Duration,
+}
+
+/// What [`Reattach::recv_with`] got.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Recv<R> {
+    /// The value returned by the closure handed a sample.
+    Sample(R),
+    /// The producer restarted; the next samples come from its new segment.
+    Restarted(Restart),
+}
+
+/// A consumer `C` that follows its producer across restarts.
+pub struct Reattach<C> {
+    session: Session,
+    key_expr: String,
+    consumer: C,
+    appeared: Arc<Event>,
+    _subscriber: Subscriber<()>,
+}
+
+impl<C: RawConsumer> Reattach<C> {
+    /// Attaches to the segment served on `key_expr`, as `C::attach` does, and
+    /// starts watching for producers showing up on it.
+    pub fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        let appeared = Arc::new(Event::new());
+        let a = appeared.clone();
+        // Declared first, so that a producer restarting right after the
+        // attachment is not missed
+        let subscriber = session
+            .liveliness()
+            .declare_subscriber(key_expr)
+            .callback(move |sample| {
+                if sample.kind() == SampleKind::Put {
+                    a.notify_all();
+                }
+            })
+            .wait()?;
+        Ok(Reattach {
+            session: session.clone(),
+            key_expr: key_expr.to_string(),
+            consumer: C::attach(session, key_expr)?,
+            appeared,
+            _subscriber: subscriber,
+        })
+    }
+
+    /// The consumer of the current segment.
+    pub fn consumer(&self) -> &C {
+        &self.consumer
+    }
+
+    /// The consumer of the current segment, e.g. to receive from it without
+    /// waiting for a restart.
+    pub fn consumer_mut(&mut self) -> &mut C {
+        &mut self.consumer
+    }
+
+    /// Epoch of the producer of the current segment.
+    pub fn epoch(&self) -> u64 {
+        self.consumer.header().producer.epoch()
+    }
+
+    /// Waits for a sample and hands it to `f` with its sequence number. Once
+    /// the producer is gone and every sample it published was received,
+    /// waits for it to restart instead of failing with
+    /// [`Error::Disconnected`], and returns [`Recv::Restarted`] after
+    /// attaching to the new segment.
+    pub fn recv_with<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<Recv<R>>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        match self.consumer.recv_raw(deadline, f) {
+            Ok(r) => Ok(Recv::Sample(r)),
+            Err(Error::Disconnected) => self.reattach(deadline).map(Recv::Restarted),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Waits for a producer of another epoch to serve the key expression and
+    /// attaches to its segment. The old segment is kept until then, so that
+    /// a failed attempt can be retried by the next call.
+    fn reattach(&mut self, deadline: &Deadline) -> Result<Restart> {
+        let previous = &self.consumer.header().producer;
+        let epoch = previous.epoch();
+        let closed = previous.is_closed();
+        let heartbeat = previous.heartbeat.load(Ordering::Acquire);
+        log::info!(
+            "Producer on '{}' is gone, waiting for a new one",
+            self.key_expr
+        );
+        loop {
+            let seen = self.appeared.generation();
+            // Bounded by the deadline, since nothing else interrupts a query
+            let timeout = deadline
+                .instant()
+                .map(|at| at.saturating_duration_since(Instant::now()));
+            match segment::fetch_within(&self.session, &self.key_expr, timeout) {
+                // Checked before attaching, which registers 1:N consumers, so
+                // that an old producer still answering, e.g. because it hangs
+                // rather than died, does not make them join and leave again
+                Ok(payload) if segment::epoch(&payload)? == epoch => {}
+                Ok(payload) => {
+                    let consumer = if payload.as_shm().is_some() {
+                        C::open(payload)?
+                    } else {
+                        // Subscribes, for remote consumers
+                        C::attach(&self.session, &self.key_expr)?
+                    };
+                    let started = consumer.header().producer.epoch();
+                    let restart = Restart {
+                        previous: epoch,
+                        epoch: started,
+                        closed,
+                        downtime: Duration::from_nanos(
+                            started.saturating_sub(heartbeat.saturating_mul(1_000_000)),
+                        ),
+                    };
+                    log::info!("Reattached to '{}'", self.key_expr);
+                    self.consumer = consumer;
+                    return Ok(restart);
+                }
+                Err(Error::NoProducer(_)) => {}
+                Err(e) => return Err(e),
+            }
+            deadline.wait_at_most(&self.appeared, seen, RETRY_INTERVAL)?;
+        }
+    }
+}
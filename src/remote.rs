@@ -0,0 +1,338 @@
+//! Fallback to Zenoh pub/sub for consumers that cannot map the segment.
+//!
+//! A consumer on another host, or in a session without shared memory, gets a
+//! copy of the segment in reply to its bootstrap query; [`Consumer`] tells
+//! the producer which host it runs on, so that producers elsewhere only copy
+//! the fixed part of the segment, without the slots. [`Producer`] wraps any
+//! producer and also publishes each sample on `<key>/@samples`, prefixed with
+//! its sequence number, as long as some subscriber matches. [`Consumer`] maps
+//! the segment when it can and otherwise subscribes to these samples, reading
+//! the message type from the copied header, so that the same program runs
+//! next to the producer or across the network.
+//!
+//! Remote consumers are plain subscribers: the producer never waits for them,
+//! samples they are too slow for are dropped and counted by
+//! [`Consumer::missed`], and they receive every sample even on channels whose
+//! local consumers share them out, such as [`crate::mpmc`]. They learn that
+//! the producer is gone from its liveliness token.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use zenoh::bytes::ZBytes;
+use zenoh::matching::MatchingListener;
+use zenoh::pubsub::{Publisher, Subscriber};
+use zenoh::sample::SampleKind;
+use zenoh::{Session, Wait};
+
+use crate::deadline::Deadline;
+use crate::header::{Header, Layout, LayoutError};
+use crate::lease::CHECK_INTERVAL;
+use crate::notify::Event;
+use crate::pod::{RawConsumer, RawProducer};
+use crate::segment;
+use crate::{Error, Result};
+
+/// Samples a remote consumer buffers before dropping the oldest.
+pub const QUEUE_LEN: usize = 256;
+
+/// Size of the sequence number prefixed to each sample.
+const SN_LEN: usize = std::mem::size_of::<u64>();
+
+/// Key expression on which the samples of the channel served on `key_expr`
+/// are published.
+pub fn samples_key(key_expr: &str) -> String {
+    format!("{key_expr}/@samples")
+}
+
+/// A producer `C` that also publishes its samples to remote consumers.
+pub struct Producer<C> {
+    channel: C,
+    publisher: Publisher<'static>,
+    matching: Arc<AtomicBool>,
+    _listener: MatchingListener<()>,
+}
+
+impl<C: RawProducer<Published = u64>> Producer<C> {
+    /// Wraps `channel`, which serves `key_expr`. Samples are only copied
+    /// while a remote consumer subscribes.
+    pub fn new(session: &Session, key_expr: &str, channel: C) -> Result<Self> {
+        let publisher = session.declare_publisher(samples_key(key_expr)).wait()?;
+        let matching = Arc::new(AtomicBool::new(false));
+        let m = matching.clone();
+        let listener = publisher
+            .matching_listener()
+            .callback(move |status| m.store(status.matching(), Ordering::Release))
+            .wait()?;
+        Ok(Producer {
+            channel,
+            publisher,
+            matching,
+            _listener: listener,
+        })
+    }
+
+    /// The producer of the segment.
+    pub fn channel(&self) -> &C {
+        &self.channel
+    }
+
+    /// Publishes a sample as `C::publish_raw` does, then sends a copy of it to
+    /// the remote consumers, if any. Failing to send it is only logged, the
+    /// sample being published already.
+    pub fn publish_with<F>(&mut self, deadline: &Deadline, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        if !self.matching.load(Ordering::Acquire) {
+            return self.channel.publish_raw(deadline, f);
+        }
+        let mut copy = vec![0; SN_LEN];
+        let sn = self.channel.publish_raw(deadline, |buf| {
+            let len = f(buf);
+            copy.extend_from_slice(&buf[..len.min(buf.len())]);
+            len
+        })?;
+        copy[..SN_LEN].copy_from_slice(&sn.to_le_bytes());
+        // The local consumers have the sample already, failing would make the
+        // caller publish it twice
+        if let Err(e) = self.publisher.put(copy).wait() {
+            log::warn!("Failed to send sample {sn} to remote consumers: {e}");
+        }
+        Ok(sn)
+    }
+}
+
+impl<C: RawProducer<Published = u64>> RawProducer for Producer<C> {
+    type Published = u64;
+
+    fn header(&self) -> &Header {
+        self.channel.header()
+    }
+
+    fn publish_raw<F>(&mut self, deadline: &Deadline, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        self.publish_with(deadline, f)
+    }
+}
+
+/// A consumer that maps the segment through `C` when it can, and receives
+/// the samples of a [`Producer`] over the network otherwise.
+pub struct Consumer<C> {
+    inner: Inner<C>,
+}
+
+enum Inner<C> {
+    Shm(C),
+    Remote(Remote),
+}
+
+impl<C: RawConsumer> Consumer<C> {
+    /// Fetches the segment served on `key_expr` and maps it, or subscribes to
+    /// its samples if the reply is not in shared memory. Producers on another
+    /// host only reply with the fixed part of the segment.
+    pub fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        let payload = segment::fetch(session, key_expr)?;
+        let inner = if payload.as_shm().is_some() {
+            Inner::Shm(C::open(payload)?)
+        } else {
+            log::info!("Segment on '{key_expr}' is not mappable, receiving samples remotely");
+            Inner::Remote(Remote::subscribe::<C::Shared>(session, key_expr, &payload)?)
+        };
+        Ok(Consumer { inner })
+    }
+
+    /// Whether samples come over the network rather than from the segment.
+    pub fn is_remote(&self) -> bool {
+        matches!(self.inner, Inner::Remote(_))
+    }
+
+    /// The consumer of the segment, unless samples come over the network.
+    pub fn channel(&self) -> Option<&C> {
+        match &self.inner {
+            Inner::Shm(channel) => Some(channel),
+            Inner::Remote(_) => None,
+        }
+    }
+
+    /// Samples dropped because they came over the network faster than they
+    /// were received. Always 0 for a mapped segment.
+    pub fn missed(&self) -> u64 {
+        match &self.inner {
+            Inner::Shm(_) => 0,
+            Inner::Remote(remote) => remote.queue.missed.load(Ordering::Acquire),
+        }
+    }
+
+    /// Waits for a sample and hands it to `f` along with its sequence number.
+    /// Fails with [`Error::Disconnected`] once the producer is gone and every
+    /// sample received.
+    pub fn recv_with<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        match &mut self.inner {
+            Inner::Shm(channel) => channel.recv_raw(deadline, f),
+            Inner::Remote(remote) => remote.recv_with(deadline, f),
+        }
+    }
+}
+
+impl<C: RawConsumer> RawConsumer for Consumer<C> {
+    type Shared = C::Shared;
+
+    fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        Consumer::attach(session, key_expr)
+    }
+
+    /// Only maps `payload`, there being no session to subscribe with.
+    fn open(payload: ZBytes) -> Result<Self> {
+        Ok(Consumer {
+            inner: Inner::Shm(C::open(payload)?),
+        })
+    }
+
+    fn header(&self) -> &Header {
+        match &self.inner {
+            Inner::Shm(channel) => channel.header(),
+            Inner::Remote(remote) => &remote.header,
+        }
+    }
+
+    fn recv_raw<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        self.recv_with(deadline, f)
+    }
+}
+
+/// Samples received over the network, filled by the subscriber's callback.
+#[derive(Default)]
+struct Queue {
+    samples: Mutex<VecDeque<ZBytes>>,
+    arrived: Event, // Also notified when the producer goes away
+    gone: AtomicBool,
+    missed: AtomicU64,
+}
+
+impl Queue {
+    fn push(&self, payload: ZBytes) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == QUEUE_LEN {
+            samples.pop_front();
+            self.missed.fetch_add(1, Ordering::AcqRel);
+        }
+        samples.push_back(payload);
+        drop(samples);
+        self.arrived.notify_all();
+    }
+}
+
+struct Remote {
+    header: Header, // Copied from the reply, the producer's lease is stale
+    queue: Arc<Queue>,
+    buf: Vec<u64>, // Keeps samples aligned to `pod::MAX_ALIGN`
+    _samples: Subscriber<()>,
+    _liveliness: Subscriber<()>,
+}
+
+impl Remote {
+    fn subscribe<T: Layout>(session: &Session, key_expr: &str, payload: &ZBytes) -> Result<Self> {
+        let header = copy_header::<T>(payload)?;
+        let queue = Arc::new(Queue::default());
+        queue
+            .gone
+            .store(header.producer.is_closed(), Ordering::Release);
+        let q = queue.clone();
+        let liveliness = session
+            .liveliness()
+            .declare_subscriber(key_expr)
+            .callback(move |sample| {
+                if sample.kind() == SampleKind::Delete {
+                    q.gone.store(true, Ordering::Release);
+                    q.arrived.notify_all();
+                }
+            })
+            .wait()?;
+        let q = queue.clone();
+        let samples = session
+            .declare_subscriber(samples_key(key_expr))
+            .callback(move |sample| q.push(sample.payload().clone()))
+            .wait()?;
+        Ok(Remote {
+            header,
+            queue,
+            buf: Vec::new(),
+            _samples: samples,
+            _liveliness: liveliness,
+        })
+    }
+
+    fn recv_with<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let payload = loop {
+            let seen = self.queue.arrived.generation();
+            // Checked first, so that the samples received before leaving are read
+            let gone = self.queue.gone.load(Ordering::Acquire);
+            if let Some(payload) = self.queue.samples.lock().unwrap().pop_front() {
+                break payload;
+            }
+            if gone {
+                return Err(Error::Disconnected);
+            }
+            deadline.wait_at_most(&self.queue.arrived, seen, CHECK_INTERVAL)?;
+        };
+        let bytes = payload.to_bytes();
+        if bytes.len() < SN_LEN {
+            return Err(LayoutError::TooShort {
+                expected: SN_LEN,
+                found: bytes.len(),
+            }
+            .into());
+        }
+        let (sn, data) = bytes.split_at(SN_LEN);
+        let sn = u64::from_le_bytes(sn.try_into().unwrap());
+        self.buf.clear();
+        self.buf.resize(data.len().div_ceil(8), 0);
+        // SAFETY: `buf` holds at least `data.len()` bytes.
+        let aligned = unsafe {
+            std::slice::from_raw_parts_mut(self.buf.as_mut_ptr().cast::<u8>(), data.len())
+        };
+        aligned.copy_from_slice(data);
+        Ok(f(sn, aligned))
+    }
+}
+
+/// Reads the header of a segment of layout `T` copied over the network,
+/// after the same checks as [`crate::segment::Segment::attach`].
+fn copy_header<T: Layout>(payload: &ZBytes) -> Result<Header> {
+    let bytes = payload.to_bytes();
+    let expected = std::mem::size_of::<T>();
+    if bytes.len() < expected {
+        return Err(LayoutError::TooShort {
+            expected,
+            found: bytes.len(),
+        }
+        .into());
+    }
+    // SAFETY: the buffer holds at least a `T`, hence a `Header`, and any bit
+    // pattern is a valid `Header`.
+    let header = unsafe { std::ptr::read_unaligned(bytes.as_ptr().cast::<Header>()) };
+    header.validate::<T>()?;
+    // Copied to an aligned `T` to read the size of the tail from its fields
+    let mut layout = Box::<T>::new_uninit();
+    // SAFETY: `layout` has room for the `T` the buffer starts with, which was
+    // written by a producer of the same layout, as the header says.
+    let layout = unsafe {
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), layout.as_mut_ptr().cast::<u8>(), expected);
+        layout.assume_init()
+    };
+    segment::check_size(&header, &*layout)?;
+    Ok(header)
+}
@@ -0,0 +1,508 @@
+//! Typed views over a shared segment and the queryable bootstrap used to share it.
+
+use std::ptr::NonNull;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use zenoh::{
+    Session, Wait,
+    bytes::ZBytes,
+    liveliness::LivelinessToken,
+    query::{Query, Queryable},
+    shm::{AllocAlignment, ShmProviderBuilder, ZShm},
+};
+
+use crate::header::{Header, Layout, LayoutError, MAGIC};
+use crate::lease::Heartbeat;
+use crate::stats::{self, Report};
+use crate::{Error, Result};
+
+/// A `T` living in a shared-memory buffer.
+///
+/// The buffer is kept alive by the `ZBytes` handle, which is also what gets
+/// (shallow) copied into the bootstrap replies.
+pub(crate) struct Segment<T> {
+    payload: ZBytes,
+    ptr: NonNull<T>,
+}
+
+// SAFETY: the pointee is only accessed through `&T`, and the layouts are
+// designed for concurrent access from several processes.
+unsafe impl<T: Sync> Send for Segment<T> {}
+unsafe impl<T: Sync> Sync for Segment<T> {}
+
+impl<T: Layout> Segment<T> {
+    /// Allocates a zero-initialized `T` followed by `tail` bytes in a fresh
+    /// SHM provider, stamps its header with slots of `capacity` bytes and
+    /// lets `init` fill the fields that describe the tail.
+    pub(crate) fn create_with<F>(capacity: usize, tail: usize, init: F) -> Result<Self>
+    where
+        F: FnOnce(&mut T),
+    {
+        // get alignment for the layout type by means of new API
+        let alignment = AllocAlignment::for_type::<T>();
+        let size = std::mem::size_of::<T>()
+            .checked_add(tail)
+            .ok_or_else(|| Error::Alloc(format!("a tail of {tail} bytes is too large")))?;
+
+        let shm_provider = ShmProviderBuilder::default_backend(size)
+            .with_alignment(alignment)
+            .wait()
+            .map_err(|e| Error::Alloc(format!("{e:?}")))?;
+
+        let mut buf = shm_provider
+            .alloc(size)
+            .with_alignment(alignment)
+            .wait()
+            .map_err(|e| Error::Alloc(format!("{e:?}")))?;
+
+        let mut ptr = NonNull::new(buf.as_mut_ptr() as *mut T).expect("SHM buffer is null");
+        // SAFETY: the buffer is `size` bytes, aligned for `T`, zero is a valid
+        // value for `T` and `T` starts with a `Header`. Nobody else can see
+        // the buffer until it is served.
+        unsafe {
+            (ptr.as_ptr() as *mut u8).write_bytes(0, size);
+            (ptr.as_ptr() as *mut Header).write(Header::new::<T>(size, capacity));
+            init(ptr.as_mut());
+        }
+        debug_assert_eq!(unsafe { ptr.as_ref() }.tail_len(), tail);
+
+        // change the morph of buf to be able to make it's copies
+        let buf: ZShm = buf.into();
+        Ok(Segment {
+            payload: buf.into(),
+            ptr,
+        })
+    }
+
+    /// Maps a `T` onto a buffer received from the producer, after checking
+    /// that its header matches `T`.
+    pub(crate) fn attach(payload: ZBytes) -> Result<Self> {
+        let align = std::mem::align_of::<T>();
+        let addr = payload.as_shm().ok_or(Error::NotShm)?.as_ptr() as usize;
+        if !addr.is_multiple_of(align) {
+            return Err(LayoutError::Misaligned { align, addr }.into());
+        }
+        let header = header(&payload)?;
+        header.validate::<T>()?;
+        let ptr = NonNull::new(addr as *mut T).expect("SHM buffer is null");
+        // SAFETY: `validate` checked that the buffer holds at least a `T`.
+        check_size(header, unsafe { ptr.as_ref() })?;
+        Ok(Segment { payload, ptr })
+    }
+}
+
+#[cfg(test)]
+impl<T: Layout> Segment<T> {
+    /// Same as [`Segment::create_with`], but in a heap buffer that is never
+    /// freed, so that unit tests need neither Zenoh nor an SHM provider. The
+    /// payload is empty: other handles on the segment are clones.
+    pub(crate) fn local_with<F>(capacity: usize, tail: usize, init: F) -> Self
+    where
+        F: FnOnce(&mut T),
+    {
+        let size = std::mem::size_of::<T>() + tail;
+        let layout = std::alloc::Layout::from_size_align(size, std::mem::align_of::<T>())
+            .expect("segment too large");
+        // SAFETY: `T` is not zero-sized since it starts with a `Header`.
+        let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+        let mut ptr = NonNull::new(ptr.cast::<T>()).expect("allocation failed");
+        // SAFETY: as in `create_with`.
+        unsafe {
+            (ptr.as_ptr() as *mut Header).write(Header::new::<T>(size, capacity));
+            init(ptr.as_mut());
+        }
+        Segment {
+            payload: ZBytes::default(),
+            ptr,
+        }
+    }
+}
+
+/// Epoch of the producer of a segment fetched from it, whether mapped or
+/// copied.
+pub(crate) fn epoch(payload: &ZBytes) -> Result<u64> {
+    if payload.as_shm().is_some() {
+        return Ok(header(payload)?.producer.epoch());
+    }
+    let bytes = payload.to_bytes();
+    let expected = std::mem::size_of::<Header>();
+    if bytes.len() < expected {
+        return Err(LayoutError::TooShort {
+            expected,
+            found: bytes.len(),
+        }
+        .into());
+    }
+    // SAFETY: the buffer holds at least a `Header`, and any bit pattern is a
+    // valid `Header`.
+    let header = unsafe { std::ptr::read_unaligned(bytes.as_ptr().cast::<Header>()) };
+    if header.magic != MAGIC {
+        return Err(LayoutError::BadMagic(header.magic).into());
+    }
+    Ok(header.producer.epoch())
+}
+
+/// Checks that the segment described by `header` is as large as `layout`
+/// and the tail its fields announce, which may be overflowing sizes taken
+/// from a corrupt header.
+pub(crate) fn check_size<T: Layout>(header: &Header, layout: &T) -> Result<()> {
+    let expected = std::mem::size_of::<T>()
+        .checked_add(layout.tail_len())
+        .map(|size| size as u64);
+    if expected != Some(header.size) {
+        return Err(LayoutError::Shape {
+            size: expected.unwrap_or(u64::MAX),
+            align: std::mem::align_of::<T>() as u64,
+            found_size: header.size,
+            found_align: header.align,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// The header of a buffer received from a producer, whatever its layout.
+/// Checks that the buffer holds as many bytes as the header announces.
+pub(crate) fn header(payload: &ZBytes) -> Result<&Header> {
+    let shm = payload.as_shm().ok_or(Error::NotShm)?;
+    let align = std::mem::align_of::<Header>();
+    let addr = shm.as_ptr() as usize;
+    if !addr.is_multiple_of(align) {
+        return Err(LayoutError::Misaligned { align, addr }.into());
+    }
+    if shm.len() < std::mem::size_of::<Header>() {
+        return Err(LayoutError::TooShort {
+            expected: std::mem::size_of::<Header>(),
+            found: shm.len(),
+        }
+        .into());
+    }
+    // SAFETY: the buffer is long enough and aligned for a `Header`, whose
+    // fields accept any bit pattern, and lives as long as `payload`.
+    let header = unsafe { &*(shm.as_ptr() as *const Header) };
+    if header.magic != MAGIC {
+        return Err(LayoutError::BadMagic(header.magic).into());
+    }
+    if (shm.len() as u64) < header.size {
+        return Err(LayoutError::TooShort {
+            expected: header.size as usize,
+            found: shm.len(),
+        }
+        .into());
+    }
+    Ok(header)
+}
+
+impl<T> Segment<T> {
+    /// Start of the bytes following `T`.
+    pub(crate) fn tail(&self) -> *mut u8 {
+        // SAFETY: stays within (or one past) the allocation.
+        unsafe { (self.ptr.as_ptr() as *mut u8).add(std::mem::size_of::<T>()) }
+    }
+
+    /// The buffer handed out to consumers.
+    pub(crate) fn payload(&self) -> &ZBytes {
+        &self.payload
+    }
+}
+
+// Another handle on the same buffer
+impl<T> Clone for Segment<T> {
+    fn clone(&self) -> Self {
+        Segment {
+            payload: self.payload.clone(),
+            ptr: self.ptr,
+        }
+    }
+}
+
+impl<T> std::ops::Deref for Segment<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: validated at construction and kept alive by `payload`.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+/// Fails with [`Error::TooLarge`] if a sample of `len` bytes does not fit in
+/// a slot of `capacity` bytes.
+pub(crate) fn check_len(len: usize, capacity: usize) -> Result<()> {
+    if len > capacity {
+        return Err(Error::TooLarge { len, capacity });
+    }
+    Ok(())
+}
+
+/// How long to wait for the liveliness token of an existing producer before
+/// serving a key.
+pub(crate) const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What keeps a segment served and its producer visibly alive: the queryable
+/// answering bootstrap queries, the one answering statistics queries, a
+/// liveliness token on the same key expression and the heartbeat of the
+/// producer's lease.
+pub(crate) struct Served {
+    _queryable: Queryable<()>,
+    _stats: Queryable<()>,
+    _token: LivelinessToken,
+    _heartbeat: Heartbeat,
+}
+
+impl Served {
+    /// Starts the heartbeat of the producer of `segment`.
+    pub(crate) fn new<T: Layout + 'static>(
+        queryable: Queryable<()>,
+        stats: Queryable<()>,
+        token: LivelinessToken,
+        segment: &Segment<T>,
+    ) -> Self {
+        let heartbeat = Heartbeat::start(segment.clone(), |shared: &T| {
+            shared.header().producer.beat();
+            true
+        });
+        Served {
+            _queryable: queryable,
+            _stats: stats,
+            _token: token,
+            _heartbeat: heartbeat,
+        }
+    }
+}
+
+/// Answers every query on `key_expr` with a shallow copy of the segment, and
+/// those on [`stats::stats_key`] with its statistics, and keeps the
+/// producer's lease alive until the returned handle is dropped.
+///
+/// Fails with [`Error::ProducerExists`] if another producer already serves
+/// `key_expr`: consumers would otherwise attach to whichever segment answers
+/// first, and two single-writer segments would silently split the stream.
+/// Producers are told apart by their liveliness token, whose query is
+/// answered from Zenoh's token table. Two producers starting at the same
+/// time may still both miss the other's token and serve the key.
+pub(crate) fn serve<T: Report + 'static>(
+    session: &Session,
+    key_expr: &str,
+    segment: &Segment<T>,
+) -> Result<Served> {
+    let replies = session
+        .liveliness()
+        .get(key_expr)
+        .timeout(PROBE_TIMEOUT)
+        .wait()?;
+    if replies.recv().is_ok_and(|reply| reply.result().is_ok()) {
+        return Err(Error::ProducerExists(key_expr.to_string()));
+    }
+    let queryable = session
+        .declare_queryable(key_expr)
+        .callback(replier(key_expr, segment))
+        .wait()?;
+    let stats = session
+        .declare_queryable(stats::stats_key(key_expr))
+        .callback(stats::replier(key_expr, segment))
+        .wait()?;
+    let token = session.liveliness().declare_token(key_expr).wait()?;
+    Ok(Served::new(queryable, stats, token, segment))
+}
+
+/// Replies to a bootstrap query with a shallow copy of the segment, or with
+/// a copy of its fixed part, without the tail, when the query comes from
+/// another host (see [`selector`]). The tail would be copied over the network
+/// in full, only to be thrown away by consumers that cannot map it.
+pub(crate) fn replier<T: Layout>(
+    key_expr: &str,
+    segment: &Segment<T>,
+) -> impl Fn(Query) + Send + Sync + 'static {
+    let key = key_expr.to_string();
+    let payload = segment.payload().clone();
+    move |query| {
+        let reply = match query.parameters().get(HOST_PARAM) {
+            Some(host) if Some(host) != host_id() => {
+                ZBytes::from(payload.to_bytes()[..std::mem::size_of::<T>()].to_vec())
+            }
+            _ => payload.clone(),
+        };
+        if let Err(e) = query.reply(&key, reply).wait() {
+            log::warn!("Failed to reply to query on {key}: {e}");
+        }
+    }
+}
+
+/// Selector parameter through which a consumer tells the producer which host
+/// it runs on.
+const HOST_PARAM: &str = "host";
+
+/// Identifies the running kernel, hence the hosts on which a segment can be
+/// mapped. `None` where it is unknown, in which case queries always fetch the
+/// whole segment.
+fn host_id() -> Option<&'static str> {
+    static HOST_ID: OnceLock<Option<String>> = OnceLock::new();
+    HOST_ID
+        .get_or_init(|| {
+            let id = std::fs::read_to_string("/proc/sys/kernel/random/boot_id").ok()?;
+            Some(id.trim().to_string())
+        })
+        .as_deref()
+}
+
+/// Selector of the bootstrap queries on `key_expr`, which tells producers
+/// the host the query comes from, so that those running on another host
+/// only reply with the fixed part of their segment. Every bootstrap query
+/// goes through it.
+pub(crate) fn selector(key_expr: &str) -> String {
+    match host_id() {
+        Some(host) => format!("{key_expr}?{HOST_PARAM}={host}"),
+        None => key_expr.to_string(),
+    }
+}
+
+/// Fetches the segment published on `key_expr`, taking the first reply.
+pub(crate) fn fetch(session: &Session, key_expr: &str) -> Result<ZBytes> {
+    fetch_within(session, key_expr, None)
+}
+
+/// Same as [`fetch`], but fails with [`Error::NoProducer`] if no reply
+/// arrives within `timeout`, Zenoh's query timeout by default.
+pub(crate) fn fetch_within(
+    session: &Session,
+    key_expr: &str,
+    timeout: Option<Duration>,
+) -> Result<ZBytes> {
+    let get = session.get(selector(key_expr));
+    let replies = match timeout {
+        Some(timeout) => get.timeout(timeout).wait()?,
+        None => get.wait()?,
+    };
+    let reply = replies
+        .recv()
+        .map_err(|_| Error::NoProducer(key_expr.to_string()))?;
+    let sample = reply
+        .into_result()
+        .map_err(|e| Error::Reply(format!("{e:?}")))?;
+    Ok(sample.payload().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::{Layout, Protocol};
+    use crate::seqlock::SharedData;
+
+    const CAPACITY: usize = 64;
+
+    /// What is altered in the header, how, and the resulting error.
+    type Case = (&'static str, fn(&mut Header), LayoutError);
+
+    /// Checks a seqlock segment whose header was altered by `corrupt` as
+    /// [`Segment::attach`] does.
+    fn check(corrupt: impl FnOnce(&mut Header)) -> Result<()> {
+        let segment = Segment::<SharedData>::local_with(CAPACITY, CAPACITY, |shared| {
+            corrupt(&mut shared.header);
+        });
+        segment.header.validate::<SharedData>()?;
+        check_size(&segment.header, &*segment)
+    }
+
+    #[test]
+    fn corrupt_headers_are_rejected() {
+        let size = std::mem::size_of::<SharedData>() as u64;
+        let align = std::mem::align_of::<SharedData>() as u64;
+        let full = size + CAPACITY as u64;
+        let shape = |found_size, found_align| LayoutError::Shape {
+            size: full,
+            align,
+            found_size,
+            found_align,
+        };
+        let cases: [Case; 11] = [
+            ("magic", |h| h.magic = 0, LayoutError::BadMagic(0)),
+            (
+                "byte-swapped magic",
+                |h| h.magic = h.magic.swap_bytes(),
+                LayoutError::BadMagic(MAGIC.swap_bytes()),
+            ),
+            (
+                "protocol",
+                |h| h.protocol = Protocol::Spsc as u32,
+                LayoutError::Protocol {
+                    expected: Protocol::Seqlock,
+                    found: Protocol::Spsc as u32,
+                },
+            ),
+            (
+                "unknown protocol",
+                |h| h.protocol = 0,
+                LayoutError::Protocol {
+                    expected: Protocol::Seqlock,
+                    found: 0,
+                },
+            ),
+            (
+                "version",
+                |h| h.version += 1,
+                LayoutError::Version {
+                    expected: SharedData::VERSION,
+                    found: SharedData::VERSION + 1,
+                },
+            ),
+            (
+                "size below the layout",
+                |h| h.size = 8,
+                LayoutError::Shape {
+                    size,
+                    align,
+                    found_size: 8,
+                    found_align: align,
+                },
+            ),
+            (
+                "size past the tail",
+                |h| h.size += 1,
+                shape(full + 1, align),
+            ),
+            (
+                "size short of the tail",
+                |h| h.size -= 1,
+                shape(full - 1, align),
+            ),
+            (
+                "align",
+                |h| h.align = 8,
+                LayoutError::Shape {
+                    size,
+                    align,
+                    found_size: full,
+                    found_align: 8,
+                },
+            ),
+            (
+                "capacity",
+                |h| h.capacity += 1,
+                LayoutError::Shape {
+                    size: full + 1,
+                    align,
+                    found_size: full,
+                    found_align: align,
+                },
+            ),
+            (
+                "overflowing capacity",
+                |h| h.capacity = u64::MAX,
+                LayoutError::Shape {
+                    size: u64::MAX,
+                    align,
+                    found_size: full,
+                    found_align: align,
+                },
+            ),
+        ];
+        assert!(check(|_| {}).is_ok());
+        for (name, corrupt, expected) in cases {
+            match check(corrupt) {
+                Err(Error::Layout(e)) => assert_eq!(e, expected, "{name}"),
+                result => panic!("{name}: {result:?}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,603 @@
+//! 1:N latest-value channel based on a seqlock.
+//!
+//! The segment holds a single value that the producer overwrites at will: it
+//! never waits for readers, which in turn never block it. `seq` is odd while
+//! a write is in progress and even otherwise, so that the value with sequence
+//! number `seq / 2` is complete whenever `seq` is even. Readers copy the value
+//! out and retry if `seq` changed meanwhile, i.e. if the copy may be torn.
+//! Since only the newest value is kept, readers tell how many updates they
+//! skipped from the gaps between the sequence numbers they read.
+//!
+//! Readers copy the value with relaxed atomic loads, so that racing with the
+//! producer yields a torn copy to discard rather than a data race. The
+//! producer still writes it with plain stores through the buffer handed to
+//! its closure: like every seqlock written in Rust, this mix of atomic and
+//! non-atomic accesses is not covered by the memory model, and relies on the
+//! loads never observing anything worse than stale or torn bytes, which is
+//! what the hardware provides.
+
+use std::sync::atomic::{AtomicU8, AtomicU64, AtomicUsize, Ordering, fence};
+
+use zenoh::Session;
+use zenoh::bytes::ZBytes;
+
+#[cfg(feature = "tokio")]
+use crate::bridge;
+use crate::cache_padded::CachePadded;
+use crate::deadline::Deadline;
+use crate::discovery::Channel;
+use crate::header::{Header, Layout, MessageType, Protocol};
+use crate::lease::CHECK_INTERVAL;
+use crate::notify::Event;
+use crate::pod::{self, RawConsumer, RawProducer, ShmPod};
+use crate::segment::{self, Segment, Served, check_len};
+use crate::stats::{Report, Stopwatch};
+use crate::{Error, Result};
+
+/// Attempts at reading a value before yielding to the writer.
+const SPIN_LIMIT: u32 = 64;
+
+// Shared data, followed by `header.capacity` bytes of payload
+#[repr(C)]
+pub struct SharedData {
+    pub header: Header,
+    pub seq: CachePadded<AtomicU64>, // Twice the sequence number, plus one during writes
+    pub len: AtomicUsize,
+    pub updated: Event, // Notified when a value is published
+}
+
+unsafe impl Layout for SharedData {
+    const PROTOCOL: Protocol = Protocol::Seqlock;
+    const VERSION: u32 = 5;
+
+    fn tail_len(&self) -> usize {
+        self.header.capacity as usize
+    }
+}
+
+impl Report for SharedData {}
+
+fn allocate(capacity: usize, message: MessageType) -> Result<Segment<SharedData>> {
+    Segment::<SharedData>::create_with(capacity, capacity, |shared| {
+        shared.header.message = message;
+    })
+}
+
+pub struct Producer {
+    shared: Segment<SharedData>,
+    _served: Served,
+}
+
+impl Producer {
+    /// Allocates a value of up to `capacity` bytes and serves it on
+    /// `key_expr`.
+    pub fn new(session: &Session, key_expr: &str, capacity: usize) -> Result<Self> {
+        Self::with_message(session, key_expr, capacity, MessageType::BYTES)
+    }
+
+    /// Allocates room for an `M` value and serves it on `key_expr`.
+    pub fn typed<M: ShmPod>(session: &Session, key_expr: &str) -> Result<pod::Producer<Self, M>> {
+        let producer = Self::with_message(
+            session,
+            key_expr,
+            std::mem::size_of::<M>(),
+            MessageType::of::<M>(),
+        )?;
+        pod::Producer::new(producer)
+    }
+
+    /// Same as [`Producer::new`], but records the type of the messages
+    /// carried in the header, see [`crate::pod`] and [`crate::arena`].
+    pub fn with_message(
+        session: &Session,
+        key_expr: &str,
+        capacity: usize,
+        message: MessageType,
+    ) -> Result<Self> {
+        let shared = allocate(capacity, message)?;
+        let _served = segment::serve(session, key_expr, &shared)?;
+        Ok(Producer { shared, _served })
+    }
+
+    /// Payload capacity of the value.
+    pub fn capacity(&self) -> usize {
+        self.shared.header.capacity as usize
+    }
+
+    /// Replaces the value with `data`. Fails with [`Error::TooLarge`] if
+    /// `data` exceeds the capacity.
+    pub fn publish(&mut self, data: &[u8]) -> Result<u64> {
+        check_len(data.len(), self.capacity())?;
+        self.publish_with(|buf| {
+            buf[..data.len()].copy_from_slice(data);
+            data.len()
+        })
+    }
+
+    /// Overwrites the value in place, without waiting for readers. `f` gets
+    /// the previous value, fills the buffer and returns the number of bytes
+    /// written. Returns the sequence number of the new value, or fails with
+    /// [`Error::TooLarge`] if `f` returns more than the capacity. The previous
+    /// value may have been overwritten by then, so readers get none until the
+    /// next one is published.
+    pub fn publish_with<F>(&mut self, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        write(&self.shared, f)
+    }
+}
+
+/// Overwrites the value of `shared`, see [`Producer::publish_with`]. The
+/// caller must be the only writer.
+fn write<F>(shared: &Segment<SharedData>, f: F) -> Result<u64>
+where
+    F: FnOnce(&mut [u8]) -> usize,
+{
+    let capacity = shared.header.capacity as usize;
+    // We are the only writer, so `seq` is even here
+    let seq = shared.seq.load(Ordering::Relaxed);
+    shared.seq.store(seq + 1, Ordering::Relaxed);
+    // Orders the odd `seq` before the writes to the payload
+    fence(Ordering::Release);
+
+    // SAFETY: readers never hand out the payload while `seq` is odd or
+    // changed during their copy.
+    let len = f(unsafe { std::slice::from_raw_parts_mut(shared.tail(), capacity) });
+    let result = check_len(len, capacity);
+    match result {
+        Ok(()) => {
+            shared.len.store(len, Ordering::Relaxed);
+            shared.header.stats.published();
+        }
+        // Marks the value as missing, for readers to skip it
+        Err(_) => shared.len.store(usize::MAX, Ordering::Relaxed),
+    }
+
+    shared.seq.store(seq + 2, Ordering::Release);
+    shared.updated.notify_all();
+    result.map(|()| seq / 2 + 1)
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.shared.header.producer.close();
+        self.shared.updated.notify_all();
+    }
+}
+
+pub struct Consumer {
+    shared: Segment<SharedData>,
+    buf: Vec<u64>, // Copy of the value, aligned for typed messages
+    sn: u64,       // Last value read
+    skipped: u64,
+}
+
+impl Consumer {
+    /// Fetches the value served on `key_expr`. The consumer then reads the
+    /// current value, if any, and every later one it gets to see.
+    pub fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        Self::open(segment::fetch(session, key_expr)?)
+    }
+
+    /// Attaches to a channel found by [`crate::discovery`].
+    pub fn from_channel(channel: &Channel) -> Result<Self> {
+        Self::open(channel.payload().clone())
+    }
+
+    fn open(payload: ZBytes) -> Result<Self> {
+        Ok(Self::mapped(Segment::attach(payload)?))
+    }
+
+    pub(crate) fn mapped(shared: Segment<SharedData>) -> Self {
+        let words = (shared.header.capacity as usize).div_ceil(std::mem::size_of::<u64>());
+        Consumer {
+            shared,
+            buf: vec![0; words],
+            sn: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Payload capacity of the value.
+    pub fn capacity(&self) -> usize {
+        self.shared.header.capacity as usize
+    }
+
+    /// Whether the producer is still alive and has not closed the channel.
+    pub fn is_connected(&self) -> bool {
+        !self.shared.header.producer.is_gone()
+    }
+
+    /// Sequence number of the last value read, 0 before the first one.
+    pub fn last_sn(&self) -> u64 {
+        self.sn
+    }
+
+    /// Number of values published since attaching that this consumer never
+    /// read because newer ones replaced them first.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Sequence number of the newest complete value, 0 if none was published.
+    pub fn latest_sn(&self) -> u64 {
+        self.shared.seq.load(Ordering::Acquire) / 2
+    }
+
+    /// Hands the newest value and its sequence number to `f` if it was not
+    /// read yet. Returns `None` if nothing new was published, or if the
+    /// producer keeps overwriting the value faster than it can be copied.
+    pub fn try_recv_with<F, R>(&mut self, f: F) -> Option<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let mut attempt = 0;
+        loop {
+            match self.read() {
+                Read::Value(sn, len) => return Some(self.consume(sn, len, f)),
+                Read::Old => return None,
+                Read::Torn if attempt < SPIN_LIMIT => {
+                    attempt += 1;
+                    std::hint::spin_loop();
+                }
+                Read::Torn => return None,
+            }
+        }
+    }
+
+    /// Waits for a value newer than the last one read and hands it to `f`.
+    /// Fails with [`Error::Disconnected`] once the producer is gone and the
+    /// newest value was read.
+    pub fn recv_with<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let mut waited = Stopwatch::new();
+        loop {
+            let seen = self.shared.updated.generation();
+            match self.poll(deadline)? {
+                Some((sn, len)) => {
+                    self.shared.header.stats.waited(&waited);
+                    return Ok(self.consume(sn, len, f));
+                }
+                None => {
+                    waited.start();
+                    deadline.wait_at_most(&self.shared.updated, seen, CHECK_INTERVAL)?;
+                }
+            }
+        }
+    }
+
+    /// Reads the newest value, retrying torn reads. Returns `None` if there
+    /// is nothing new to read yet.
+    fn poll(&mut self, deadline: &Deadline) -> Result<Option<(u64, usize)>> {
+        let mut attempt = 0;
+        loop {
+            let gone = self.shared.header.producer.is_gone();
+            match self.read() {
+                Read::Value(sn, len) => return Ok(Some((sn, len))),
+                Read::Torn => {
+                    // A write is in progress, or the producer died during one
+                    attempt += 1;
+                    if attempt % SPIN_LIMIT == 0 {
+                        if gone {
+                            return Err(Error::Disconnected);
+                        }
+                        deadline.check()?;
+                        std::thread::yield_now();
+                    } else {
+                        std::hint::spin_loop();
+                    }
+                }
+                Read::Old if gone => return Err(Error::Disconnected),
+                Read::Old => return Ok(None),
+            }
+        }
+    }
+
+    /// Copies the current value into `buf` if it is newer than the last one
+    /// read.
+    fn read(&mut self) -> Read {
+        let shared = &*self.shared;
+        let before = shared.seq.load(Ordering::Acquire);
+        if before % 2 == 1 {
+            return Read::Torn;
+        }
+        let sn = before / 2;
+        if sn <= self.sn {
+            return Read::Old;
+        }
+        let len = shared.len.load(Ordering::Relaxed);
+        if len > self.capacity() {
+            // The producer failed to write this value
+            return Read::Old;
+        }
+        // SAFETY: the payload holds `capacity` bytes, aligned on a cache
+        // line since `SharedData` is. The copy may race with the producer, in
+        // which case it is discarded below.
+        unsafe { load_relaxed(self.shared.tail(), &mut self.buf, len) };
+        // Orders the copy before reading `seq` again
+        fence(Ordering::Acquire);
+        if shared.seq.load(Ordering::Relaxed) != before {
+            return Read::Torn;
+        }
+        Read::Value(sn, len)
+    }
+
+    fn consume<F, R>(&mut self, sn: u64, len: usize, f: F) -> R
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        if self.sn != 0 {
+            let skipped = sn - self.sn - 1;
+            self.skipped += skipped;
+            self.shared.header.stats.skip(skipped);
+        }
+        self.shared.header.stats.read();
+        self.sn = sn;
+        // SAFETY: `buf` holds at least `len` bytes, initialized by `read`.
+        let data = unsafe { std::slice::from_raw_parts(self.buf.as_ptr().cast::<u8>(), len) };
+        f(sn, data)
+    }
+}
+
+/// Copies `len` bytes from `src` into `dst` with relaxed atomic loads, a
+/// word at a time.
+///
+/// # Safety
+///
+/// `src` must be aligned for `u64` and valid for reads of `len` bytes, which
+/// must fit in `dst`.
+unsafe fn load_relaxed(src: *mut u8, dst: &mut [u64], len: usize) {
+    let words = len / 8;
+    for (i, word) in dst[..words].iter_mut().enumerate() {
+        // SAFETY: in bounds and aligned, see above.
+        let src = unsafe { AtomicU64::from_ptr(src.cast::<u64>().add(i)) };
+        *word = src.load(Ordering::Relaxed);
+    }
+    let mut last = [0; 8];
+    for (i, byte) in last[..len % 8].iter_mut().enumerate() {
+        // SAFETY: in bounds, see above.
+        let src = unsafe { AtomicU8::from_ptr(src.add(words * 8 + i)) };
+        *byte = src.load(Ordering::Relaxed);
+    }
+    if !len.is_multiple_of(8) {
+        dst[words] = u64::from_ne_bytes(last);
+    }
+}
+
+enum Read {
+    Value(u64, usize), // Sequence number and length of a consistent copy
+    Old,               // Nothing newer than the last value read
+    Torn,              // Overwritten while copying, retry
+}
+
+impl RawProducer for Producer {
+    type Published = u64;
+
+    fn header(&self) -> &Header {
+        &self.shared.header
+    }
+
+    fn publish_raw<F>(&mut self, _deadline: &Deadline, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        self.publish_with(f)
+    }
+}
+
+impl RawConsumer for Consumer {
+    type Shared = SharedData;
+
+    fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        Consumer::attach(session, key_expr)
+    }
+
+    fn open(payload: ZBytes) -> Result<Self> {
+        Consumer::open(payload)
+    }
+
+    fn header(&self) -> &Header {
+        &self.shared.header
+    }
+
+    fn recv_raw<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        self.recv_with(deadline, f)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Producer {
+    /// Async version of [`Producer::new`]. Publishing never waits, so it
+    /// needs no async counterpart.
+    pub async fn new_async(session: &Session, key_expr: &str, capacity: usize) -> Result<Self> {
+        Self::with_message_async(session, key_expr, capacity, MessageType::BYTES).await
+    }
+
+    /// Async version of [`Producer::with_message`].
+    pub async fn with_message_async(
+        session: &Session,
+        key_expr: &str,
+        capacity: usize,
+        message: MessageType,
+    ) -> Result<Self> {
+        let shared = allocate(capacity, message)?;
+        let _served = bridge::serve(session, key_expr, &shared).await?;
+        Ok(Producer { shared, _served })
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Consumer {
+    /// Async version of [`Consumer::attach`].
+    pub async fn attach_async(session: &Session, key_expr: &str) -> Result<Self> {
+        Self::open(bridge::fetch(session, key_expr).await?)
+    }
+
+    /// Async version of [`Consumer::recv_with`].
+    pub async fn recv_async<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let mut waited = Stopwatch::new();
+        loop {
+            let seen = self.shared.updated.generation();
+            match self.poll(deadline)? {
+                Some((sn, len)) => {
+                    self.shared.header.stats.waited(&waited);
+                    return Ok(self.consume(sn, len, f));
+                }
+                None => {
+                    waited.start();
+                    bridge::wait(&self.shared, |s| &s.updated, seen, deadline).await?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+
+    use super::*;
+
+    fn local(capacity: usize) -> Segment<SharedData> {
+        Segment::local_with(capacity, capacity, |_| {})
+    }
+
+    fn publish(shared: &Segment<SharedData>, data: &[u8]) -> Result<u64> {
+        write(shared, |buf| {
+            buf[..data.len()].copy_from_slice(data);
+            data.len()
+        })
+    }
+
+    fn recv(consumer: &mut Consumer) -> Option<(u64, Vec<u8>)> {
+        consumer.try_recv_with(|sn, data| (sn, data.to_vec()))
+    }
+
+    #[test]
+    fn load_relaxed_copies_partial_words() {
+        let src: Vec<u64> = (0..5).map(|i| 0x0101_0101_0101_0101 * (i + 1)).collect();
+        let bytes: Vec<u8> = src.iter().flat_map(|w| w.to_ne_bytes()).collect();
+        for len in 0..=bytes.len() {
+            let mut dst = [0; 5];
+            // SAFETY: `src` is aligned and holds `len` bytes, which fit in
+            // `dst`.
+            unsafe { load_relaxed(src.as_ptr().cast::<u8>().cast_mut(), &mut dst, len) };
+            let copied: Vec<u8> = dst.iter().flat_map(|w| w.to_ne_bytes()).collect();
+            assert_eq!(copied[..len], bytes[..len], "len {len}");
+        }
+    }
+
+    #[test]
+    fn write_in_progress_is_not_read() {
+        let shared = local(8);
+        let mut consumer = Consumer::mapped(shared.clone());
+        publish(&shared, b"value").unwrap();
+
+        // A writer stopped between its two increments of `seq`
+        shared.seq.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(recv(&mut consumer), None);
+        shared.seq.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(recv(&mut consumer), Some((2, b"value".to_vec())));
+    }
+
+    #[test]
+    fn torn_reads_are_retried() {
+        const WORDS: usize = 64;
+        const VALUES: u64 = 20_000;
+        let shared = local(WORDS * 8);
+        let mut consumer = Consumer::mapped(shared.clone());
+        let done = Arc::new(AtomicBool::new(false));
+
+        let writer = {
+            let shared = shared.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                for sn in 1..=VALUES {
+                    let value = sn.to_ne_bytes().repeat(WORDS);
+                    assert_eq!(publish(&shared, &value).unwrap(), sn);
+                }
+                done.store(true, Ordering::Release);
+            })
+        };
+        let mut read = Vec::new();
+        loop {
+            // Checked before reading, for the last value to be read too
+            let finished = done.load(Ordering::Acquire);
+            if let Some((sn, data)) = recv(&mut consumer) {
+                let words: Vec<u64> = data
+                    .chunks(8)
+                    .map(|w| u64::from_ne_bytes(w.try_into().unwrap()))
+                    .collect();
+                assert_eq!(words, vec![sn; WORDS]);
+                read.push(sn);
+            } else if finished {
+                break;
+            }
+        }
+        writer.join().unwrap();
+        assert_eq!(read.last(), Some(&VALUES));
+        let skipped = VALUES - read[0] + 1 - read.len() as u64;
+        assert_eq!(consumer.skipped(), skipped);
+    }
+
+    #[test]
+    fn skipped_values_are_counted_from_sequence_numbers() {
+        let shared = local(8);
+        let mut consumer = Consumer::mapped(shared.clone());
+        publish(&shared, b"1").unwrap();
+        publish(&shared, b"2").unwrap();
+        // Values published before the first read are not skipped ones
+        assert_eq!(recv(&mut consumer), Some((2, b"2".to_vec())));
+        assert_eq!(consumer.skipped(), 0);
+        assert_eq!(recv(&mut consumer), None);
+
+        for value in [b"3", b"4", b"5"] {
+            publish(&shared, value).unwrap();
+        }
+        assert_eq!(recv(&mut consumer), Some((5, b"5".to_vec())));
+        assert_eq!(consumer.skipped(), 2);
+        assert_eq!(shared.header.stats.skipped.load(Ordering::Relaxed), 2);
+        assert_eq!(shared.header.stats.consumed.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn oversized_value_is_never_read() {
+        let shared = local(8);
+        let mut consumer = Consumer::mapped(shared.clone());
+        publish(&shared, b"1").unwrap();
+        assert_eq!(recv(&mut consumer), Some((1, b"1".to_vec())));
+
+        let result = write(&shared, |buf| {
+            buf.fill(0xff);
+            buf.len() + 1
+        });
+        assert!(matches!(
+            result,
+            Err(Error::TooLarge {
+                len: 9,
+                capacity: 8
+            })
+        ));
+        // The value is complete but marked as missing
+        assert_eq!(shared.len.load(Ordering::Relaxed), usize::MAX);
+        assert_eq!(consumer.latest_sn(), 2);
+        assert_eq!(recv(&mut consumer), None);
+        assert_eq!(consumer.last_sn(), 1);
+
+        // The next value replaces it, which the consumer never got to read
+        publish(&shared, b"3").unwrap();
+        assert_eq!(recv(&mut consumer), Some((3, b"3".to_vec())));
+        assert_eq!(consumer.skipped(), 1);
+        assert_eq!(shared.header.stats.produced.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,67 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use zshm::cli::{Args, Defaults};
+use zshm::seqlock::Consumer;
+
+fn main() {
+    let args = Args::parse(
+        "Seqlock latest-value consumer",
+        Defaults {
+            key_expr: "shm/seqlock/state",
+            delay: Duration::from_millis(500),
+            ..Default::default()
+        },
+    );
+
+    // Set up Ctrl-C handler
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+
+    ctrlc::set_handler(move || {
+        println!("\nReceived Ctrl-C! Shutting down gracefully...");
+        r.store(false, Ordering::Release);
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    let z = args.open().expect("Failed to open Zenoh session");
+
+    let mut consumer = match Consumer::attach(&z, &args.key_expr) {
+        Ok(consumer) => consumer,
+        Err(e) => {
+            println!("Failed to attach: {e}");
+            return;
+        }
+    };
+    println!("Received SHM buffer");
+
+    let mut received = 0;
+    while running.load(Ordering::Acquire) {
+        let connected = consumer.is_connected();
+        let sampled = consumer.try_recv_with(|sn, data| {
+            let sum: u32 = data.iter().map(|&b| b as u32).sum();
+            println!(
+                "{sn} - Sampled value of {} bytes with sum {sum}",
+                data.len()
+            );
+        });
+        if sampled.is_some() {
+            println!("Skipped {} updates so far", consumer.skipped());
+            received += 1;
+            if args.done(received) {
+                break;
+            }
+            // Just simulate some processing time, the producer keeps going
+            args.process();
+        } else {
+            if !connected {
+                println!("Producer is gone");
+                break;
+            }
+            // Nothing new, wait for a while
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+    println!("Seqlock consumer stopped.");
+}
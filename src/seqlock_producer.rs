@@ -0,0 +1,42 @@
+use rand::random;
+use zshm::cli::{Args, Defaults};
+use zshm::seqlock::Producer;
+
+fn main() {
+    let args = Args::parse(
+        "Seqlock latest-value producer",
+        Defaults {
+            key_expr: "shm/seqlock/state",
+            rate: 10.0,
+            ..Default::default()
+        },
+    );
+
+    let z = args.open().expect("Failed to open Zenoh session");
+
+    let mut producer =
+        Producer::new(&z, &args.key_expr, args.size).expect("Failed to create producer");
+
+    // producer loop, never waits for readers
+    for n in 1.. {
+        let mut sum: usize = 0;
+        let mut len = 0;
+        let sn = producer
+            .publish_with(|data| {
+                len = args.payload_len();
+                for b in &mut data[..len] {
+                    *b = random();
+                    sum += *b as usize;
+                }
+                len
+            })
+            .expect("Failed to publish");
+        println!("{sn} - Published value of {len} bytes with sum of {sum}");
+        if args.done(n) {
+            break;
+        }
+        if let Some(period) = args.period() {
+            std::thread::sleep(period);
+        }
+    }
+}
@@ -0,0 +1,660 @@
+//! Bounded single-producer/single-consumer ring.
+//!
+//! The segment holds `slot_count` slots of `slot_size` bytes each, so the
+//! producer can run ahead of the consumer by up to `slot_count` samples
+//! instead of waiting for every sample to be consumed as in
+//! [`crate::polling`]. `head` (next sequence to write, owned by the producer)
+//! and `tail` (next sequence to read, owned by the consumer) only ever grow
+//! and live on separate cache lines; slot `i` holds sequence `i % slot_count`.
+//!
+//! A single consumer may attach at a time: it holds the [`ConsumerLease`],
+//! which records its PID and a heartbeat refreshed by a background thread
+//! (see [`crate::lease`]), until dropped. Another one fails with
+//! [`Error::Full`] unless the process holding the lease is gone or its lease
+//! expired, in which case it takes the lease over. A consumer whose lease was
+//! taken over while still alive, e.g. stopped in a debugger, fails with
+//! [`Error::Evicted`].
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use zenoh::Session;
+use zenoh::bytes::ZBytes;
+
+#[cfg(feature = "tokio")]
+use crate::bridge;
+use crate::cache_padded::CachePadded;
+use crate::deadline::Deadline;
+use crate::discovery::Channel;
+use crate::header::{Header, Layout, LayoutError, MessageType, Protocol};
+use crate::lease::{CHECK_INTERVAL, Heartbeat, expired, now_millis, pid_namespace, process_gone};
+use crate::notify::Event;
+use crate::pod::{self, RawConsumer, RawProducer, ShmPod};
+use crate::segment::{self, Segment, Served, check_len};
+use crate::stats::{Report, Stopwatch};
+use crate::{Error, Result};
+
+/// Bytes in front of each slot's payload, holding its length.
+const SLOT_HEADER: usize = std::mem::size_of::<u64>();
+
+// Consumer lease states
+pub const FREE: u32 = 0;
+pub const CLAIMED: u32 = 1;
+pub const ACTIVE: u32 = 2;
+
+/// Liveness of the consumer of a ring.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct ConsumerLease {
+    pub state: AtomicU32,
+    pub pid: AtomicU32,
+    pub pid_ns: AtomicU64, // PID namespace of `pid`, 0 if unknown
+    pub next_id: AtomicU64,
+    pub id: AtomicU64,        // Identifies the current holder, 0 once it is gone
+    pub heartbeat: AtomicU64, // Milliseconds since the UNIX epoch
+}
+
+impl ConsumerLease {
+    /// Takes the lease for the calling process and returns the id that
+    /// identifies it as the holder. Fails with [`Error::Full`] if the lease
+    /// is held, or being taken, by a live consumer.
+    fn claim(&self) -> Result<u64> {
+        loop {
+            match self
+                .state
+                .compare_exchange(FREE, CLAIMED, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => break,
+                Err(ACTIVE) if self.reap() => continue,
+                Err(_) => return Err(Error::Full(1)),
+            }
+        }
+        let id = self.next_id.fetch_add(1, Ordering::AcqRel) + 1;
+        self.pid.store(std::process::id(), Ordering::Release);
+        self.pid_ns.store(pid_namespace(), Ordering::Release);
+        self.heartbeat.store(now_millis(), Ordering::Release);
+        self.id.store(id, Ordering::Release);
+        self.state.store(ACTIVE, Ordering::Release);
+        Ok(id)
+    }
+
+    /// Frees the lease if its holder is dead or its lease expired. Returns
+    /// whether the lease was freed, by us or meanwhile.
+    fn reap(&self) -> bool {
+        let id = self.id.load(Ordering::Acquire);
+        if id == 0 {
+            // Left or reaped, and about to be free
+            return true;
+        }
+        let pid = self.pid.load(Ordering::Acquire);
+        let expired = expired(self.heartbeat.load(Ordering::Acquire));
+        if !expired && !process_gone(pid, self.pid_ns.load(Ordering::Acquire)) {
+            return false;
+        }
+        // Ids are never reused, so this fails if the lease changed hands
+        // since we checked it
+        if self
+            .id
+            .compare_exchange(id, 0, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            log::warn!("Taking over from spsc consumer (pid {pid}), lease expired: {expired}");
+            self.state.store(FREE, Ordering::Release);
+        }
+        true
+    }
+
+    /// Whether `id` still holds the lease.
+    fn is_held(&self, id: u64) -> bool {
+        self.id.load(Ordering::Acquire) == id
+    }
+
+    /// Refreshes the heartbeat of `id`. Returns `false` once the lease was
+    /// taken over.
+    fn beat(&self, id: u64) -> bool {
+        if !self.is_held(id) {
+            return false;
+        }
+        self.heartbeat.store(now_millis(), Ordering::Release);
+        true
+    }
+
+    /// Gives the lease back, unless it was taken over.
+    fn release(&self, id: u64) {
+        if self
+            .id
+            .compare_exchange(id, 0, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            self.state.store(FREE, Ordering::Release);
+        }
+    }
+}
+
+// Shared data, followed by `slot_count` slots of `slot_stride()` bytes
+#[repr(C)]
+pub struct SharedData {
+    pub header: Header,
+    pub slot_count: u64,
+    pub consumer: ConsumerLease,
+    pub head: CachePadded<AtomicU64>,
+    pub tail: CachePadded<AtomicU64>,
+    pub not_empty: Event, // Notified when a sample is published
+    pub not_full: Event,  // Notified when a slot is freed
+}
+
+impl SharedData {
+    /// Distance between two consecutive slots, padded to a cache line.
+    pub fn slot_stride(&self) -> usize {
+        slot_stride(self.header.capacity as usize)
+    }
+}
+
+/// Saturates rather than overflowing on the capacity read from a header.
+fn slot_stride(slot_size: usize) -> usize {
+    SLOT_HEADER
+        .saturating_add(slot_size)
+        .checked_next_multiple_of(64)
+        .unwrap_or(usize::MAX)
+}
+
+unsafe impl Layout for SharedData {
+    const PROTOCOL: Protocol = Protocol::Spsc;
+    const VERSION: u32 = 10;
+
+    fn tail_len(&self) -> usize {
+        (self.slot_count as usize).saturating_mul(self.slot_stride())
+    }
+
+    fn slot_count(&self) -> usize {
+        self.slot_count as usize
+    }
+}
+
+impl Report for SharedData {}
+
+/// Pointers to the length and payload of the slot holding `seq`.
+pub(crate) fn slot(shared: &Segment<SharedData>, seq: u64) -> (*mut u64, *mut u8) {
+    let index = (seq % shared.slot_count) as usize;
+    // SAFETY: `index < slot_count`, so the slot lies within the tail.
+    unsafe {
+        let base = shared.tail().add(index * shared.slot_stride());
+        (base as *mut u64, base.add(SLOT_HEADER))
+    }
+}
+
+fn allocate(
+    slot_count: usize,
+    slot_size: usize,
+    message: MessageType,
+) -> Result<Segment<SharedData>> {
+    if slot_count == 0 {
+        return Err(Error::Alloc("a ring needs at least one slot".to_string()));
+    }
+    let tail = slot_count
+        .checked_mul(slot_stride(slot_size))
+        .ok_or_else(|| {
+            Error::Alloc(format!(
+                "{slot_count} slots of {slot_size} bytes are too large"
+            ))
+        })?;
+    Segment::<SharedData>::create_with(slot_size, tail, |shared| {
+        shared.header.message = message;
+        shared.slot_count = slot_count as u64;
+    })
+}
+
+pub struct Producer {
+    shared: Segment<SharedData>,
+    _served: Served,
+    head: u64,
+    tail: u64, // Last observed consumer position
+}
+
+impl Producer {
+    /// Allocates a ring of `slot_count` slots holding up to `slot_size`
+    /// bytes each and serves it on `key_expr`.
+    pub fn new(
+        session: &Session,
+        key_expr: &str,
+        slot_count: usize,
+        slot_size: usize,
+    ) -> Result<Self> {
+        Self::with_message(session, key_expr, slot_count, slot_size, MessageType::BYTES)
+    }
+
+    /// Allocates a ring of `slot_count` slots holding one `M` message each
+    /// and serves it on `key_expr`.
+    pub fn typed<M: ShmPod>(
+        session: &Session,
+        key_expr: &str,
+        slot_count: usize,
+    ) -> Result<pod::Producer<Self, M>> {
+        let producer = Self::with_message(
+            session,
+            key_expr,
+            slot_count,
+            std::mem::size_of::<M>(),
+            MessageType::of::<M>(),
+        )?;
+        pod::Producer::new(producer)
+    }
+
+    /// Same as [`Producer::new`], but records the type of the messages
+    /// carried in the header, see [`crate::pod`] and [`crate::arena`].
+    pub fn with_message(
+        session: &Session,
+        key_expr: &str,
+        slot_count: usize,
+        slot_size: usize,
+        message: MessageType,
+    ) -> Result<Self> {
+        let shared = allocate(slot_count, slot_size, message)?;
+        let _served = segment::serve(session, key_expr, &shared)?;
+        Ok(Producer {
+            shared,
+            _served,
+            head: 0,
+            tail: 0,
+        })
+    }
+
+    /// Payload capacity of each slot.
+    pub fn slot_size(&self) -> usize {
+        self.shared.header.capacity as usize
+    }
+
+    /// Number of samples published but not yet consumed.
+    pub fn len(&self) -> usize {
+        (self.head - self.shared.tail.load(Ordering::Acquire)) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes a sample into the next slot if the ring is not full. `f` fills
+    /// the slot and returns the number of bytes written. Returns the
+    /// sequence number of the new sample, or `None` if the ring is full.
+    pub fn try_publish_with<F>(&mut self, f: F) -> Result<Option<u64>>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        if self.is_full() {
+            return Ok(None);
+        }
+        self.fill(f).map(Some)
+    }
+
+    /// Waits for a free slot and copies `data` into it. Fails with
+    /// [`Error::TooLarge`] if `data` exceeds the slot size.
+    pub fn publish(&mut self, deadline: &Deadline, data: &[u8]) -> Result<u64> {
+        check_len(data.len(), self.slot_size())?;
+        self.publish_with(deadline, |buf| {
+            buf[..data.len()].copy_from_slice(data);
+            data.len()
+        })
+    }
+
+    /// Waits for a free slot and writes a sample into it. Fails with
+    /// [`Error::TooLarge`], leaving the slot free, if `f` returns more than
+    /// the slot size.
+    pub fn publish_with<F>(&mut self, deadline: &Deadline, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        let mut blocked = Stopwatch::new();
+        loop {
+            let seen = self.shared.not_full.generation();
+            if !self.is_full() {
+                self.shared.header.stats.blocked(&blocked);
+                return self.fill(f);
+            }
+            blocked.start();
+            deadline.wait(&self.shared.not_full, seen)?;
+        }
+    }
+
+    fn is_full(&mut self) -> bool {
+        if self.head - self.tail < self.shared.slot_count {
+            return false;
+        }
+        self.tail = self.shared.tail.load(Ordering::Acquire);
+        self.head - self.tail == self.shared.slot_count
+    }
+
+    fn fill<F>(&mut self, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        let slot_size = self.slot_size();
+        let (len, data) = slot(&self.shared, self.head);
+        // SAFETY: the slot is free: the consumer is done with sequence
+        // `head - slot_count` and does not read `head` until we publish it.
+        let written = f(unsafe { std::slice::from_raw_parts_mut(data, slot_size) });
+        check_len(written, slot_size)?;
+        unsafe { len.write(written as u64) };
+        self.shared.header.stats.published();
+        self.head += 1;
+        self.shared.head.store(self.head, Ordering::Release);
+        self.shared.not_empty.notify_one();
+        Ok(self.head)
+    }
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.shared.header.producer.close();
+        self.shared.not_empty.notify_all();
+    }
+}
+
+pub struct Consumer {
+    shared: Segment<SharedData>,
+    id: u64, // Holder of the consumer lease
+    tail: u64,
+    head: u64, // Last observed producer position
+    _heartbeat: Heartbeat,
+}
+
+impl Consumer {
+    /// Fetches the ring served on `key_expr`.
+    pub fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        Self::open(segment::fetch(session, key_expr)?)
+    }
+
+    /// Attaches to a channel found by [`crate::discovery`].
+    pub fn from_channel(channel: &Channel) -> Result<Self> {
+        Self::open(channel.payload().clone())
+    }
+
+    fn open(payload: ZBytes) -> Result<Self> {
+        Self::claim(Segment::attach(payload)?)
+    }
+
+    /// Takes the consumer lease of a mapped ring.
+    fn claim(shared: Segment<SharedData>) -> Result<Self> {
+        if shared.slot_count == 0 {
+            return Err(LayoutError::Field {
+                name: "slot_count",
+                value: 0,
+            }
+            .into());
+        }
+        let id = shared.consumer.claim()?;
+        let _heartbeat = Heartbeat::start(shared.clone(), move |shared: &SharedData| {
+            shared.consumer.beat(id)
+        });
+        let tail = shared.tail.load(Ordering::Acquire);
+        Ok(Consumer {
+            shared,
+            id,
+            tail,
+            head: tail,
+            _heartbeat,
+        })
+    }
+
+    /// Payload capacity of each slot.
+    pub fn slot_size(&self) -> usize {
+        self.shared.header.capacity as usize
+    }
+
+    /// Whether the producer is still alive and has not closed the channel,
+    /// and this consumer still holds the lease.
+    pub fn is_connected(&self) -> bool {
+        !self.shared.header.producer.is_gone() && self.shared.consumer.is_held(self.id)
+    }
+
+    /// Hands the oldest unread sample and its sequence number to `f`, then
+    /// frees its slot. Returns `None` if the ring is empty, or if another
+    /// consumer took the lease over, which [`Consumer::is_connected`] tells.
+    pub fn try_recv_with<F, R>(&mut self, f: F) -> Option<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        if !self.shared.consumer.is_held(self.id) || self.is_empty() {
+            return None;
+        }
+        Some(self.consume(f))
+    }
+
+    /// Waits for a sample and hands it to `f`. Fails with
+    /// [`Error::Disconnected`] once the producer is gone and the ring is
+    /// empty, and with [`Error::Evicted`] once another consumer took the
+    /// lease over.
+    pub fn recv_with<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let mut waited = Stopwatch::new();
+        loop {
+            let seen = self.shared.not_empty.generation();
+            let gone = self.shared.header.producer.is_gone();
+            if !self.shared.consumer.is_held(self.id) {
+                return Err(Error::Evicted);
+            }
+            if !self.is_empty() {
+                self.shared.header.stats.waited(&waited);
+                return Ok(self.consume(f));
+            }
+            if gone {
+                return Err(Error::Disconnected);
+            }
+            waited.start();
+            deadline.wait_at_most(&self.shared.not_empty, seen, CHECK_INTERVAL)?;
+        }
+    }
+
+    fn is_empty(&mut self) -> bool {
+        if self.tail < self.head {
+            return false;
+        }
+        self.head = self.shared.head.load(Ordering::Acquire);
+        self.tail == self.head
+    }
+
+    fn consume<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let (len, data) = slot(&self.shared, self.tail);
+        // SAFETY: the producer published this slot (Acquire on `head`) and
+        // does not reuse it until we advance `tail`. The length is clamped in
+        // case the producer misbehaves.
+        let r = unsafe {
+            let len = (len.read() as usize).min(self.slot_size());
+            f(self.tail + 1, std::slice::from_raw_parts(data, len))
+        };
+        self.shared.header.stats.read();
+        self.tail += 1;
+        self.shared.tail.store(self.tail, Ordering::Release);
+        self.shared.not_full.notify_one();
+        r
+    }
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        // Lets another consumer attach
+        self.shared.consumer.release(self.id);
+    }
+}
+
+impl RawProducer for Producer {
+    type Published = u64;
+
+    fn header(&self) -> &Header {
+        &self.shared.header
+    }
+
+    fn publish_raw<F>(&mut self, deadline: &Deadline, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        self.publish_with(deadline, f)
+    }
+}
+
+impl RawConsumer for Consumer {
+    type Shared = SharedData;
+
+    fn attach(session: &Session, key_expr: &str) -> Result<Self> {
+        Consumer::attach(session, key_expr)
+    }
+
+    fn open(payload: ZBytes) -> Result<Self> {
+        Consumer::open(payload)
+    }
+
+    fn header(&self) -> &Header {
+        &self.shared.header
+    }
+
+    fn recv_raw<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        self.recv_with(deadline, f)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Producer {
+    /// Async version of [`Producer::new`].
+    pub async fn new_async(
+        session: &Session,
+        key_expr: &str,
+        slot_count: usize,
+        slot_size: usize,
+    ) -> Result<Self> {
+        Self::with_message_async(session, key_expr, slot_count, slot_size, MessageType::BYTES).await
+    }
+
+    /// Async version of [`Producer::with_message`].
+    pub async fn with_message_async(
+        session: &Session,
+        key_expr: &str,
+        slot_count: usize,
+        slot_size: usize,
+        message: MessageType,
+    ) -> Result<Self> {
+        let shared = allocate(slot_count, slot_size, message)?;
+        let _served = bridge::serve(session, key_expr, &shared).await?;
+        Ok(Producer {
+            shared,
+            _served,
+            head: 0,
+            tail: 0,
+        })
+    }
+
+    /// Waits for a free slot, after which [`Producer::try_publish_with`]
+    /// succeeds.
+    pub async fn reserve(&mut self, deadline: &Deadline) -> Result<()> {
+        let mut blocked = Stopwatch::new();
+        loop {
+            let seen = self.shared.not_full.generation();
+            if !self.is_full() {
+                self.shared.header.stats.blocked(&blocked);
+                return Ok(());
+            }
+            blocked.start();
+            bridge::wait(&self.shared, |s| &s.not_full, seen, deadline).await?;
+        }
+    }
+
+    /// Async version of [`Producer::publish_with`].
+    pub async fn publish_async<F>(&mut self, deadline: &Deadline, f: F) -> Result<u64>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        self.reserve(deadline).await?;
+        self.fill(f)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Consumer {
+    /// Async version of [`Consumer::attach`].
+    pub async fn attach_async(session: &Session, key_expr: &str) -> Result<Self> {
+        Self::open(bridge::fetch(session, key_expr).await?)
+    }
+
+    /// Async version of [`Consumer::recv_with`].
+    pub async fn recv_async<F, R>(&mut self, deadline: &Deadline, f: F) -> Result<R>
+    where
+        F: FnOnce(u64, &[u8]) -> R,
+    {
+        let mut waited = Stopwatch::new();
+        loop {
+            let seen = self.shared.not_empty.generation();
+            let gone = self.shared.header.producer.is_gone();
+            if !self.shared.consumer.is_held(self.id) {
+                return Err(Error::Evicted);
+            }
+            if !self.is_empty() {
+                self.shared.header.stats.waited(&waited);
+                return Ok(self.consume(f));
+            }
+            if gone {
+                return Err(Error::Disconnected);
+            }
+            waited.start();
+            bridge::wait(&self.shared, |s| &s.not_empty, seen, deadline).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(slot_count: usize) -> Segment<SharedData> {
+        let tail = slot_count * slot_stride(8);
+        Segment::local_with(8, tail, |shared: &mut SharedData| {
+            shared.slot_count = slot_count as u64;
+        })
+    }
+
+    #[test]
+    fn second_consumer_is_refused_until_first_is_dropped() {
+        let shared = local(4);
+        let first = Consumer::claim(shared.clone()).unwrap();
+        assert!(matches!(
+            Consumer::claim(shared.clone()),
+            Err(Error::Full(1))
+        ));
+        drop(first);
+        let second = Consumer::claim(shared.clone()).unwrap();
+        assert!(second.shared.consumer.is_held(second.id));
+        assert!(matches!(
+            Consumer::claim(shared.clone()),
+            Err(Error::Full(1))
+        ));
+    }
+
+    #[test]
+    fn expired_lease_is_taken_over() {
+        let shared = local(4);
+        let mut stale = Consumer::claim(shared.clone()).unwrap();
+        // As if the consumer had stopped beating, e.g. in a debugger
+        shared.consumer.heartbeat.store(0, Ordering::Release);
+        let mut consumer = Consumer::claim(shared.clone()).unwrap();
+
+        shared.head.store(1, Ordering::Release);
+        assert_eq!(stale.try_recv_with(|sn, _| sn), None);
+        assert!(matches!(
+            stale.recv_with(&Deadline::never(), |sn, _| sn),
+            Err(Error::Evicted)
+        ));
+        assert_eq!(consumer.try_recv_with(|sn, _| sn), Some(1));
+
+        // Dropping the stale consumer leaves the new one's lease alone
+        drop(stale);
+        assert!(consumer.shared.consumer.is_held(consumer.id));
+        assert!(matches!(
+            Consumer::claim(shared.clone()),
+            Err(Error::Full(1))
+        ));
+    }
+}
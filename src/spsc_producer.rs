@@ -0,0 +1,51 @@
+use rand::random;
+use zshm::Deadline;
+use zshm::cli::{Args, Defaults};
+use zshm::spsc::Producer;
+
+const SLOT_COUNT: usize = 16;
+
+fn main() {
+    let args = Args::parse(
+        "SPSC ring producer",
+        Defaults {
+            key_expr: "shm/spsc/buffer",
+            rate: 10.0,
+            ..Default::default()
+        },
+    );
+
+    let z = args.open().expect("Failed to open Zenoh session");
+
+    let mut producer = Producer::new(&z, &args.key_expr, SLOT_COUNT, args.size)
+        .expect("Failed to create producer");
+
+    // producer loop
+    for n in 1.. {
+        let mut sum: usize = 0;
+        let mut len = 0;
+        let sn = producer
+            .publish_with(&Deadline::never(), |data| {
+                len = args.payload_len();
+                for b in &mut data[..len] {
+                    *b = random();
+                    sum += *b as usize;
+                }
+                len
+            })
+            .expect("Failed to publish");
+        println!(
+            "{} - Produced buffer of {} bytes with sum of {} ({} in flight)",
+            sn,
+            len,
+            sum,
+            producer.len()
+        );
+        if args.done(n) {
+            break;
+        }
+        if let Some(period) = args.period() {
+            std::thread::sleep(period);
+        }
+    }
+}
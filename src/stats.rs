@@ -0,0 +1,231 @@
+//! Counters kept in shared memory by every channel, and the queryable that
+//! serves them.
+//!
+//! Producers and consumers update the [`Counters`] in the segment header as
+//! they go: samples published, read and skipped, and the time spent waiting
+//! for room or for samples. Consumers that register in the segment, those of
+//! the 1:N mailboxes and of [`crate::broadcast`], also keep
+//! [`ConsumerCounters`] of their own. Every producer answers queries on
+//! [`stats_key`] with a [`Stats`] snapshot rendered as JSON, so that
+//! monitoring tools need neither map the segment nor link this crate.
+
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use zenoh::Wait;
+use zenoh::query::Query;
+
+use crate::header::{Layout, Protocol};
+use crate::segment::Segment;
+
+/// Key expression on which the statistics of the channel served on
+/// `key_expr` are answered.
+pub fn stats_key(key_expr: &str) -> String {
+    format!("{key_expr}/@stats")
+}
+
+/// Counters of a channel, stored in its header.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub produced: AtomicU64,
+    pub consumed: AtomicU64,      // Summed over consumers
+    pub skipped: AtomicU64,       // Overwritten before being read, summed over consumers
+    pub blocked_nanos: AtomicU64, // Producers waiting for room
+    pub waited_nanos: AtomicU64,  // Consumers waiting for samples, summed
+}
+
+impl Counters {
+    pub(crate) fn published(&self) {
+        self.produced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts the time a producer waited before publishing.
+    pub(crate) fn blocked(&self, stopwatch: &Stopwatch) {
+        stopwatch.add_to(&self.blocked_nanos);
+    }
+
+    pub(crate) fn read(&self) {
+        self.consumed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn skip(&self, samples: u64) {
+        self.skipped.fetch_add(samples, Ordering::Relaxed);
+    }
+
+    /// Counts the time a consumer waited before receiving a sample.
+    pub(crate) fn waited(&self, stopwatch: &Stopwatch) {
+        stopwatch.add_to(&self.waited_nanos);
+    }
+}
+
+/// Counters of a registered consumer, which also update those of its
+/// channel.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct ConsumerCounters {
+    pub consumed: AtomicU64,
+    pub skipped: AtomicU64,
+    pub waited_nanos: AtomicU64,
+}
+
+impl ConsumerCounters {
+    pub(crate) fn read(&self, channel: &Counters) {
+        self.consumed.fetch_add(1, Ordering::Relaxed);
+        channel.read();
+    }
+
+    pub(crate) fn skip(&self, channel: &Counters, samples: u64) {
+        self.skipped.fetch_add(samples, Ordering::Relaxed);
+        channel.skip(samples);
+    }
+
+    pub(crate) fn waited(&self, channel: &Counters, stopwatch: &Stopwatch) {
+        stopwatch.add_to(&self.waited_nanos);
+        channel.waited(stopwatch);
+    }
+
+    /// Starts over for a new consumer.
+    pub(crate) fn reset(&self) {
+        self.consumed.store(0, Ordering::Relaxed);
+        self.skipped.store(0, Ordering::Relaxed);
+        self.waited_nanos.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Measures a wait from the first time it had to be started, so that
+/// operations that do not wait read no clock.
+#[derive(Debug, Default)]
+pub(crate) struct Stopwatch(Option<Instant>);
+
+impl Stopwatch {
+    pub(crate) fn new() -> Self {
+        Stopwatch(None)
+    }
+
+    pub(crate) fn start(&mut self) {
+        self.0.get_or_insert_with(Instant::now);
+    }
+
+    fn add_to(&self, nanos: &AtomicU64) {
+        if let Some(start) = self.0 {
+            nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Statistics of a registered consumer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConsumerStats {
+    pub pid: u32,
+    pub consumed: u64,
+    pub skipped: u64,
+    pub waited: Duration,
+    /// Samples published that the consumer has yet to read.
+    pub lag: u64,
+}
+
+impl ConsumerStats {
+    pub(crate) fn new(pid: u32, counters: &ConsumerCounters, lag: u64) -> Self {
+        ConsumerStats {
+            pid,
+            consumed: counters.consumed.load(Ordering::Relaxed),
+            skipped: counters.skipped.load(Ordering::Relaxed),
+            waited: Duration::from_nanos(counters.waited_nanos.load(Ordering::Relaxed)),
+            lag,
+        }
+    }
+}
+
+/// A snapshot of the counters of a channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stats {
+    pub protocol: Protocol,
+    pub produced: u64,
+    pub consumed: u64,
+    pub skipped: u64,
+    pub producer_blocked: Duration,
+    pub consumer_waited: Duration,
+    /// Largest lag of the registered consumers or, on channels whose
+    /// consumers do not register, samples neither read nor skipped yet.
+    pub lag: u64,
+    pub consumers: Vec<ConsumerStats>,
+}
+
+impl Stats {
+    /// Reads the counters of the channel in `shared`.
+    pub(crate) fn of<T: Report>(shared: &Segment<T>) -> Self {
+        let counters = &shared.header().stats;
+        let produced = counters.produced.load(Ordering::Relaxed);
+        let consumed = counters.consumed.load(Ordering::Relaxed);
+        let skipped = counters.skipped.load(Ordering::Relaxed);
+        let consumers = T::consumers(shared);
+        let lag = match consumers.iter().map(|c| c.lag).max() {
+            Some(lag) => lag,
+            None => produced.saturating_sub(consumed + skipped),
+        };
+        Stats {
+            protocol: T::PROTOCOL,
+            produced,
+            consumed,
+            skipped,
+            producer_blocked: Duration::from_nanos(counters.blocked_nanos.load(Ordering::Relaxed)),
+            consumer_waited: Duration::from_nanos(counters.waited_nanos.load(Ordering::Relaxed)),
+            lag,
+            consumers,
+        }
+    }
+
+    /// Renders the snapshot as a JSON object, durations in nanoseconds.
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"protocol\":\"{}\",\"produced\":{},\"consumed\":{},\"skipped\":{},\
+             \"producer_blocked_ns\":{},\"consumer_waited_ns\":{},\"lag\":{},\"consumers\":[",
+            self.protocol,
+            self.produced,
+            self.consumed,
+            self.skipped,
+            self.producer_blocked.as_nanos(),
+            self.consumer_waited.as_nanos(),
+            self.lag,
+        );
+        for (i, c) in self.consumers.iter().enumerate() {
+            let comma = if i == 0 { "" } else { "," };
+            let _ = write!(
+                json,
+                "{comma}{{\"pid\":{},\"consumed\":{},\"skipped\":{},\"waited_ns\":{},\"lag\":{}}}",
+                c.pid,
+                c.consumed,
+                c.skipped,
+                c.waited.as_nanos(),
+                c.lag,
+            );
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+/// What a layout knows about its consumers beyond the header counters.
+pub(crate) trait Report: Layout + Sized {
+    /// Statistics of each registered consumer, none by default.
+    fn consumers(_shared: &Segment<Self>) -> Vec<ConsumerStats> {
+        Vec::new()
+    }
+}
+
+/// Replies to a query on [`stats_key`] with a snapshot of the channel.
+pub(crate) fn replier<T: Report + 'static>(
+    key_expr: &str,
+    segment: &Segment<T>,
+) -> impl Fn(Query) + Send + Sync + 'static {
+    let key = stats_key(key_expr);
+    let shared = segment.clone();
+    move |query| {
+        let json = Stats::of(&shared).to_json();
+        if let Err(e) = query.reply(&key, json).wait() {
+            log::warn!("Failed to reply to query on {key}: {e}");
+        }
+    }
+}
@@ -1,13 +1,20 @@
 use zenoh::Wait;
+use zshm::cli::{Args, Defaults};
 fn main() {
-    let z = zenoh::open(zenoh::Config::default())
-        .wait()
-        .expect("Failed to open Zenoh session");    
+    let args = Args::parse(
+        "Prints the strings put by put_shm",
+        Defaults {
+            key_expr: "zenoh/shm/buffer",
+            ..Default::default()
+        },
+    );
+    let z = args.open().expect("Failed to open Zenoh session");
 
-    let sub = z.declare_subscriber("zenoh/shm/buffer")
+    let sub = z.declare_subscriber(&args.key_expr)
         .wait()
         .expect("Failed to declare subscriber");
 
+    let mut received = 0;
     while let Ok(s) = sub.recv() {
         let buf = s.payload();
         
@@ -16,5 +23,9 @@ fn main() {
         buf.try_to_string()
             .map(|s| println!("Received (SHM: {is_shm}): {s}"))
             .unwrap_or_else(|_| println!("Received non-string payload"));                       
+        received += 1;
+        if args.done(received) {
+            break;
+        }
     }
 }
\ No newline at end of file
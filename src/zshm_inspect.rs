@@ -0,0 +1,78 @@
+use std::fmt::Write as _;
+use std::time::Duration;
+
+use clap::{Arg, ArgAction, Command, value_parser};
+
+use zshm::cli::SessionArgs;
+use zshm::discovery::{self, QUERY_TIMEOUT};
+use zshm::inspect::Snapshot;
+
+fn options(command: Command) -> Command {
+    command
+        .arg(
+            Arg::new("watch")
+                .short('w')
+                .long("watch")
+                .action(ArgAction::SetTrue)
+                .help("Refresh continuously until interrupted"),
+        )
+        .arg(
+            Arg::new("interval")
+                .long("interval")
+                .value_name("MS")
+                .value_parser(value_parser!(u64))
+                .default_value("500")
+                .help("Time between two refreshes with --watch"),
+        )
+        .arg(
+            Arg::new("bytes")
+                .long("bytes")
+                .value_name("N")
+                .value_parser(value_parser!(usize))
+                .default_value("256")
+                .help("Bytes of the current payload to dump"),
+        )
+}
+
+fn main() {
+    let (args, m) = SessionArgs::parse_with(
+        "Dumps the state of the segments served on a key expression, without registering as a consumer",
+        "shm/**",
+        options,
+    );
+    let watch = m.get_flag("watch");
+    let interval = Duration::from_millis(*m.get_one::<u64>("interval").unwrap());
+    let bytes = *m.get_one::<usize>("bytes").unwrap();
+
+    let z = args.open().expect("Failed to open Zenoh session");
+
+    loop {
+        // Queried again on every refresh, to follow producers that restart
+        let channels = match discovery::list(&z, &args.key_expr, QUERY_TIMEOUT) {
+            Ok(channels) => channels,
+            Err(e) => {
+                println!("Failed to query '{}': {e}", args.key_expr);
+                return;
+            }
+        };
+        let mut out = String::new();
+        if watch {
+            // Clears the terminal
+            out.push_str("\x1b[2J\x1b[H");
+        }
+        if channels.is_empty() {
+            let _ = writeln!(out, "No channel found on '{}'", args.key_expr);
+        }
+        for channel in &channels {
+            let _ = match Snapshot::of(channel, bytes) {
+                Ok(snapshot) => writeln!(out, "{snapshot}"),
+                Err(e) => writeln!(out, "{channel}\n  cannot inspect: {e}\n"),
+            };
+        }
+        print!("{out}");
+        if !watch {
+            break;
+        }
+        std::thread::sleep(interval);
+    }
+}
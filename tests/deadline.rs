@@ -0,0 +1,59 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use zshm::notify::Event;
+use zshm::{CancellationToken, Deadline, Error};
+
+#[test]
+fn wait_times_out() {
+    let event = Event::new();
+    let deadline = Deadline::after(Duration::from_millis(50));
+    let start = Instant::now();
+    assert!(matches!(
+        deadline.wait(&event, event.generation()),
+        Err(Error::Timeout)
+    ));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+}
+
+#[test]
+fn sleep_stops_at_deadline() {
+    let deadline = Deadline::after(Duration::from_millis(20));
+    let start = Instant::now();
+    let result = loop {
+        if let Err(e) = deadline.sleep(Duration::from_secs(1)) {
+            break e;
+        }
+    };
+    assert!(matches!(result, Error::Timeout));
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn cancel_wakes_blocked_waiter() {
+    let event = Arc::new(Event::new());
+    let token = CancellationToken::new();
+    let deadline = Deadline::from(token.clone());
+
+    let waiter = {
+        let event = event.clone();
+        std::thread::spawn(move || deadline.wait(&event, event.generation()))
+    };
+    while event.waiters() == 0 {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    token.cancel();
+    assert!(matches!(waiter.join().unwrap(), Err(Error::Cancelled)));
+}
+
+#[test]
+fn cancelled_token_fails_immediately() {
+    let token = CancellationToken::new();
+    token.cancel();
+    let deadline = Deadline::never().cancellable(token);
+    assert!(matches!(deadline.check(), Err(Error::Cancelled)));
+    assert!(matches!(
+        deadline.sleep(Duration::from_secs(10)),
+        Err(Error::Cancelled)
+    ));
+}
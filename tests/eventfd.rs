@@ -0,0 +1,54 @@
+#![cfg(target_os = "linux")]
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use zshm::eventfd::EventFd;
+use zshm::{Deadline, Error};
+
+#[test]
+fn notification_persists_until_drained() {
+    let fd = EventFd::new().unwrap();
+    assert!(!fd.drain());
+    fd.notify();
+    fd.notify();
+    // Notified before waiting, so it must not block
+    assert!(fd.wait_timeout(Duration::from_secs(5)));
+    assert!(fd.drain());
+    assert!(!fd.drain());
+}
+
+#[test]
+fn wait_timeout_expires_without_notification() {
+    let fd = EventFd::new().unwrap();
+    let start = Instant::now();
+    assert!(!fd.wait_timeout(Duration::from_millis(50)));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+}
+
+#[test]
+fn notify_wakes_a_waiting_thread() {
+    let fd = Arc::new(EventFd::new().unwrap());
+    let f = fd.clone();
+    let waiter = std::thread::spawn(move || f.wait_timeout(Duration::from_secs(5)));
+    std::thread::sleep(Duration::from_millis(20));
+    fd.notify();
+    assert!(waiter.join().unwrap());
+}
+
+#[test]
+fn wait_at_most_stops_at_deadline() {
+    let fd = EventFd::new().unwrap();
+    let deadline = Deadline::after(Duration::from_millis(50));
+    let start = Instant::now();
+    assert!(matches!(
+        fd.wait_at_most(&deadline, Duration::from_secs(5)),
+        Err(Error::Timeout)
+    ));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    // Without a deadline, the interval bounds the wait
+    assert!(
+        fd.wait_at_most(&Deadline::never(), Duration::from_millis(10))
+            .is_ok()
+    );
+}
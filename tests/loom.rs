@@ -0,0 +1,266 @@
+//! Model checks of the 1:N consumers table, and of the cursors of broadcast
+//! rings, under every interleaving loom explores. Run with
+//! `RUSTFLAGS="--cfg loom" cargo test --release --test loom`.
+//!
+//! Each model of the table ends with every remaining credit returned, and
+//! checks that the mailbox was handed back exactly once and that no credit
+//! leaked. Those of the ring check that no slot is read while written.
+
+#![cfg(loom)]
+
+use loom::cell::UnsafeCell;
+use loom::sync::Arc;
+use loom::sync::atomic::{AtomicU64, Ordering};
+use loom::thread;
+
+use zshm::CachePadded;
+use zshm::broadcast::{Cursor, Policy, Ring};
+use zshm::consumers::{Consumers, MAX_CONSUMERS, Membership};
+use zshm::stats::Counters;
+
+fn model(f: impl Fn() + Sync + Send + 'static) {
+    let mut builder = loom::model::Builder::new();
+    builder.preemption_bound.get_or_insert(3);
+    builder.check(f);
+}
+
+/// Returns the credit of `m` if it holds one, as a consumer reading would.
+fn drain(consumers: &Consumers, m: Membership) -> bool {
+    consumers.owed(m).is_some_and(|sn| consumers.release(m, sn))
+}
+
+fn assert_settled(consumers: &Consumers, last: &[bool]) {
+    let handed_back = last.iter().filter(|&&last| last).count();
+    assert_eq!(handed_back, 1, "mailbox handed back {handed_back} times");
+    assert_eq!(consumers.read_count.load(Ordering::Acquire), 0);
+    for slot in &consumers.slots {
+        assert_eq!(slot.owed.load(Ordering::Acquire), 0, "stray credit");
+    }
+}
+
+#[test]
+fn readers_race_the_producer() {
+    model(|| {
+        let consumers = Arc::new(Consumers::default());
+        let members: Vec<_> = (0..MAX_CONSUMERS)
+            .map(|_| consumers.join().unwrap())
+            .collect();
+        let readers: Vec<_> = members
+            .iter()
+            .map(|&m| {
+                let c = consumers.clone();
+                thread::spawn(move || drain(&c, m))
+            })
+            .collect();
+        let mut last = vec![consumers.assign(1)];
+        last.extend(readers.into_iter().map(|t| t.join().unwrap()));
+        last.extend(members.iter().map(|&m| drain(&consumers, m)));
+        assert_settled(&consumers, &last);
+    });
+}
+
+#[test]
+fn join_during_assign() {
+    model(|| {
+        let consumers = Arc::new(Consumers::default());
+        let existing = consumers.join().unwrap();
+        let c = consumers.clone();
+        let joining = thread::spawn(move || {
+            let m = c.join().unwrap();
+            (m, drain(&c, m))
+        });
+        let mut last = vec![consumers.assign(1)];
+        let (joined, read) = joining.join().unwrap();
+        last.push(read);
+        // The newcomer may or may not get a credit, but cannot take the
+        // existing consumer's
+        assert_eq!(consumers.owed(existing), Some(1));
+        last.push(drain(&consumers, existing));
+        last.push(drain(&consumers, joined));
+        assert_settled(&consumers, &last);
+    });
+}
+
+#[test]
+fn join_gets_its_first_sample() {
+    model(|| {
+        let consumers = Arc::new(Consumers::default());
+        let existing = consumers.join().unwrap();
+        let c = consumers.clone();
+        let joining = thread::spawn(move || c.join().unwrap());
+        let mut last = vec![consumers.assign(1)];
+        let joined = joining.join().unwrap();
+        // Whichever sample the newcomer starts from, it is given a credit on it
+        match consumers.first(joined) {
+            1 => assert_eq!(consumers.owed(joined), Some(1)),
+            first => {
+                assert_eq!(first, 2);
+                assert_eq!(consumers.owed(joined), None);
+            }
+        }
+        last.push(drain(&consumers, existing));
+        last.push(drain(&consumers, joined));
+        assert_settled(&consumers, &last);
+    });
+}
+
+#[test]
+fn join_takes_effect_from_next_sample() {
+    model(|| {
+        let consumers = Arc::new(Consumers::default());
+        let existing = consumers.join().unwrap();
+        assert!(!consumers.assign(1));
+        let c = consumers.clone();
+        // Joins while the first sample is still being read
+        let joining = thread::spawn(move || {
+            let m = c.join().unwrap();
+            assert_eq!(c.first(m), 2);
+            assert_eq!(c.owed(m), None);
+            m
+        });
+        let mut last = vec![drain(&consumers, existing)];
+        let joined = joining.join().unwrap();
+        assert_settled(&consumers, &last);
+        last = vec![consumers.assign(2)];
+        assert_eq!(consumers.owed(joined), Some(2));
+        last.push(drain(&consumers, existing));
+        last.push(drain(&consumers, joined));
+        assert_settled(&consumers, &last);
+    });
+}
+
+#[test]
+fn leave_during_assign() {
+    model(|| {
+        let consumers = Arc::new(Consumers::default());
+        let leaving = consumers.join().unwrap();
+        let staying = consumers.join().unwrap();
+        let c = consumers.clone();
+        let leaver = thread::spawn(move || c.leave(leaving));
+        let mut last = vec![consumers.assign(1)];
+        last.push(leaver.join().unwrap());
+        assert_eq!(consumers.owed(staying), Some(1));
+        last.push(drain(&consumers, staying));
+        assert_settled(&consumers, &last);
+        assert_eq!(consumers.active(), 1);
+    });
+}
+
+#[test]
+fn rejoin_during_assign() {
+    // The slot freed by the leaving consumer is taken over by the joining
+    // one while the producer hands out credits
+    model(|| {
+        let consumers = Arc::new(Consumers::default());
+        let leaving = consumers.join().unwrap();
+        let c = consumers.clone();
+        let rejoining = thread::spawn(move || {
+            let left = c.leave(leaving);
+            let m = c.join().unwrap();
+            let owed = c.owed(m);
+            (m, left, owed, owed.is_some_and(|sn| c.release(m, sn)))
+        });
+        let mut last = vec![consumers.assign(1)];
+        let (joined, left, owed, read) = rejoining.join().unwrap();
+        // The newcomer only reads the sample if it joined before it was
+        // given out, not on the credit of the previous owner, and then does
+        let late = consumers.owed(joined);
+        assert_eq!(
+            consumers.first(joined) == 1,
+            owed == Some(1) || late == Some(1)
+        );
+        last.extend([left, read, drain(&consumers, joined)]);
+        assert_settled(&consumers, &last);
+    });
+}
+
+#[test]
+fn reap_during_release() {
+    model(|| {
+        let consumers = Arc::new(Consumers::default());
+        let dead = consumers.join().unwrap();
+        let alive = consumers.join().unwrap();
+        let mut last = vec![consumers.assign(1)];
+        // Let the first lease expire while its consumer is still reading
+        consumers.slots[0].heartbeat.store(0, Ordering::Release);
+        let c = consumers.clone();
+        let reader = thread::spawn(move || drain(&c, dead));
+        last.push(consumers.reap());
+        last.push(reader.join().unwrap());
+        last.push(drain(&consumers, alive));
+        assert_settled(&consumers, &last);
+        assert_eq!(consumers.active(), 1);
+        // The reaped consumer can tell it was evicted
+        assert!(!consumers.is_member(dead));
+        assert!(consumers.is_member(alive));
+    });
+}
+
+#[test]
+fn reap_during_leave() {
+    model(|| {
+        let consumers = Arc::new(Consumers::default());
+        let dead = consumers.join().unwrap();
+        let mut last = vec![consumers.assign(1)];
+        consumers.slots[0].heartbeat.store(0, Ordering::Release);
+        let c = consumers.clone();
+        let leaver = thread::spawn(move || c.leave(dead));
+        last.push(consumers.reap());
+        last.push(leaver.join().unwrap());
+        assert_settled(&consumers, &last);
+        assert_eq!(consumers.active(), 0);
+    });
+}
+
+/// A broadcast ring of one slot, whose payload is the sequence number it
+/// holds, and a single cursor.
+#[derive(Default)]
+struct OneSlotRing {
+    head: AtomicU64,
+    next_id: AtomicU64,
+    cursors: [CachePadded<Cursor>; 1],
+    slot: UnsafeCell<u64>,
+    stats: Counters,
+}
+
+impl OneSlotRing {
+    fn ring(&self) -> Ring<'_> {
+        Ring {
+            head: &self.head,
+            next_id: &self.next_id,
+            cursors: &self.cursors,
+            slot_count: 1,
+        }
+    }
+
+    /// Writes sequence `head` once there is room for it, as a producer would.
+    fn fill(&self, head: u64, policy: Policy) {
+        while !self.ring().make_room(head, policy, &self.stats) {
+            thread::yield_now();
+        }
+        self.slot.with_mut(|slot| unsafe { *slot = head });
+        self.ring().publish(head + 1);
+    }
+}
+
+#[test]
+fn broadcast_join_during_fill() {
+    // The producer overwrites the only slot while a consumer joins and reads
+    // it: loom fails the model if the read and a write are not ordered
+    model(|| {
+        let ring = Arc::new(OneSlotRing::default());
+        let r = ring.clone();
+        let joining = thread::spawn(move || {
+            let m = r.ring().join().unwrap();
+            if let Some(position) = r.ring().claim(m) {
+                let read = r.slot.with(|slot| unsafe { *slot });
+                assert_eq!(read, position);
+                r.ring().release(m, position);
+            }
+        });
+        for head in 0..2 {
+            ring.fill(head, Policy::Overwrite);
+        }
+        joining.join().unwrap();
+    });
+}
@@ -0,0 +1,90 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use zshm::notify::Event;
+
+#[test]
+fn wait_returns_when_generation_already_moved() {
+    let event = Event::new();
+    let seen = event.generation();
+    event.notify_one();
+    // Must not block: the notification happened after `seen` was read
+    assert!(event.wait_timeout(seen, Duration::from_secs(5)));
+}
+
+#[test]
+fn wait_timeout_expires_without_notification() {
+    let event = Event::new();
+    let start = Instant::now();
+    assert!(!event.wait_timeout(event.generation(), Duration::from_millis(50)));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert_eq!(event.waiters(), 0);
+}
+
+#[test]
+fn notify_all_wakes_every_waiter() {
+    let event = Arc::new(Event::new());
+    let seen = event.generation();
+    let woken = Arc::new(AtomicU32::new(0));
+
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let event = event.clone();
+            let woken = woken.clone();
+            std::thread::spawn(move || {
+                assert!(event.wait_timeout(seen, Duration::from_secs(5)));
+                woken.fetch_add(1, Ordering::AcqRel);
+            })
+        })
+        .collect();
+
+    while event.waiters() < 4 {
+        std::thread::yield_now();
+    }
+    event.notify_all();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(woken.load(Ordering::Acquire), 4);
+}
+
+#[test]
+fn ping_pong_loses_no_wakeup() {
+    const ROUNDS: u32 = 10_000;
+    let event = Arc::new(Event::new());
+    let turn = Arc::new(AtomicU32::new(0));
+    let done = Arc::new(AtomicBool::new(false));
+
+    let other = {
+        let (event, turn, done) = (event.clone(), turn.clone(), done.clone());
+        std::thread::spawn(move || {
+            for i in 0..ROUNDS {
+                loop {
+                    let seen = event.generation();
+                    if turn.load(Ordering::Acquire) == 2 * i + 1 {
+                        break;
+                    }
+                    assert!(event.wait_timeout(seen, Duration::from_secs(5)));
+                }
+                turn.store(2 * i + 2, Ordering::Release);
+                event.notify_all();
+            }
+            done.store(true, Ordering::Release);
+        })
+    };
+
+    for i in 0..ROUNDS {
+        loop {
+            let seen = event.generation();
+            if turn.load(Ordering::Acquire) == 2 * i {
+                break;
+            }
+            assert!(event.wait_timeout(seen, Duration::from_secs(5)));
+        }
+        turn.store(2 * i + 1, Ordering::Release);
+        event.notify_all();
+    }
+    other.join().unwrap();
+    assert!(done.load(Ordering::Acquire));
+}
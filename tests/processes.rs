@@ -0,0 +1,361 @@
+//! Runs the example producers and consumers as separate processes, connected
+//! over loopback, and checks what the consumers print against what the
+//! producer printed.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader};
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A sample as printed by the examples: sequence number, length and sum.
+type Sample = (u64, u64, u64);
+
+struct Process {
+    child: Child,
+    lines: mpsc::Receiver<String>,
+    seen: Vec<String>,
+}
+
+impl Process {
+    fn spawn(exe: &str, args: &[&str]) -> Self {
+        let mut child = Command::new(exe)
+            .args(args)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap_or_else(|e| panic!("failed to run {exe}: {e}"));
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let (tx, lines) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in stdout.lines().map_while(Result::ok) {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Process {
+            child,
+            lines,
+            seen: Vec::new(),
+        }
+    }
+
+    /// Waits until the process prints a line containing `pattern`.
+    fn expect(&mut self, pattern: &str) {
+        let deadline = Instant::now() + TIMEOUT;
+        while !self.seen.iter().any(|line| line.contains(pattern)) {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.lines.recv_timeout(left) {
+                Ok(line) => self.seen.push(line),
+                Err(_) => panic!("no line containing '{pattern}' in {:#?}", self.seen),
+            }
+        }
+    }
+
+    /// Waits for the process to exit and returns everything it printed.
+    fn finish(&mut self) -> Vec<String> {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                assert!(status.success(), "exited with {status}: {:#?}", self.seen);
+                break;
+            }
+            if Instant::now() >= deadline {
+                let _ = self.child.kill();
+                panic!("still running after {TIMEOUT:?}: {:#?}", self.seen);
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        // The reader thread ends with the pipe
+        self.seen.extend(self.lines.iter());
+        std::mem::take(&mut self.seen)
+    }
+
+    fn kill(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            self.kill();
+        }
+    }
+}
+
+/// A channel served by one producer process on a free loopback port.
+struct Channel {
+    key_expr: String,
+    endpoint: String,
+}
+
+impl Channel {
+    fn new(name: &str) -> Self {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        Channel {
+            key_expr: format!("test/{name}/{}", std::process::id()),
+            endpoint: format!("tcp/127.0.0.1:{port}"),
+        }
+    }
+
+    fn producer(&self, exe: &str, args: &[&str]) -> Process {
+        let mut all = vec!["-k", &self.key_expr, "-l", &self.endpoint];
+        all.extend(args);
+        Process::spawn(exe, &all)
+    }
+
+    fn consumer(&self, exe: &str, args: &[&str]) -> Process {
+        let mut all = vec!["-k", &self.key_expr, "-e", &self.endpoint, "-d", "0"];
+        all.extend(args);
+        Process::spawn(exe, &all)
+    }
+}
+
+/// Parses the lines `<sn> - <verb> buffer of <len> bytes with sum [of] <sum>`.
+fn samples(lines: &[String], verb: &str) -> Vec<Sample> {
+    lines
+        .iter()
+        .filter_map(|line| {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.get(2) != Some(&verb) {
+                return None;
+            }
+            let sum = match words.get(9) {
+                Some(&"of") => words.get(10),
+                sum => sum,
+            };
+            Some((
+                words[0].parse().ok()?,
+                words.get(5)?.parse().ok()?,
+                sum?.parse().ok()?,
+            ))
+        })
+        .collect()
+}
+
+fn produced(lines: &[String]) -> HashMap<u64, Sample> {
+    samples(lines, "Produced")
+        .into_iter()
+        .map(|s| (s.0, s))
+        .collect()
+}
+
+/// Checks that every consumed sample is one that was produced, unaltered.
+fn assert_intact(produced: &HashMap<u64, Sample>, consumed: &[Sample]) {
+    for sample in consumed {
+        assert_eq!(produced.get(&sample.0), Some(sample), "corrupted sample");
+    }
+}
+
+/// Checks that a consumer saw every sample from the one it attached at to
+/// `last`, once each and in order.
+fn assert_contiguous(consumed: &[Sample], last: u64) {
+    assert!(!consumed.is_empty(), "nothing consumed");
+    let first = consumed[0].0;
+    let sns: Vec<u64> = consumed.iter().map(|s| s.0).collect();
+    assert_eq!(sns, (first..=last).collect::<Vec<_>>());
+}
+
+/// Runs a 1:N producer publishing `count` samples, starts `n` consumers once
+/// it printed `ready`, then checks that each consumer got a gapless,
+/// duplicate-free suffix of the stream.
+fn one_to_n(name: &str, producer: &str, consumer: &str, ready: &str, count: u64, n: usize) {
+    let channel = Channel::new(name);
+    let count_arg = count.to_string();
+    let mut p = channel.producer(producer, &["-n", &count_arg, "-r", "200", "-s", "256"]);
+    p.expect(ready);
+    let mut consumers: Vec<_> = (0..n).map(|_| channel.consumer(consumer, &[])).collect();
+    let produced = produced(&p.finish());
+    assert_eq!(produced.len() as u64, count);
+    for c in &mut consumers {
+        let consumed = samples(&c.finish(), "Consumed");
+        assert_intact(&produced, &consumed);
+        assert_contiguous(&consumed, count);
+    }
+}
+
+#[test]
+fn spsc_delivers_every_sample_in_order() {
+    let channel = Channel::new("spsc");
+    let mut p = channel.producer(
+        env!("CARGO_BIN_EXE_spsc_producer"),
+        &["-n", "300", "-r", "500", "-s", "512"],
+    );
+    p.expect("Produced");
+    // The ring holds the first samples until the consumer attaches
+    let mut c = channel.consumer(env!("CARGO_BIN_EXE_spsc_consumer"), &[]);
+    let produced = produced(&p.finish());
+    let consumed = samples(&c.finish(), "Consumed");
+    assert_intact(&produced, &consumed);
+    assert_eq!(consumed.first().map(|s| s.0), Some(1));
+    assert_contiguous(&consumed, 300);
+}
+
+#[test]
+fn spsc_refuses_second_consumer_until_first_leaves() {
+    let channel = Channel::new("spsc_second");
+    let mut p = channel.producer(
+        env!("CARGO_BIN_EXE_spsc_producer"),
+        &["-n", "300", "-r", "100", "-s", "512"],
+    );
+    p.expect("Produced");
+    let mut first = channel.consumer(env!("CARGO_BIN_EXE_spsc_consumer"), &["-n", "100"]);
+    first.expect("Received SHM buffer");
+    let mut second = channel.consumer(env!("CARGO_BIN_EXE_spsc_consumer"), &[]);
+    second.expect("Failed to attach: all 1 consumer slots are taken");
+    second.finish();
+
+    // The ring waits for the next consumer from where the first one left
+    let first = samples(&first.finish(), "Consumed");
+    assert_eq!(first.len(), 100);
+    let mut third = channel.consumer(env!("CARGO_BIN_EXE_spsc_consumer"), &[]);
+    third.expect("Received SHM buffer");
+    let produced = produced(&p.finish());
+    let third = samples(&third.finish(), "Consumed");
+    assert_intact(&produced, &first);
+    assert_intact(&produced, &third);
+    assert_eq!(third.first().map(|s| s.0), Some(first[99].0 + 1));
+    assert_contiguous(&third, 300);
+}
+
+#[test]
+fn polling_1n_consumers_see_every_sample_once() {
+    // The producer polls for read credits every 100ms, hence few samples
+    one_to_n(
+        "polling_1n",
+        env!("CARGO_BIN_EXE_polling_producer_1n"),
+        env!("CARGO_BIN_EXE_polling_consumer_1n"),
+        "Waiting for consumers",
+        30,
+        3,
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn await_1n_consumers_see_every_sample_once() {
+    one_to_n(
+        "await_1n",
+        env!("CARGO_BIN_EXE_await_producer_1n"),
+        env!("CARGO_BIN_EXE_await_consumer_1n"),
+        "Waiting for consumers",
+        400,
+        3,
+    );
+}
+
+#[test]
+fn broadcast_consumers_see_every_sample_once() {
+    one_to_n(
+        "broadcast",
+        env!("CARGO_BIN_EXE_broadcast_producer"),
+        env!("CARGO_BIN_EXE_broadcast_consumer"),
+        "Produced",
+        400,
+        3,
+    );
+}
+
+#[test]
+fn mpmc_consumers_share_every_sample_once() {
+    let channel = Channel::new("mpmc");
+    let mut p = channel.producer(
+        env!("CARGO_BIN_EXE_mpmc_producer"),
+        &["-n", "300", "-r", "300", "-s", "256"],
+    );
+    p.expect("Produced");
+    let mut consumers: Vec<_> = (0..3)
+        .map(|_| channel.consumer(env!("CARGO_BIN_EXE_mpmc_consumer"), &[]))
+        .collect();
+    let produced = produced(&p.finish());
+    let mut seen = HashSet::new();
+    for c in &mut consumers {
+        let consumed = samples(&c.finish(), "Consumed");
+        assert_intact(&produced, &consumed);
+        for sample in consumed {
+            assert!(seen.insert(sample.0), "sample {} consumed twice", sample.0);
+        }
+    }
+    // The queue keeps what nobody consumed yet, so nothing is skipped
+    assert_eq!(seen, (1..=300).collect());
+}
+
+/// Runs a 1:N polling producer with two consumers, stops one of them with
+/// `stop` midway and checks that the producer and the other consumer carry
+/// on to the end.
+fn producer_survives(name: &str, stop: impl FnOnce(&mut Process)) {
+    let channel = Channel::new(name);
+    let mut p = channel.producer(
+        env!("CARGO_BIN_EXE_polling_producer_1n"),
+        &["-n", "60", "-s", "256"],
+    );
+    p.expect("Waiting for consumers");
+    let consumer = env!("CARGO_BIN_EXE_polling_consumer_1n");
+    let mut leaving = channel.consumer(consumer, &[]);
+    let mut staying = channel.consumer(consumer, &[]);
+    leaving.expect("Consumed");
+    p.expect("for 2 subs");
+    stop(&mut leaving);
+    let lines = p.finish();
+    let produced = produced(&lines);
+    assert_eq!(produced.len(), 60);
+    // The producer no longer counts the consumer that left
+    assert!(lines.last().unwrap().ends_with("for 1 subs"), "{lines:#?}");
+    let consumed = samples(&staying.finish(), "Consumed");
+    assert_intact(&produced, &consumed);
+    assert_contiguous(&consumed, 60);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn polling_1n_producer_continues_after_consumer_interrupted() {
+    // Leaving releases the consumer's slot and read credit right away
+    producer_survives("interrupted", |consumer| {
+        // SAFETY: plain signal delivery to our own child.
+        let rc = unsafe { libc::kill(consumer.child.id() as libc::pid_t, libc::SIGINT) };
+        assert_eq!(rc, 0);
+        let lines = consumer.finish();
+        assert!(lines.iter().any(|line| line.contains("Shutting down")));
+        let consumed = samples(&lines, "Consumed");
+        assert_contiguous(&consumed, consumed.last().unwrap().0);
+    });
+}
+
+#[test]
+fn polling_1n_producer_reaps_killed_consumer() {
+    // Reaped once its heartbeat expires, the producer meanwhile waits for it
+    producer_survives("killed", Process::kill);
+}
+
+#[test]
+fn broadcast_producer_reaps_killed_consumer() {
+    // A blocking producer would otherwise wait for the dead cursor forever
+    let channel = Channel::new("broadcast_killed");
+    let mut p = channel.producer(
+        env!("CARGO_BIN_EXE_broadcast_producer"),
+        &["-n", "100", "-r", "50", "-s", "256", "--block"],
+    );
+    p.expect("Produced");
+    let consumer = env!("CARGO_BIN_EXE_broadcast_consumer");
+    let mut killed = channel.consumer(consumer, &[]);
+    let mut staying = channel.consumer(consumer, &[]);
+    killed.expect("Consumed");
+    p.expect("for 2 subs");
+    killed.kill();
+    let lines = p.finish();
+    let produced = produced(&lines);
+    assert_eq!(produced.len(), 100);
+    assert!(lines.last().unwrap().ends_with("for 1 subs"), "{lines:#?}");
+    let consumed = samples(&staying.finish(), "Consumed");
+    assert_intact(&produced, &consumed);
+    assert_contiguous(&consumed, 100);
+}
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use zshm::Protocol;
+use zshm::stats::{ConsumerStats, Stats};
+
+fn stats(consumers: Vec<ConsumerStats>) -> Stats {
+    Stats {
+        protocol: Protocol::Broadcast,
+        produced: 10,
+        consumed: 7,
+        skipped: 2,
+        producer_blocked: Duration::from_micros(3),
+        consumer_waited: Duration::from_millis(4),
+        lag: 5,
+        consumers,
+    }
+}
+
+#[test]
+fn json_without_consumers() {
+    assert_eq!(
+        stats(Vec::new()).to_json(),
+        concat!(
+            r#"{"protocol":"broadcast","produced":10,"consumed":7,"skipped":2,"#,
+            r#""producer_blocked_ns":3000,"consumer_waited_ns":4000000,"lag":5,"#,
+            r#""consumers":[]}"#,
+        )
+    );
+}
+
+#[test]
+fn json_lists_every_consumer() {
+    let consumers = vec![
+        ConsumerStats {
+            pid: 42,
+            consumed: 5,
+            skipped: 2,
+            waited: Duration::from_nanos(1500),
+            lag: 0,
+        },
+        ConsumerStats {
+            pid: 43,
+            consumed: 2,
+            skipped: 0,
+            waited: Duration::ZERO,
+            lag: 5,
+        },
+    ];
+    assert_eq!(
+        stats(consumers).to_json(),
+        concat!(
+            r#"{"protocol":"broadcast","produced":10,"consumed":7,"skipped":2,"#,
+            r#""producer_blocked_ns":3000,"consumer_waited_ns":4000000,"lag":5,"#,
+            r#""consumers":["#,
+            r#"{"pid":42,"consumed":5,"skipped":2,"waited_ns":1500,"lag":0},"#,
+            r#"{"pid":43,"consumed":2,"skipped":0,"waited_ns":0,"lag":5}"#,
+            r#"]}"#,
+        )
+    );
+}
+
+#[test]
+fn protocols_are_named_in_kebab_case() {
+    for (protocol, name) in [
+        (Protocol::Polling, "polling"),
+        (Protocol::Polling1N, "polling-1n"),
+        (Protocol::Await1N, "await-1n"),
+        (Protocol::Spsc, "spsc"),
+        (Protocol::Broadcast, "broadcast"),
+        (Protocol::Mpmc, "mpmc"),
+        (Protocol::Seqlock, "seqlock"),
+    ] {
+        let json = Stats {
+            protocol,
+            ..stats(Vec::new())
+        }
+        .to_json();
+        assert!(
+            json.starts_with(&format!(r#"{{"protocol":"{name}","#)),
+            "{json}"
+        );
+    }
+}